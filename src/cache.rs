@@ -0,0 +1,513 @@
+//! Manifest cache for incremental conversions.
+//!
+//! For each input file the manifest records its size, mtime and a SHA-1 of its
+//! contents, together with the list of XML files it contained. The parsed features
+//! of every XML file are kept as a small FlatGeobuf file under the cache directory,
+//! so unchanged inputs can be re-assembled into the output without parsing them again.
+
+use crate::dataset::read_fgb;
+use crate::parse::{ParseOptions, ParsedXML, SkipReason};
+use crate::writer::{FGBWriter, WriterOptions};
+use anyhow::{Result, anyhow};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+const MANIFEST_FILE: &str = "manifest.json";
+/// Bump this when the cached file layout or the parser output changes.
+const CACHE_VERSION: u32 = 2;
+/// How often the manifest is saved while a run is in progress, so that an
+/// interrupted run can resume from the inputs finished so far.
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Manifest {
+    version: u32,
+    /// Keyed by the input path as given on the command line.
+    inputs: BTreeMap<String, ManifestEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub path: PathBuf,
+    #[serde(flatten)]
+    pub stamp: FileStamp,
+    /// The parse options the cached results were produced with.
+    pub parse_options: String,
+    pub files: Vec<CachedFile>,
+}
+
+/// What an input looked like when it was read, to tell whether it has changed since.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileStamp {
+    pub size: u64,
+    pub mtime_ms: u64,
+    /// SHA-1 of the file contents, as hex.
+    pub hash: String,
+}
+
+impl FileStamp {
+    pub fn read(path: &Path) -> std::io::Result<Self> {
+        let (size, mtime_ms) = file_stat(path)?;
+        Ok(FileStamp {
+            size,
+            mtime_ms,
+            hash: file_hash(path)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedFile {
+    pub file_name: String,
+    pub feature_count: usize,
+    pub skip_reason: Option<SkipReason>,
+    pub filtered_chikugai: usize,
+    /// Cache file holding the features, relative to the cache directory.
+    /// `None` when the XML file had no features.
+    pub cache_file: Option<PathBuf>,
+}
+
+pub struct Cache {
+    dir: PathBuf,
+    manifest: Manifest,
+    parse_options: String,
+}
+
+impl Cache {
+    /// Opens (or creates) the cache directory and loads its manifest.
+    /// A manifest from a different cache version is ignored.
+    pub fn open(dir: &Path, parse_options: &ParseOptions) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        let manifest_path = dir.join(MANIFEST_FILE);
+        let manifest = if manifest_path.exists() {
+            let manifest: Manifest =
+                serde_json::from_reader(BufReader::new(File::open(&manifest_path)?))?;
+            if manifest.version == CACHE_VERSION {
+                manifest
+            } else {
+                info!(
+                    "[cache] Ignoring manifest with version {}",
+                    manifest.version
+                );
+                Manifest::default()
+            }
+        } else {
+            Manifest::default()
+        };
+        Ok(Cache {
+            dir: dir.to_path_buf(),
+            manifest,
+            parse_options: options_fingerprint(parse_options),
+        })
+    }
+
+    /// Returns the cached results for `path` if the input is unchanged since it was cached.
+    pub fn lookup(&mut self, path: &Path) -> Option<ManifestEntry> {
+        let key = path.to_string_lossy().to_string();
+        let entry = self.manifest.inputs.get_mut(&key)?;
+        if entry.parse_options != self.parse_options {
+            return None;
+        }
+        let (size, mtime_ms) = file_stat(path).ok()?;
+        if entry.stamp.size != size {
+            return None;
+        }
+        if entry.stamp.mtime_ms != mtime_ms {
+            // Touched but maybe not modified: fall back to comparing contents.
+            if file_hash(path).ok()? != entry.stamp.hash {
+                return None;
+            }
+            entry.stamp.mtime_ms = mtime_ms;
+        }
+        Some(entry.clone())
+    }
+
+    /// Removes the cached results of an input that is about to be parsed again.
+    pub fn invalidate(&mut self, path: &Path) -> Result<()> {
+        self.manifest.inputs.remove(path.to_string_lossy().as_ref());
+        let dir = self.dir.join(input_dir_name(path));
+        if dir.exists() {
+            fs::remove_dir_all(&dir)?;
+        }
+        fs::create_dir_all(&dir)?;
+        Ok(())
+    }
+
+    /// Records an input whose XML files have all been written with [`write_cache_file`].
+    /// `stamp` must be taken before the input was read, so that a change made while it
+    /// was being parsed invalidates the entry on the next run.
+    pub fn store(&mut self, path: &Path, stamp: FileStamp, files: Vec<CachedFile>) -> Result<()> {
+        let entry = ManifestEntry {
+            path: path.to_path_buf(),
+            stamp,
+            parse_options: self.parse_options.clone(),
+            files,
+        };
+        self.manifest
+            .inputs
+            .insert(path.to_string_lossy().to_string(), entry);
+        Ok(())
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Writes the manifest. Written to a temporary file first so an interrupted
+    /// run never leaves a truncated manifest behind.
+    pub fn save(&self) -> Result<()> {
+        let tmp_path = self.dir.join(format!("{}.tmp", MANIFEST_FILE));
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        let manifest = Manifest {
+            version: CACHE_VERSION,
+            inputs: self.manifest.inputs.clone(),
+        };
+        serde_json::to_writer_pretty(&mut writer, &manifest)?;
+        writer.flush()?;
+        drop(writer);
+        fs::rename(&tmp_path, self.dir.join(MANIFEST_FILE))?;
+        Ok(())
+    }
+}
+
+/// Thread-safe handle used by the pipeline stages to record parsed inputs in the cache.
+///
+/// An input is added to the manifest once it has been read completely and every XML
+/// file in it has been written to a cache file. Inputs with read errors, parse errors
+/// or lenient-mode warnings are never cached, so they are parsed (and reported) again
+/// on the next run.
+#[derive(Clone)]
+pub struct CacheRecorder {
+    dir: Arc<PathBuf>,
+    state: Arc<Mutex<RecorderState>>,
+}
+
+struct RecorderState {
+    cache: Cache,
+    pending: HashMap<usize, PendingInput>,
+    last_save: Instant,
+}
+
+struct PendingInput {
+    path: PathBuf,
+    /// Taken when the input started being tracked, before it was read.
+    stamp: Option<FileStamp>,
+    /// XML files sent to the parsers but not yet cached.
+    remaining: usize,
+    read_done: bool,
+    failed: bool,
+    /// Cached files, keyed by the name of the zip entry they were read from (when the
+    /// entries of an input are read in parallel, see [`crate::reader::ZipEntries`]; empty
+    /// otherwise) and their index within the input, so the stored order doesn't depend on
+    /// which thread got to an entry first.
+    files: BTreeMap<(String, usize), CachedFile>,
+}
+
+impl CacheRecorder {
+    pub fn new(cache: Cache) -> Self {
+        CacheRecorder {
+            dir: Arc::new(cache.dir().to_path_buf()),
+            state: Arc::new(Mutex::new(RecorderState {
+                cache,
+                pending: HashMap::new(),
+                last_save: Instant::now(),
+            })),
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn lookup(&self, path: &Path) -> Option<ManifestEntry> {
+        self.state.lock().unwrap().cache.lookup(path)
+    }
+
+    /// Starts tracking an input that is about to be parsed, dropping its old cached results.
+    pub fn track(&self, input: usize, path: &Path) {
+        let stamp = match FileStamp::read(path) {
+            Ok(stamp) => Some(stamp),
+            Err(e) => {
+                error!("[cache] Failed to read {}: {}", path.display(), e);
+                None
+            }
+        };
+        let mut state = self.state.lock().unwrap();
+        let failed = match state.cache.invalidate(path) {
+            Ok(_) => stamp.is_none(),
+            Err(e) => {
+                error!("[cache] Failed to invalidate {}: {}", path.display(), e);
+                true
+            }
+        };
+        state.pending.insert(
+            input,
+            PendingInput {
+                path: path.to_path_buf(),
+                stamp,
+                remaining: 0,
+                read_done: false,
+                failed,
+                files: BTreeMap::new(),
+            },
+        );
+    }
+
+    /// Path of the cache file for an XML file of a tracked input.
+    pub fn cache_file_name(&self, input: usize, seq: usize) -> Option<PathBuf> {
+        let state = self.state.lock().unwrap();
+        let pending = state.pending.get(&input)?;
+        Some(cache_file_name(&pending.path, seq))
+    }
+
+    /// An XML file of the input was sent to the parsers.
+    pub fn file_queued(&self, input: usize) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(pending) = state.pending.get_mut(&input) {
+            pending.remaining += 1;
+        }
+    }
+
+    pub fn file_cached(&self, input: usize, entry: String, seq: usize, file: CachedFile) {
+        self.file_done(input, Some(((entry, seq), file)));
+    }
+
+    /// An XML file of the input failed to parse or could not be cached.
+    pub fn file_failed(&self, input: usize) {
+        self.file_done(input, None);
+    }
+
+    /// All XML files of the input have been queued. `ok` is false if reading it failed.
+    pub fn input_read(&self, input: usize, ok: bool) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(pending) = state.pending.get_mut(&input) {
+            pending.read_done = true;
+            pending.failed |= !ok;
+        }
+        state.complete(input);
+    }
+
+    fn file_done(&self, input: usize, file: Option<((String, usize), CachedFile)>) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(pending) = state.pending.get_mut(&input) {
+            pending.remaining -= 1;
+            match file {
+                Some((key, file)) => {
+                    pending.files.insert(key, file);
+                }
+                None => pending.failed = true,
+            }
+        }
+        state.complete(input);
+    }
+
+    /// Saves the manifest. Call once all pipeline threads have finished.
+    pub fn finish(&self) -> Result<()> {
+        self.state.lock().unwrap().cache.save()
+    }
+}
+
+impl RecorderState {
+    /// Stores the input in the manifest if it is finished.
+    fn complete(&mut self, input: usize) {
+        match self.pending.get(&input) {
+            Some(pending) if pending.read_done && pending.remaining == 0 => {}
+            _ => return,
+        }
+        let pending = self.pending.remove(&input).unwrap();
+        let stamp = match pending.stamp {
+            Some(stamp) if !pending.failed => stamp,
+            _ => {
+                info!("[cache] Not caching {}", pending.path.display());
+                return;
+            }
+        };
+        let files = pending.files.into_values().collect();
+        if let Err(e) = self.cache.store(&pending.path, stamp, files) {
+            error!("[cache] Failed to cache {}: {}", pending.path.display(), e);
+            return;
+        }
+        info!("[cache] Cached {}", pending.path.display());
+        if self.last_save.elapsed() >= SAVE_INTERVAL {
+            if let Err(e) = self.cache.save() {
+                error!("[cache] Failed to save manifest: {}", e);
+            }
+            self.last_save = Instant::now();
+        }
+    }
+}
+
+/// Name of the directory holding the per-XML cache files of an input.
+/// A collision would make two inputs clear each other's cache files, hence the SHA-1.
+fn input_dir_name(path: &Path) -> String {
+    hex(&Sha1::digest(path.to_string_lossy().as_bytes()))
+}
+
+/// Path of the cache file for the `seq`-th XML file of an input, relative to the cache directory.
+pub fn cache_file_name(path: &Path, seq: usize) -> PathBuf {
+    Path::new(&input_dir_name(path)).join(format!("{}.fgb", seq))
+}
+
+/// Loads the parsed results of a cached XML file.
+pub fn load_cached_file(cache_dir: &Path, file: &CachedFile) -> Result<ParsedXML> {
+    let features = match &file.cache_file {
+        Some(cache_file) => read_fgb(&cache_dir.join(cache_file))?,
+        None => vec![],
+    };
+    if features.len() != file.feature_count {
+        return Err(anyhow!(
+            "Cache file for {} has {} features, expected {}",
+            file.file_name,
+            features.len(),
+            file.feature_count
+        ));
+    }
+    let mut features = features.into_iter();
+    let (common_props, first) = match features.next() {
+        Some((common_props, feature)) => (common_props, Some(feature)),
+        // Files without features only need their report information.
+        None => (Default::default(), None),
+    };
+    Ok(ParsedXML {
+        file_name: file.file_name.clone(),
+        features: first
+            .into_iter()
+            .chain(features.map(|(_, feature)| feature))
+            .collect(),
+        common_props,
+        skip_reason: file.skip_reason,
+        filtered_chikugai: file.filtered_chikugai,
+        warnings: vec![],
+    })
+}
+
+/// Writes the features of a parsed XML file to a cache file.
+/// Returns `false` if there were no features (and no file was written).
+pub fn write_cache_file(path: &Path, parsed: &ParsedXML) -> Result<bool> {
+    let mut fgb = FGBWriter::new(
+        path,
+        &WriterOptions {
+            write_index: false,
+            write_fid: false,
+        },
+    )?;
+    for feature in &parsed.features {
+        fgb.add_feature(
+            &parsed.common_props,
+            feature.geometry.clone().into(),
+            &feature.props,
+        )?;
+    }
+    fgb.flush()
+}
+
+fn options_fingerprint(options: &ParseOptions) -> String {
+    format!(
+        "arbitrary={},chikugai={},lenient={}",
+        options.include_arbitrary_crs, options.include_chikugai, options.lenient
+    )
+}
+
+fn file_stat(path: &Path) -> std::io::Result<(u64, u64)> {
+    let metadata = fs::metadata(path)?;
+    let mtime_ms = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+    Ok((metadata.len(), mtime_ms))
+}
+
+fn file_hash(path: &Path) -> std::io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha1::new();
+    let mut buf = vec![0; 1 << 16];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hex(&hasher.finalize()))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::parse_xml_content;
+    use crate::parse::tests::{SAMPLE_XML, sample_file};
+
+    fn options() -> ParseOptions {
+        ParseOptions {
+            include_arbitrary_crs: true,
+            include_chikugai: false,
+            lenient: false,
+        }
+    }
+
+    #[test]
+    fn test_store_and_lookup() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let input = dir.path().join("input.xml");
+        fs::write(&input, SAMPLE_XML)?;
+        let cache_dir = dir.path().join("cache");
+
+        let mut cache = Cache::open(&cache_dir, &options())?;
+        assert!(cache.lookup(&input).is_none());
+        cache.invalidate(&input)?;
+        let stamp = FileStamp::read(&input)?;
+
+        let parsed = parse_xml_content(&sample_file(SAMPLE_XML), &options())?;
+        let cache_file = cache_file_name(&input, 0);
+        assert!(write_cache_file(&cache.dir().join(&cache_file), &parsed)?);
+        cache.store(
+            &input,
+            stamp.clone(),
+            vec![CachedFile {
+                file_name: parsed.file_name.clone(),
+                feature_count: parsed.features.len(),
+                skip_reason: None,
+                filtered_chikugai: 0,
+                cache_file: Some(cache_file),
+            }],
+        )?;
+        cache.save()?;
+
+        // A fresh cache sees the stored entry...
+        let mut cache = Cache::open(&cache_dir, &options())?;
+        let entry = cache.lookup(&input).expect("Expected a cache hit");
+        let loaded = load_cached_file(cache.dir(), &entry.files[0])?;
+        assert_eq!(loaded.features.len(), 1);
+        assert_eq!(loaded.common_props.地図名, "テスト地図");
+        assert_eq!(loaded.features[0].props.地番.as_deref(), Some("1"));
+
+        // ...but not with different parse options...
+        let lenient = ParseOptions {
+            lenient: true,
+            ..options()
+        };
+        assert!(Cache::open(&cache_dir, &lenient)?.lookup(&input).is_none());
+
+        // ...or once the input changes.
+        fs::write(&input, SAMPLE_XML.replace("テスト地図", "別の地図"))?;
+        assert!(cache.lookup(&input).is_none());
+
+        // A change made while the input was being parsed is caught on the next run,
+        // since the stamp stored is the one from before it was read.
+        cache.store(&input, stamp, entry.files)?;
+        assert!(cache.lookup(&input).is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,222 @@
+//! Loading whole datasets into memory: FlatGeobuf files produced by
+//! [`crate::writer::FGBWriter`] are read back, MOJ XML / zip inputs are parsed.
+
+use crate::parse::{CommonProperties, Feature, FeatureProperties, ParseOptions, parse_xml_content};
+use crate::reader::{ReaderOptions, iter_xml_contents};
+use anyhow::{Result, anyhow};
+use crossbeam_channel::bounded;
+use flatgeobuf::{FallibleStreamingIterator, FgbFeature, FgbReader};
+use geo_types::{Geometry, MultiPolygon};
+use geozero::{FeatureProperties as _, ToGeo};
+use log::error;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+
+pub struct Dataset {
+    /// Features in input order.
+    pub features: Vec<(CommonProperties, Feature)>,
+    /// Number of inputs or XML files that could not be read or parsed.
+    pub failed_files: usize,
+}
+
+/// Converts a FlatGeobuf feature back into the parsed representation.
+pub fn feature_from_fgb(feature: &FgbFeature) -> Result<(CommonProperties, Feature)> {
+    let mut props = feature.properties()?;
+    let geometry = match feature.to_geo()? {
+        Geometry::MultiPolygon(mp) => mp,
+        Geometry::Polygon(p) => MultiPolygon::new(vec![p]),
+        other => return Err(anyhow!("Unexpected geometry type: {:?}", other)),
+    };
+    let mut required = |name: &str| {
+        props
+            .remove(name)
+            .ok_or_else(|| anyhow!("Missing property: {}", name))
+    };
+    let common_props = CommonProperties {
+        地図名: required("地図名")?,
+        市区町村コード: required("市区町村コード")?,
+        市区町村名: required("市区町村名")?,
+        座標系: required("座標系")?,
+        測地系判別: props.remove("測地系判別"),
+    };
+    let feature = Feature {
+        geometry,
+        props: feature_properties(&mut props)?,
+    };
+    Ok((common_props, feature))
+}
+
+fn feature_properties(props: &mut HashMap<String, String>) -> Result<FeatureProperties> {
+    Ok(FeatureProperties {
+        筆id: props
+            .remove("筆id")
+            .ok_or_else(|| anyhow!("Missing property: 筆id"))?,
+        精度区分: props.remove("精度区分"),
+        大字コード: props.remove("大字コード"),
+        丁目コード: props.remove("丁目コード"),
+        小字コード: props.remove("小字コード"),
+        予備コード: props.remove("予備コード"),
+        大字名: props.remove("大字名"),
+        丁目名: props.remove("丁目名"),
+        小字名: props.remove("小字名"),
+        予備名: props.remove("予備名"),
+        地番: props.remove("地番"),
+        座標値種別: props.remove("座標値種別"),
+        筆界未定構成筆: props.remove("筆界未定構成筆"),
+    })
+}
+
+/// Reads every feature of a FlatGeobuf file, in file order.
+pub fn read_fgb(path: &Path) -> Result<Vec<(CommonProperties, Feature)>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut iter = FgbReader::open(&mut reader)?.select_all_seq()?;
+    let mut features = Vec::new();
+    while let Some(feature) = iter.next()? {
+        features.push(feature_from_fgb(feature)?);
+    }
+    Ok(features)
+}
+
+/// Loads the features of a set of inputs. `.fgb` files are read back directly, other
+/// inputs are parsed like in a normal conversion. Files that fail are logged, printed
+/// and counted, but do not stop the load.
+pub fn load_dataset(paths: &[PathBuf], options: &ParseOptions) -> Result<Dataset> {
+    let mut failed_files = 0;
+    let mut parsed = Vec::new();
+    let (tx, rx) = bounded(num_cpus::get());
+    let results = Mutex::new(Vec::new());
+    let parse_failures = Mutex::new(0);
+    thread::scope(|s| {
+        for _ in 0..num_cpus::get() {
+            let rx = rx.clone();
+            let results = &results;
+            let parse_failures = &parse_failures;
+            s.spawn(move || {
+                while let Ok((seq, file_data)) = rx.recv() {
+                    match parse_xml_content(&file_data, options) {
+                        Ok(xml) => results.lock().unwrap().push((seq, xml)),
+                        Err(e) => {
+                            error!("Error parsing file {}: {}", file_data.file_name, e);
+                            eprintln!("Error parsing file {}: {}", file_data.file_name, e);
+                            *parse_failures.lock().unwrap() += 1;
+                        }
+                    }
+                }
+            });
+        }
+        drop(rx);
+
+        // Read on this thread; the sequence number keeps the parsed files in input order.
+        let mut seq = 0;
+        for path in paths {
+            if is_fgb(path) {
+                match read_fgb(path) {
+                    Ok(features) => parsed.push((seq, features)),
+                    Err(e) => {
+                        eprintln!("Error reading file {}: {}", path.display(), e);
+                        failed_files += 1;
+                    }
+                }
+                seq += 1;
+                continue;
+            }
+            for item in iter_xml_contents(path, &ReaderOptions::default()) {
+                match item {
+                    Ok(file_data) => {
+                        tx.send((seq, file_data))?;
+                        seq += 1;
+                    }
+                    Err(e) => {
+                        eprintln!("Error reading file {}: {}", path.display(), e);
+                        failed_files += 1;
+                    }
+                }
+            }
+        }
+        drop(tx);
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    for (seq, xml) in results.into_inner().unwrap() {
+        let features = xml
+            .features
+            .into_iter()
+            .map(|feature| (xml.common_props.clone(), feature))
+            .collect();
+        parsed.push((seq, features));
+    }
+    parsed.sort_by_key(|(seq, _)| *seq);
+    Ok(Dataset {
+        features: parsed.into_iter().flat_map(|(_, f)| f).collect(),
+        failed_files: failed_files + parse_failures.into_inner().unwrap(),
+    })
+}
+
+pub fn is_fgb(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("fgb"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::ParsedXML;
+    use crate::writer::{FGBWriter, WriterOptions};
+    use geo_types::polygon;
+
+    #[test]
+    fn test_round_trip() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("round_trip.fgb");
+        let mut fgb = FGBWriter::new(
+            &path,
+            &WriterOptions {
+                write_index: false,
+                write_fid: false,
+            },
+        )?;
+        fgb.add_xml_features(ParsedXML {
+            file_name: "test.xml".to_string(),
+            features: vec![Feature {
+                geometry: MultiPolygon::from(vec![polygon![
+                    (x: 0.0, y: 0.0),
+                    (x: 1.0, y: 0.0),
+                    (x: 1.0, y: 1.0),
+                    (x: 0.0, y: 0.0)
+                ]]),
+                props: FeatureProperties {
+                    筆id: "H1".to_string(),
+                    地番: Some("1-2".to_string()),
+                    ..Default::default()
+                },
+            }],
+            common_props: CommonProperties {
+                地図名: "テスト地図".to_string(),
+                市区町村コード: "00000".to_string(),
+                市区町村名: "テスト市".to_string(),
+                座標系: "公共座標1系".to_string(),
+                測地系判別: None,
+            },
+            skip_reason: None,
+            filtered_chikugai: 0,
+            warnings: vec![],
+        })?;
+        fgb.flush()?;
+
+        let features = read_fgb(&path)?;
+        assert_eq!(features.len(), 1);
+        let (common, feature) = &features[0];
+        assert_eq!(common.地図名, "テスト地図");
+        assert_eq!(common.測地系判別, None);
+        assert_eq!(feature.props.筆id, "H1");
+        assert_eq!(feature.props.地番.as_deref(), Some("1-2"));
+        assert_eq!(feature.props.大字名, None);
+        assert_eq!(feature.geometry.0[0].exterior().0.len(), 4);
+        Ok(())
+    }
+}
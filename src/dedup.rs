@@ -0,0 +1,150 @@
+//! Dropping features that appear in more than one input, e.g. when a prefecture
+//! archive and an updated city archive are converted together.
+//!
+//! Inputs are written in priority order (the copy that should win first, see
+//! [`crate::order::OrderGate`]), and the first copy of every feature key is kept.
+
+use crate::id;
+use crate::parse::{CommonProperties, Feature, ParsedXML};
+use clap::ValueEnum;
+use geo::CoordsIter;
+use sha1::{Digest, Sha1};
+use std::collections::HashSet;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DedupKey {
+    /// 市区町村コード + 地図名 + 筆id.
+    Id,
+    /// All attributes and the geometry.
+    Content,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum DedupPolicy {
+    /// The copy from the input given last on the command line wins.
+    #[default]
+    LastArg,
+    /// The copy from the most recently modified input wins.
+    LatestMtime,
+}
+
+#[derive(Debug, Clone)]
+pub struct DedupOptions {
+    pub key: DedupKey,
+    pub policy: DedupPolicy,
+}
+
+/// Returns the input indices in the order they should be written, winners first.
+pub fn priority_order(src_files: &[PathBuf], policy: DedupPolicy) -> Vec<usize> {
+    // Later arguments come first, which also breaks mtime ties.
+    let mut order: Vec<usize> = (0..src_files.len()).rev().collect();
+    if policy == DedupPolicy::LatestMtime {
+        let mtimes: Vec<_> = src_files
+            .iter()
+            .map(|path| {
+                fs::metadata(path)
+                    .and_then(|m| m.modified())
+                    .unwrap_or(UNIX_EPOCH)
+            })
+            .collect();
+        order.sort_by(|a, b| mtimes[*b].cmp(&mtimes[*a]));
+    }
+    order
+}
+
+/// Remembers the keys of written features and filters out later copies.
+pub struct Deduplicator {
+    key: DedupKey,
+    seen: HashSet<SeenKey>,
+}
+
+/// What is remembered of a written feature. Never a plain 64-bit hash, whose
+/// collisions would silently drop distinct parcels.
+#[derive(PartialEq, Eq, Hash)]
+enum SeenKey {
+    /// The canonical parcel key itself.
+    Id(Vec<u8>),
+    /// SHA-1 of the attributes and the geometry, which would take too much memory
+    /// to keep for national runs.
+    Content([u8; 20]),
+}
+
+impl Deduplicator {
+    pub fn new(key: DedupKey) -> Self {
+        Deduplicator {
+            key,
+            seen: HashSet::new(),
+        }
+    }
+
+    /// Removes features that were already seen. Returns the number of dropped features.
+    pub fn retain(&mut self, parsed: &mut ParsedXML) -> usize {
+        let before = parsed.features.len();
+        let common = &parsed.common_props;
+        parsed
+            .features
+            .retain(|feature| self.seen.insert(seen_key(self.key, common, feature)));
+        before - parsed.features.len()
+    }
+}
+
+fn seen_key(key: DedupKey, common: &CommonProperties, feature: &Feature) -> SeenKey {
+    match key {
+        // The key the fid column is derived from.
+        DedupKey::Id => SeenKey::Id(id::parcel_key(common, &feature.props)),
+        DedupKey::Content => {
+            let mut hasher = Sha1Hasher::default();
+            common.hash(&mut hasher);
+            feature.props.hash(&mut hasher);
+            for coord in feature.geometry.coords_iter() {
+                coord.x.to_bits().hash(&mut hasher);
+                coord.y.to_bits().hash(&mut hasher);
+            }
+            SeenKey::Content(hasher.0.finalize().into())
+        }
+    }
+}
+
+/// Feeds `Hash` implementations into SHA-1.
+#[derive(Default)]
+struct Sha1Hasher(Sha1);
+
+impl Hasher for Sha1Hasher {
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+
+    fn finish(&self) -> u64 {
+        let digest = self.0.clone().finalize();
+        u64::from_le_bytes(digest[..8].try_into().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::parse_xml_content;
+    use crate::parse::tests::{SAMPLE_XML, arbitrary_options, sample_file};
+
+    fn sample(xml: &str) -> ParsedXML {
+        parse_xml_content(&sample_file(xml), &arbitrary_options()).unwrap()
+    }
+
+    #[test]
+    fn test_retain() {
+        let changed_xml = SAMPLE_XML.replace("<地番>1</地番>", "<地番>2</地番>");
+        let mut by_id = Deduplicator::new(DedupKey::Id);
+        let mut by_content = Deduplicator::new(DedupKey::Content);
+        assert_eq!(by_id.retain(&mut sample(SAMPLE_XML)), 0);
+        assert_eq!(by_content.retain(&mut sample(SAMPLE_XML)), 0);
+
+        // Same 筆id, different 地番: a duplicate by id, but not by content.
+        assert_eq!(by_id.retain(&mut sample(&changed_xml)), 1);
+        assert_eq!(by_content.retain(&mut sample(&changed_xml)), 0);
+        assert_eq!(by_content.retain(&mut sample(SAMPLE_XML)), 1);
+    }
+}
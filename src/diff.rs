@@ -0,0 +1,581 @@
+//! Comparing two datasets (e.g. two annual releases) parcel by parcel.
+//!
+//! Parcels are keyed on 市区町村コード + 大字/丁目/小字コード + 地番. Keys present in
+//! only one dataset are matched up geometrically to detect splits (分筆) and merges
+//! (合筆): a new parcel whose interior point lies in a different old parcel was split
+//! off from it, and an old parcel whose interior point lies in a different new parcel
+//! was merged into it.
+
+use crate::dataset::{Dataset, load_dataset};
+use crate::id;
+use crate::parse::{CommonProperties, Feature, FeatureProperties, ParseOptions};
+use crate::writer::remove_output;
+use anyhow::Result;
+use flatgeobuf::{
+    ColumnType, FgbCrs, FgbWriter, FgbWriterOptions, GeometryType,
+    geozero::{ColumnValue, PropertyProcessor},
+};
+use geo::{
+    Area, BoundingRect, Centroid, Contains, Distance, Euclidean, GeodesicArea, Haversine,
+    InteriorPoint,
+};
+use geo_types::{Geometry, MultiPolygon, Point, Rect};
+use log::info;
+use rstar::RTree;
+use rstar::primitives::{GeomWithData, Rectangle};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Coordinates closer than this are considered unchanged (about 1mm in degrees).
+const COORD_TOLERANCE: f64 = 1e-8;
+
+#[derive(Debug, Clone, Default)]
+pub struct DiffOptions {
+    /// Also write parcels whose geometry did not change.
+    pub include_unchanged: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ParcelKey {
+    pub 市区町村コード: String,
+    pub 大字コード: String,
+    pub 丁目コード: String,
+    pub 小字コード: String,
+    pub 地番: String,
+}
+
+impl ParcelKey {
+    /// Returns `None` for features without a 地番, which can't be matched across datasets.
+    fn new(common: &CommonProperties, props: &FeatureProperties) -> Option<Self> {
+        let code = |c: &Option<String>| c.clone().unwrap_or_default();
+        Some(ParcelKey {
+            市区町村コード: common.市区町村コード.clone(),
+            大字コード: code(&props.大字コード),
+            丁目コード: code(&props.丁目コード),
+            小字コード: code(&props.小字コード),
+            地番: props.地番.clone()?,
+        })
+    }
+
+    /// Split and merge candidates are only searched within the same 大字.
+    fn area_key(&self) -> (&str, &str) {
+        (&self.市区町村コード, &self.大字コード)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ChangeType {
+    Unchanged,
+    Added,
+    Removed,
+    Changed,
+    Split,
+    Merged,
+}
+
+impl ChangeType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChangeType::Unchanged => "unchanged",
+            ChangeType::Added => "added",
+            ChangeType::Removed => "removed",
+            ChangeType::Changed => "changed",
+            ChangeType::Split => "split",
+            ChangeType::Merged => "merged",
+        }
+    }
+}
+
+/// All features of one dataset sharing a key (e.g. 筆界未定 parcels listed more than once).
+struct Parcel {
+    common: CommonProperties,
+    props: FeatureProperties,
+    geometry: MultiPolygon,
+    bbox: Option<Rect>,
+}
+
+impl Parcel {
+    fn new(common: CommonProperties, feature: Feature) -> Self {
+        let bbox = feature.geometry.bounding_rect();
+        Parcel {
+            common,
+            props: feature.props,
+            geometry: feature.geometry,
+            bbox,
+        }
+    }
+
+    fn extend(&mut self, feature: Feature) {
+        self.geometry.0.extend(feature.geometry.0);
+        self.bbox = self.geometry.bounding_rect();
+    }
+
+    /// Whether coordinates are longitude / latitude (everything except 任意座標系).
+    fn is_geographic(&self) -> bool {
+        self.common.座標系 != "任意座標系"
+    }
+
+    /// Area in square meters (or square units of the arbitrary coordinate system).
+    fn area(&self) -> f64 {
+        if self.is_geographic() {
+            self.geometry.geodesic_area_unsigned()
+        } else {
+            self.geometry.unsigned_area()
+        }
+    }
+
+    fn centroid(&self) -> Option<Point> {
+        self.geometry.centroid()
+    }
+}
+
+#[derive(Debug)]
+pub struct Change {
+    pub key: ParcelKey,
+    pub change_type: ChangeType,
+    /// 地番 of the parcels this one was split from / into or merged with.
+    pub related: Vec<String>,
+    pub old_area: Option<f64>,
+    pub new_area: Option<f64>,
+    /// Distance between the old and new centroids, in meters.
+    pub centroid_shift: Option<f64>,
+    common: CommonProperties,
+    props: FeatureProperties,
+    /// The new geometry, or the old one for removed and merged-away parcels.
+    geometry: MultiPolygon,
+}
+
+impl Change {
+    fn is_geographic(&self) -> bool {
+        self.common.座標系 != "任意座標系"
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct DiffSummary {
+    pub counts: BTreeMap<&'static str, usize>,
+    /// Features without a 地番, which were left out of the comparison.
+    pub unkeyed: usize,
+    pub failed_files: usize,
+    pub output_written: bool,
+}
+
+/// Loads both datasets, compares them and writes the changes to `output_path`.
+pub fn diff_datasets(
+    output_path: &Path,
+    old: &[PathBuf],
+    new: &[PathBuf],
+    parse_options: &ParseOptions,
+    options: &DiffOptions,
+) -> Result<DiffSummary> {
+    info!("Loading old dataset...");
+    let old = load_dataset(old, parse_options)?;
+    info!("Loading new dataset...");
+    let new = load_dataset(new, parse_options)?;
+    let failed_files = old.failed_files + new.failed_files;
+
+    let (old, old_unkeyed) = group_parcels(old);
+    let (new, new_unkeyed) = group_parcels(new);
+    let changes = compare(&old, &new, options);
+
+    let mut summary = DiffSummary {
+        unkeyed: old_unkeyed + new_unkeyed,
+        failed_files,
+        ..Default::default()
+    };
+    for change in &changes {
+        *summary
+            .counts
+            .entry(change.change_type.as_str())
+            .or_insert(0) += 1;
+    }
+    summary.output_written = write_changes(output_path, &changes)?;
+    Ok(summary)
+}
+
+fn group_parcels(dataset: Dataset) -> (BTreeMap<ParcelKey, Parcel>, usize) {
+    let mut parcels: BTreeMap<ParcelKey, Parcel> = BTreeMap::new();
+    let mut unkeyed = 0;
+    for (common, feature) in dataset.features {
+        let Some(key) = ParcelKey::new(&common, &feature.props) else {
+            unkeyed += 1;
+            continue;
+        };
+        match parcels.get_mut(&key) {
+            Some(parcel) => parcel.extend(feature),
+            None => {
+                parcels.insert(key, Parcel::new(common, feature));
+            }
+        }
+    }
+    (parcels, unkeyed)
+}
+
+/// Bounding boxes of the parcels of each 大字, for finding the parcel containing a point.
+type AreaIndex<'a> =
+    HashMap<(&'a str, &'a str), RTree<GeomWithData<Rectangle<[f64; 2]>, &'a ParcelKey>>>;
+
+fn by_area(parcels: &BTreeMap<ParcelKey, Parcel>) -> AreaIndex<'_> {
+    let mut boxes: HashMap<_, Vec<_>> = HashMap::new();
+    for (key, parcel) in parcels {
+        if let Some(bbox) = parcel.bbox {
+            let rect = Rectangle::from_corners(bbox.min().x_y().into(), bbox.max().x_y().into());
+            boxes
+                .entry(key.area_key())
+                .or_default()
+                .push(GeomWithData::new(rect, key));
+        }
+    }
+    boxes
+        .into_iter()
+        .map(|(area, boxes)| (area, RTree::bulk_load(boxes)))
+        .collect()
+}
+
+/// Finds the parcel in `candidates` (other than `key` itself) containing the interior point of `parcel`.
+fn find_container<'a>(
+    key: &ParcelKey,
+    parcel: &Parcel,
+    candidates: &'a BTreeMap<ParcelKey, Parcel>,
+    index: &AreaIndex<'a>,
+) -> Option<&'a ParcelKey> {
+    let point = parcel.geometry.interior_point()?;
+    index
+        .get(&key.area_key())?
+        .locate_all_at_point(&[point.x(), point.y()])
+        .map(|candidate| candidate.data)
+        .filter(|candidate| *candidate != key && candidates[*candidate].geometry.contains(&point))
+        // The lowest key, so overlapping candidates resolve the same way every run.
+        .min()
+}
+
+fn compare(
+    old: &BTreeMap<ParcelKey, Parcel>,
+    new: &BTreeMap<ParcelKey, Parcel>,
+    options: &DiffOptions,
+) -> Vec<Change> {
+    let old_index = by_area(old);
+    let new_index = by_area(new);
+
+    // Related 地番 for every key involved in a split or merge.
+    let mut split: BTreeMap<&ParcelKey, BTreeSet<String>> = BTreeMap::new();
+    let mut merged: BTreeMap<&ParcelKey, BTreeSet<String>> = BTreeMap::new();
+    for (key, parcel) in new.iter().filter(|(key, _)| !old.contains_key(*key)) {
+        if let Some(source) = find_container(key, parcel, old, &old_index) {
+            split.entry(key).or_default().insert(source.地番.clone());
+            split.entry(source).or_default().insert(key.地番.clone());
+        }
+    }
+    for (key, parcel) in old.iter().filter(|(key, _)| !new.contains_key(*key)) {
+        if let Some(target) = find_container(key, parcel, new, &new_index) {
+            merged.entry(key).or_default().insert(target.地番.clone());
+            merged.entry(target).or_default().insert(key.地番.clone());
+        }
+    }
+
+    let keys: BTreeSet<&ParcelKey> = old.keys().chain(new.keys()).collect();
+    let mut changes = Vec::new();
+    for key in keys {
+        let old_parcel = old.get(key);
+        let new_parcel = new.get(key);
+        let (change_type, related) = if let Some(related) = split.get(key) {
+            (ChangeType::Split, related)
+        } else if let Some(related) = merged.get(key) {
+            (ChangeType::Merged, related)
+        } else {
+            let change_type = match (old_parcel, new_parcel) {
+                (Some(o), Some(n)) if same_geometry(&o.geometry, &n.geometry) => {
+                    ChangeType::Unchanged
+                }
+                (Some(_), Some(_)) => ChangeType::Changed,
+                (Some(_), None) => ChangeType::Removed,
+                (None, _) => ChangeType::Added,
+            };
+            (change_type, &BTreeSet::new())
+        };
+        if change_type == ChangeType::Unchanged && !options.include_unchanged {
+            continue;
+        }
+        // Prefer the new parcel's attributes and geometry where there is one.
+        let parcel = new_parcel.or(old_parcel).unwrap();
+        let centroid_shift = match (old_parcel, new_parcel) {
+            (Some(o), Some(n)) => centroid_shift(o, n),
+            _ => None,
+        };
+        changes.push(Change {
+            key: key.clone(),
+            change_type,
+            related: related.iter().cloned().collect(),
+            old_area: old_parcel.map(Parcel::area),
+            new_area: new_parcel.map(Parcel::area),
+            centroid_shift,
+            common: parcel.common.clone(),
+            props: parcel.props.clone(),
+            geometry: parcel.geometry.clone(),
+        });
+    }
+    changes
+}
+
+fn same_geometry(a: &MultiPolygon, b: &MultiPolygon) -> bool {
+    use geo::CoordsIter;
+    a.coords_count() == b.coords_count()
+        && a.coords_iter().zip(b.coords_iter()).all(|(a, b)| {
+            (a.x - b.x).abs() <= COORD_TOLERANCE && (a.y - b.y).abs() <= COORD_TOLERANCE
+        })
+}
+
+fn centroid_shift(old: &Parcel, new: &Parcel) -> Option<f64> {
+    let (a, b) = (old.centroid()?, new.centroid()?);
+    Some(if new.is_geographic() {
+        Haversine.distance(a, b)
+    } else {
+        Euclidean.distance(a, b)
+    })
+}
+
+/// Writes the change layer. Returns `false` (and removes the file left by an earlier
+/// run) if there were no changes.
+fn write_changes(output_path: &Path, changes: &[Change]) -> Result<bool> {
+    if changes.is_empty() {
+        remove_output(output_path)?;
+        return Ok(false);
+    }
+    // 任意座標系 coordinates aren't longitude / latitude, so the CRS is left unknown
+    // if any of the parcels are in it.
+    let crs = if changes.iter().all(|change| change.is_geographic()) {
+        FgbCrs {
+            code: 4326,
+            ..Default::default()
+        }
+    } else {
+        FgbCrs::default()
+    };
+    let mut fgb = FgbWriter::create_with_options(
+        "mojxml_diff",
+        GeometryType::MultiPolygon,
+        FgbWriterOptions {
+            crs,
+            ..Default::default()
+        },
+    )?;
+    fgb.add_column("global_id", ColumnType::String, |_, col| {
+        col.primary_key = true;
+    });
+    fgb.add_column("change_type", ColumnType::String, |_, _| {});
+    for name in [
+        "市区町村コード",
+        "大字コード",
+        "丁目コード",
+        "小字コード",
+        "地番",
+    ] {
+        fgb.add_column(name, ColumnType::String, |_, _| {});
+    }
+    for name in ["地図名", "大字名", "丁目名", "小字名", "related"] {
+        fgb.add_column(name, ColumnType::String, |_, col| {
+            col.nullable = true;
+        });
+    }
+    for name in [
+        "old_area_m2",
+        "new_area_m2",
+        "area_delta_m2",
+        "centroid_shift_m",
+    ] {
+        fgb.add_column(name, ColumnType::Double, |_, col| {
+            col.nullable = true;
+        });
+    }
+
+    for change in changes {
+        let mut props_result = Ok(());
+        fgb.add_feature_geom(Geometry::from(change.geometry.clone()), |feat| {
+            props_result = write_change_properties(feat, change);
+        })?;
+        props_result?;
+    }
+
+    let mut writer = BufWriter::new(File::create(output_path)?);
+    fgb.write(&mut writer)?;
+    writer.flush()?;
+    Ok(true)
+}
+
+fn write_change_properties(
+    feat: &mut impl PropertyProcessor,
+    change: &Change,
+) -> geozero::error::Result<()> {
+    let key = &change.key;
+    feat.property(
+        0,
+        "global_id",
+        &ColumnValue::String(&id::global_id(&change.common, &change.props)),
+    )?;
+    let strings = [
+        (1, "change_type", Some(change.change_type.as_str())),
+        (2, "市区町村コード", Some(key.市区町村コード.as_str())),
+        (3, "大字コード", Some(key.大字コード.as_str())),
+        (4, "丁目コード", Some(key.丁目コード.as_str())),
+        (5, "小字コード", Some(key.小字コード.as_str())),
+        (6, "地番", Some(key.地番.as_str())),
+        (7, "地図名", Some(change.common.地図名.as_str())),
+        (8, "大字名", change.props.大字名.as_deref()),
+        (9, "丁目名", change.props.丁目名.as_deref()),
+        (10, "小字名", change.props.小字名.as_deref()),
+    ];
+    for (i, name, value) in strings {
+        if let Some(v) = value {
+            feat.property(i, name, &ColumnValue::String(v))?;
+        }
+    }
+    if !change.related.is_empty() {
+        feat.property(
+            11,
+            "related",
+            &ColumnValue::String(&change.related.join(",")),
+        )?;
+    }
+    let area_delta = match (change.old_area, change.new_area) {
+        (Some(old), Some(new)) => Some(new - old),
+        _ => None,
+    };
+    let numbers = [
+        (12, "old_area_m2", change.old_area),
+        (13, "new_area_m2", change.new_area),
+        (14, "area_delta_m2", area_delta),
+        (15, "centroid_shift_m", change.centroid_shift),
+    ];
+    for (i, name, value) in numbers {
+        if let Some(v) = value {
+            feat.property(i, name, &ColumnValue::Double(v))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geo_types::polygon;
+
+    fn square(x: f64, y: f64, w: f64, h: f64) -> MultiPolygon {
+        MultiPolygon::from(vec![polygon![
+            (x: x, y: y),
+            (x: x + w, y: y),
+            (x: x + w, y: y + h),
+            (x: x, y: y + h),
+            (x: x, y: y),
+        ]])
+    }
+
+    fn dataset(parcels: &[(&str, MultiPolygon)]) -> BTreeMap<ParcelKey, Parcel> {
+        let features = parcels
+            .iter()
+            .map(|(chiban, geometry)| {
+                let common = CommonProperties {
+                    地図名: "テスト地図".to_string(),
+                    市区町村コード: "46505".to_string(),
+                    座標系: "公共座標2系".to_string(),
+                    ..Default::default()
+                };
+                let feature = Feature {
+                    geometry: geometry.clone(),
+                    props: FeatureProperties {
+                        大字コード: Some("001".to_string()),
+                        地番: Some(chiban.to_string()),
+                        ..Default::default()
+                    },
+                };
+                (common, feature)
+            })
+            .collect();
+        group_parcels(Dataset {
+            features,
+            failed_files: 0,
+        })
+        .0
+    }
+
+    #[test]
+    fn test_compare() {
+        let e = 0.0001;
+        let old = dataset(&[
+            ("1", square(0.0, 0.0, e, e)),
+            ("2", square(e, 0.0, e, e)),
+            ("3", square(2.0 * e, 0.0, e, e)),
+            ("4", square(3.0 * e, 0.0, e, e)),
+            ("5", square(4.0 * e, 0.0, e, e)),
+            ("6", square(5.0 * e, 0.0, e, e)),
+        ]);
+        let new = dataset(&[
+            // 1 is unchanged, 2 moved slightly, 3 was removed.
+            ("1", square(0.0, 0.0, e, e)),
+            ("2", square(e, 0.0, e, e * 1.1)),
+            // 4 was split into 4 and 4-2.
+            ("4", square(3.0 * e, 0.0, e / 2.0, e)),
+            ("4-2", square(3.5 * e, 0.0, e / 2.0, e)),
+            // 6 was merged into 5.
+            ("5", square(4.0 * e, 0.0, 2.0 * e, e)),
+            // 7 is new, outside of any old parcel.
+            ("7", square(0.0, 2.0 * e, e, e)),
+        ]);
+        let changes = compare(&old, &new, &DiffOptions::default());
+        let summary: Vec<_> = changes
+            .iter()
+            .map(|c| (c.key.地番.as_str(), c.change_type, c.related.join(",")))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("2", ChangeType::Changed, "".to_string()),
+                ("3", ChangeType::Removed, "".to_string()),
+                ("4", ChangeType::Split, "4-2".to_string()),
+                ("4-2", ChangeType::Split, "4".to_string()),
+                ("5", ChangeType::Merged, "6".to_string()),
+                ("6", ChangeType::Merged, "5".to_string()),
+                ("7", ChangeType::Added, "".to_string()),
+            ]
+        );
+
+        let changed = &changes[0];
+        let delta = changed.new_area.unwrap() - changed.old_area.unwrap();
+        // 0.0001° is roughly 11m, so the parcel grew by about 12m².
+        assert!(delta > 10.0 && delta < 15.0, "unexpected delta {}", delta);
+        assert!(changed.centroid_shift.unwrap() > 0.0);
+
+        let with_unchanged = compare(
+            &old,
+            &new,
+            &DiffOptions {
+                include_unchanged: true,
+            },
+        );
+        assert_eq!(with_unchanged[0].change_type, ChangeType::Unchanged);
+    }
+
+    #[test]
+    fn test_write_changes_crs() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let output = dir.path().join("diff.fgb");
+        let crs_code = |changes: &[Change]| -> Result<Option<i32>> {
+            assert!(write_changes(&output, changes)?);
+            let fgb = flatgeobuf::FgbReader::open(File::open(&output)?)?;
+            Ok(fgb.header().crs().map(|crs| crs.code()))
+        };
+
+        let old = dataset(&[("1", square(0.0, 0.0, 1.0, 1.0))]);
+        let mut changes = compare(&old, &BTreeMap::new(), &DiffOptions::default());
+        assert_eq!(crs_code(&changes)?, Some(4326));
+        // 任意座標系 parcels leave the CRS unknown.
+        changes[0].common.座標系 = "任意座標系".to_string();
+        assert_eq!(crs_code(&changes)?, Some(0));
+
+        // Without changes, the layer of the earlier run is removed.
+        assert!(!write_changes(&output, &[])?);
+        assert!(!output.exists());
+        Ok(())
+    }
+}
@@ -1,4 +1,6 @@
 // use proj::ProjCreateError;
+use crate::report::RunReport;
+use std::path::PathBuf;
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -18,10 +20,159 @@ pub enum Error {
     PointNotFound(String),
     #[error("Unexpected XML element: {0}")]
     UnexpectedElement(String),
+    #[error("Element {element} in unknown namespace: {namespace}")]
+    UnknownNamespace { element: String, namespace: String },
+    #[error("Duplicate ID: {0}")]
+    DuplicateId(String),
+    #[error("Failed to find {element} referenced by ID: {id}")]
+    ReferenceNotFound { element: String, id: String },
     #[error("Projection error: {0}")]
     Projection(#[from] proj4rs::errors::Error),
     #[error("IO error: {0}")]
     FS(#[from] std::io::Error),
+    #[error("{source} at {location}")]
+    Located {
+        location: Location,
+        source: Box<Error>,
+    },
+}
+
+/// Where in an XML file an error was found.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Location {
+    pub file_name: Option<String>,
+    /// 1-based line and column of the element the error was found at.
+    pub position: Option<(u32, u32)>,
+    /// The enclosing element, e.g. `筆 H000000001` or `GM_Surface F000000001`.
+    pub element: Option<String>,
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.file_name.as_deref().unwrap_or("<unknown>"))?;
+        if let Some((line, col)) = self.position {
+            write!(f, ":{}:{}", line, col)?;
+        }
+        if let Some(element) = &self.element {
+            write!(f, " ({})", element)?;
+        }
+        Ok(())
+    }
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
+
+/// Fatal errors that stop the conversion pipeline in `processor`.
+#[derive(Debug, thiserror::Error)]
+pub enum PipelineError {
+    #[error("Failed to create output file {}: {error}", path.display())]
+    CreateOutput { path: PathBuf, error: anyhow::Error },
+    #[error("Failed to write output file {}: {error}", path.display())]
+    Flush { path: PathBuf, error: anyhow::Error },
+    #[error("Failed to remove output file {}: {error}", path.display())]
+    Cleanup { path: PathBuf, error: anyhow::Error },
+    #[error("Cache error: {0}")]
+    Cache(anyhow::Error),
+    #[error("The {0} thread panicked")]
+    ThreadPanicked(&'static str),
+}
+
+impl PipelineError {
+    /// Short, stable name of the error variant, used for reporting.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            PipelineError::CreateOutput { .. } => "CreateOutput",
+            PipelineError::Flush { .. } => "Flush",
+            PipelineError::Cleanup { .. } => "Cleanup",
+            PipelineError::Cache(_) => "Cache",
+            PipelineError::ThreadPanicked(_) => "ThreadPanicked",
+        }
+    }
+}
+
+/// A pipeline run that stopped with an error, with the report of what was done before.
+#[derive(Debug, thiserror::Error)]
+#[error("{error}")]
+pub struct PipelineFailure {
+    pub error: PipelineError,
+    pub report: Box<RunReport>,
+}
+
+impl Error {
+    /// Short, stable name of the error variant, used for reporting.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Xml(_) => "Xml",
+            Error::Encoding(_) => "Encoding",
+            Error::MissingElement(_) => "MissingElement",
+            Error::UnsupportedCrs(_) => "UnsupportedCrs",
+            Error::MissingAttribute { .. } => "MissingAttribute",
+            Error::ParseFloat(_) => "ParseFloat",
+            Error::PointNotFound(_) => "PointNotFound",
+            Error::UnexpectedElement(_) => "UnexpectedElement",
+            Error::UnknownNamespace { .. } => "UnknownNamespace",
+            Error::DuplicateId(_) => "DuplicateId",
+            Error::ReferenceNotFound { .. } => "ReferenceNotFound",
+            Error::Projection(_) => "Projection",
+            Error::FS(_) => "FS",
+            Error::Located { source, .. } => source.kind(),
+        }
+    }
+
+    /// Location information attached with [`Error::at`], [`Error::within`] or [`Error::in_file`].
+    pub fn location(&self) -> Option<&Location> {
+        match self {
+            Error::Located { location, .. } => Some(location),
+            _ => None,
+        }
+    }
+
+    /// Records the position of `node`, unless a more precise position is already known.
+    pub fn at(self, node: &roxmltree::Node) -> Self {
+        let pos = node.document().text_pos_at(node.range().start);
+        self.at_position(pos.row, pos.col)
+    }
+
+    /// Records a 1-based line and column, unless a more precise position is already known.
+    pub fn at_position(self, line: u32, column: u32) -> Self {
+        self.with_location(|location| {
+            location.position.get_or_insert((line, column));
+        })
+    }
+
+    /// Records the enclosing element (and its id), unless one is already known.
+    pub fn within(self, element: &str, id: Option<&str>) -> Self {
+        self.with_location(|location| {
+            location.element.get_or_insert_with(|| match id {
+                Some(id) => format!("{} {}", element, id),
+                None => element.to_string(),
+            });
+        })
+    }
+
+    pub fn in_file(self, file_name: &str) -> Self {
+        self.with_location(|location| {
+            location.file_name = Some(file_name.to_string());
+        })
+    }
+
+    fn with_location(self, f: impl FnOnce(&mut Location)) -> Self {
+        match self {
+            Error::Located {
+                mut location,
+                source,
+            } => {
+                f(&mut location);
+                Error::Located { location, source }
+            }
+            other => {
+                let mut location = Location::default();
+                f(&mut location);
+                Error::Located {
+                    location,
+                    source: Box::new(other),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,579 @@
+//! Geocoding addresses (市区町村 + 大字・丁目 + 地番) to parcels of a converted dataset.
+//!
+//! The parcels of a FlatGeobuf file are indexed by normalised address parts, so that
+//! the usual ways of writing the same address find the same parcel: full-width and
+//! half-width characters, kanji and arabic numerals (三丁目 / 3丁目, 二十五番 / 25番),
+//! 番地 variants (12番地3 / 12番3 / 12の3 / 12-3), a leading 大字 or 字, and the 郡 or
+//! prefecture before the municipality name.
+
+use crate::dataset::feature_from_fgb;
+use crate::id;
+use crate::query;
+use anyhow::{Result, bail};
+use flatgeobuf::{FallibleStreamingIterator, FgbReader};
+use geo::InteriorPoint;
+use geo_types::{MultiPolygon, Point};
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::path::Path;
+
+/// Columns added to the input CSV, besides the optional `geometry`.
+pub const RESULT_COLUMNS: [&str; 4] = ["global_id", "lon", "lat", "matches"];
+
+/// A parcel found by its address.
+#[derive(Debug, Clone)]
+pub struct Parcel {
+    pub global_id: String,
+    pub 市区町村名: String,
+    pub 大字名: Option<String>,
+    pub 丁目名: Option<String>,
+    pub 地番: Option<String>,
+    /// A point inside the parcel.
+    pub point: Point,
+    /// Only kept when the index was built with `keep_geometry`.
+    pub geometry: Option<MultiPolygon>,
+}
+
+impl Parcel {
+    /// A GeoJSON feature with the parcel's geometry, or its point if the geometry wasn't
+    /// kept.
+    pub fn feature_json(&self) -> Value {
+        let geometry = match &self.geometry {
+            Some(geometry) => query::geometry_json(geometry),
+            None => json!({ "type": "Point", "coordinates": [self.point.x(), self.point.y()] }),
+        };
+        json!({
+            "type": "Feature",
+            "properties": {
+                "global_id": self.global_id,
+                "市区町村名": self.市区町村名,
+                "大字名": self.大字名,
+                "丁目名": self.丁目名,
+                "地番": self.地番,
+            },
+            "geometry": geometry,
+        })
+    }
+}
+
+/// Normalised address parts of all parcels of a dataset.
+pub struct AddressIndex {
+    parcels: Vec<Parcel>,
+    /// (municipality, area, 地番) → parcels, all normalised.
+    by_address: HashMap<(String, String, String), Vec<usize>>,
+    /// Ways of writing a municipality (with and without 郡) → normalised names.
+    municipalities: HashMap<String, Vec<String>>,
+    /// Normalised municipality → ways of writing its areas (大字 + 丁目, optionally
+    /// followed by 小字) → normalised area.
+    areas: HashMap<String, HashMap<String, String>>,
+    keep_geometry: bool,
+}
+
+impl AddressIndex {
+    /// Reads every parcel of a FlatGeobuf file written by this tool.
+    pub fn build(path: &Path, keep_geometry: bool) -> Result<Self> {
+        let mut index = Self {
+            parcels: Vec::new(),
+            by_address: HashMap::new(),
+            municipalities: HashMap::new(),
+            areas: HashMap::new(),
+            keep_geometry,
+        };
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut iter = FgbReader::open(&mut reader)?.select_all()?;
+        while let Some(feature) = iter.next()? {
+            let (common, feature) = feature_from_fgb(feature)?;
+            let Some(point) = feature.geometry.interior_point() else {
+                continue;
+            };
+            let municipality = normalize(&common.市区町村名);
+            for name in municipality_names(&municipality) {
+                let names = index.municipalities.entry(name).or_default();
+                if !names.contains(&municipality) {
+                    names.push(municipality.clone());
+                }
+            }
+            let props = &feature.props;
+            let area = area_key(props.大字名.as_deref(), props.丁目名.as_deref());
+            let areas = index.areas.entry(municipality.clone()).or_default();
+            areas.entry(area.clone()).or_insert_with(|| area.clone());
+            if let Some(小字名) = props.小字名.as_deref() {
+                let with_koaza = format!("{}{}", area, strip_aza(&normalize(小字名)));
+                areas.entry(with_koaza).or_insert_with(|| area.clone());
+            }
+            let chiban = normalize_chiban(props.地番.as_deref().unwrap_or(""));
+            index
+                .by_address
+                .entry((municipality, area, chiban))
+                .or_default()
+                .push(index.parcels.len());
+            index.parcels.push(Parcel {
+                global_id: id::global_id(&common, props),
+                市区町村名: common.市区町村名,
+                大字名: props.大字名.clone(),
+                丁目名: props.丁目名.clone(),
+                地番: props.地番.clone(),
+                point,
+                geometry: keep_geometry.then_some(feature.geometry),
+            });
+        }
+        Ok(index)
+    }
+
+    pub fn parcel_count(&self) -> usize {
+        self.parcels.len()
+    }
+
+    /// Parcels at an address given in parts. `area` is the 大字 followed by the 丁目, if
+    /// any.
+    pub fn lookup(&self, municipality: &str, area: &str, chiban: &str) -> Vec<&Parcel> {
+        let area = strip_aza(&normalize(area)).to_string();
+        let chiban = normalize_chiban(chiban);
+        let mut found = Vec::new();
+        // The prefecture is stripped before normalising, which would turn the 千 of
+        // 千葉県 into a number.
+        let municipality = normalize(strip_prefecture(municipality.trim()));
+        for municipality in self.municipality_candidates(&municipality) {
+            let area = self
+                .areas
+                .get(municipality)
+                .and_then(|areas| areas.get(&area))
+                .unwrap_or(&area);
+            let key = (municipality.clone(), area.clone(), chiban.clone());
+            if let Some(parcels) = self.by_address.get(&key) {
+                found.extend(parcels.iter().map(|&i| &self.parcels[i]));
+            }
+        }
+        found
+    }
+
+    /// Parcels at an address written as one string, e.g. "鹿児島県熊毛郡屋久島町安房2740番地1".
+    pub fn lookup_address(&self, address: &str) -> Vec<&Parcel> {
+        let address = normalize(address);
+        // The longest municipality name in the address; anything before it is the
+        // prefecture or 郡.
+        let Some((name, rest)) = self
+            .municipalities
+            .keys()
+            .filter_map(|name| {
+                address
+                    .find(name.as_str())
+                    .map(|at| (name, &address[at + name.len()..]))
+            })
+            .max_by_key(|(name, _)| name.len())
+        else {
+            return Vec::new();
+        };
+        let rest = strip_aza(rest);
+        let mut found = Vec::new();
+        for municipality in &self.municipalities[name] {
+            let Some(areas) = self.areas.get(municipality) else {
+                continue;
+            };
+            // The longest area name the rest starts with; the remainder is the 地番.
+            let area = areas
+                .iter()
+                .filter(|(name, _)| rest.starts_with(name.as_str()))
+                .max_by_key(|(name, _)| name.len());
+            let Some((name, area)) = area else {
+                continue;
+            };
+            let key = (
+                municipality.clone(),
+                area.clone(),
+                normalize_chiban(&rest[name.len()..]),
+            );
+            if let Some(parcels) = self.by_address.get(&key) {
+                found.extend(parcels.iter().map(|&i| &self.parcels[i]));
+            }
+        }
+        found
+    }
+
+    fn municipality_candidates(&self, municipality: &str) -> &[String] {
+        self.municipalities
+            .get(municipality)
+            .or_else(|| {
+                municipality_names(municipality)
+                    .last()
+                    .and_then(|short| self.municipalities.get(short))
+            })
+            .map_or(&[], Vec::as_slice)
+    }
+
+    /// Reads addresses from a CSV file with a header row and writes it back with
+    /// [`RESULT_COLUMNS`] (and `geometry` as WKT, if the index keeps geometries)
+    /// appended, from the first parcel found. The addresses are either split into
+    /// 市区町村名, 大字名, optionally 丁目名, and 地番 columns, or in one `address` (住所,
+    /// 所在) column. Returns the number of addresses and how many of them were found.
+    pub fn geocode_csv(&self, input: impl Read, out: impl Write) -> Result<(usize, usize)> {
+        let mut reader = csv::Reader::from_reader(input);
+        let headers = reader.headers()?.clone();
+        let column = |names: &[&str]| {
+            headers
+                .iter()
+                .position(|header| names.contains(&header.trim().to_lowercase().as_str()))
+        };
+        enum Columns {
+            Parts(usize, usize, Option<usize>, usize),
+            Address(usize),
+        }
+        let columns = match (
+            column(&["市区町村名", "市区町村"]),
+            column(&["大字名", "大字"]),
+            column(&["地番"]),
+            column(&["address", "住所", "所在"]),
+        ) {
+            (Some(municipality), Some(area), Some(chiban), _) => {
+                Columns::Parts(municipality, area, column(&["丁目名", "丁目"]), chiban)
+            }
+            (_, _, _, Some(address)) => Columns::Address(address),
+            _ => bail!(
+                "The input needs 市区町村名, 大字名 and 地番 columns (丁目名 is optional) or an \
+                 address column (address, 住所 or 所在)"
+            ),
+        };
+        let mut writer = csv::Writer::from_writer(out);
+        let mut result_columns = RESULT_COLUMNS.to_vec();
+        if self.keep_geometry {
+            result_columns.push("geometry");
+        }
+        writer.write_record(headers.iter().chain(result_columns.iter().copied()))?;
+        let (mut total, mut found) = (0, 0);
+        for record in reader.records() {
+            let record = record?;
+            let field = |column: usize| record.get(column).unwrap_or("");
+            let parcels = match columns {
+                Columns::Parts(municipality, area, chome, chiban) => {
+                    let area = format!("{}{}", field(area), chome.map_or("", field));
+                    self.lookup(field(municipality), &area, field(chiban))
+                }
+                Columns::Address(address) => self.lookup_address(field(address)),
+            };
+            total += 1;
+            let mut values = vec![String::new(); result_columns.len()];
+            if let Some(parcel) = parcels.first() {
+                found += 1;
+                values[0] = parcel.global_id.clone();
+                values[1] = parcel.point.x().to_string();
+                values[2] = parcel.point.y().to_string();
+                if let Some(geometry) = &parcel.geometry {
+                    values[4] = query::wkt(geometry);
+                }
+            }
+            values[3] = parcels.len().to_string();
+            writer.write_record(record.iter().chain(values.iter().map(String::as_str)))?;
+        }
+        writer.flush()?;
+        Ok((total, found))
+    }
+}
+
+/// Unifies the ways of writing the same text: full-width alphanumerics become
+/// half-width, kanji numerals become arabic numerals, dashes become "-" and spaces are
+/// removed.
+pub fn normalize(text: &str) -> String {
+    let mut chars = Vec::new();
+    for c in text.chars() {
+        let c = match c {
+            // Full-width forms are offset from ASCII by the same amount.
+            '０'..='９' | 'Ａ'..='Ｚ' | 'ａ'..='ｚ' => {
+                char::from_u32(c as u32 - '０' as u32 + '0' as u32).unwrap_or(c)
+            }
+            '－' | '‐' | '‑' | '‒' | '–' | '—' | '―' | '−' | 'ｰ' => '-',
+            'ー' if chars.last().is_some_and(char::is_ascii_digit) => '-',
+            c if c.is_whitespace() => continue,
+            c => c,
+        };
+        chars.push(c);
+    }
+
+    let mut normalized = String::with_capacity(text.len());
+    let mut run = Vec::new();
+    for c in chars {
+        if kanji_digit(c).is_some() || kanji_unit(c).is_some() {
+            run.push(c);
+            continue;
+        }
+        if !run.is_empty() {
+            normalized.push_str(&kanji_number(&run));
+            run.clear();
+        }
+        normalized.push(c);
+    }
+    if !run.is_empty() {
+        normalized.push_str(&kanji_number(&run));
+    }
+    normalized
+}
+
+/// Normalises a 地番 to numbers separated by "-": "12番地の3", "12番3" and "12の3"
+/// all become "12-3".
+pub fn normalize_chiban(chiban: &str) -> String {
+    let normalized = normalize(chiban)
+        .replace("番地", "-")
+        .replace(['番', 'の', '之'], "-");
+    let parts: Vec<&str> = normalized.split('-').filter(|p| !p.is_empty()).collect();
+    parts.join("-")
+}
+
+fn kanji_digit(c: char) -> Option<u64> {
+    "〇一二三四五六七八九"
+        .chars()
+        .position(|d| d == c)
+        .map(|d| d as u64)
+}
+
+fn kanji_unit(c: char) -> Option<u64> {
+    match c {
+        '十' => Some(10),
+        '百' => Some(100),
+        '千' => Some(1000),
+        _ => None,
+    }
+}
+
+/// "二千七百四十" → "2740"; digits alone are read positionally, so "二七四〇" → "2740".
+fn kanji_number(run: &[char]) -> String {
+    if !run.iter().any(|&c| kanji_unit(c).is_some()) {
+        return run
+            .iter()
+            .filter_map(|&c| kanji_digit(c))
+            .map(|d| d.to_string())
+            .collect();
+    }
+    let (mut total, mut current) = (0, 0);
+    for &c in run {
+        if let Some(digit) = kanji_digit(c) {
+            current = current * 10 + digit;
+        } else if let Some(unit) = kanji_unit(c) {
+            total += current.max(1) * unit;
+            current = 0;
+        }
+    }
+    (total + current).to_string()
+}
+
+fn area_key(大字名: Option<&str>, 丁目名: Option<&str>) -> String {
+    format!(
+        "{}{}",
+        strip_aza(&normalize(大字名.unwrap_or(""))),
+        normalize(丁目名.unwrap_or(""))
+    )
+}
+
+fn strip_aza(area: &str) -> &str {
+    area.strip_prefix("大字")
+        .or_else(|| area.strip_prefix('字'))
+        .unwrap_or(area)
+}
+
+/// "鹿児島県熊毛郡屋久島町" → "熊毛郡屋久島町". Takes text that hasn't been normalised.
+fn strip_prefecture(municipality: &str) -> &str {
+    PREFECTURES
+        .iter()
+        .find_map(|prefecture| municipality.strip_prefix(prefecture))
+        .unwrap_or(municipality)
+}
+
+const PREFECTURES: [&str; 47] = [
+    "北海道",
+    "青森県",
+    "岩手県",
+    "宮城県",
+    "秋田県",
+    "山形県",
+    "福島県",
+    "茨城県",
+    "栃木県",
+    "群馬県",
+    "埼玉県",
+    "千葉県",
+    "東京都",
+    "神奈川県",
+    "新潟県",
+    "富山県",
+    "石川県",
+    "福井県",
+    "山梨県",
+    "長野県",
+    "岐阜県",
+    "静岡県",
+    "愛知県",
+    "三重県",
+    "滋賀県",
+    "京都府",
+    "大阪府",
+    "兵庫県",
+    "奈良県",
+    "和歌山県",
+    "鳥取県",
+    "島根県",
+    "岡山県",
+    "広島県",
+    "山口県",
+    "徳島県",
+    "香川県",
+    "愛媛県",
+    "高知県",
+    "福岡県",
+    "佐賀県",
+    "長崎県",
+    "熊本県",
+    "大分県",
+    "宮崎県",
+    "鹿児島県",
+    "沖縄県",
+];
+
+/// A municipality name with and without its 郡 ("熊毛郡屋久島町", "屋久島町").
+fn municipality_names(municipality: &str) -> Vec<String> {
+    let mut names = vec![municipality.to_string()];
+    if let Some((_, town)) = municipality.split_once('郡')
+        && !town.is_empty()
+    {
+        names.push(town.to_string());
+    }
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::FeatureProperties;
+    use crate::query::tests::write_sample;
+
+    fn sample_index(keep_geometry: bool) -> Result<AddressIndex> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("out.fgb");
+        let props = |大字名: &str, 丁目名: Option<&str>, 小字名: Option<&str>, 地番: &str| {
+            FeatureProperties {
+                筆id: format!("H{}", 地番),
+                大字名: Some(大字名.to_string()),
+                丁目名: 丁目名.map(str::to_string),
+                小字名: 小字名.map(str::to_string),
+                地番: Some(地番.to_string()),
+                ..Default::default()
+            }
+        };
+        write_sample(
+            &path,
+            "熊毛郡屋久島町",
+            vec![
+                (130.5, props("安房", None, Some("松峯"), "2740-1")),
+                (130.501, props("安房", None, None, "2740")),
+                (130.502, props("宮之浦", Some("三丁目"), None, "25")),
+            ],
+        )?;
+        AddressIndex::build(&path, keep_geometry)
+    }
+
+    fn chiban(parcels: Vec<&Parcel>) -> Vec<&str> {
+        parcels.iter().filter_map(|p| p.地番.as_deref()).collect()
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("宮之浦 三丁目"), "宮之浦3丁目");
+        assert_eq!(normalize("二千七百四十番地の一"), "2740番地の1");
+        assert_eq!(normalize("二七四〇"), "2740");
+        assert_eq!(normalize("十五"), "15");
+        assert_eq!(normalize("２７４０－１"), "2740-1");
+        assert_eq!(normalize("２７４０ー１"), "2740-1");
+        assert_eq!(normalize("ニュータウン"), "ニュータウン");
+        for chiban in [
+            "2740番地1",
+            "2740番1",
+            "2740の1",
+            "2740-1",
+            "二七四〇番地の一",
+        ] {
+            assert_eq!(normalize_chiban(chiban), "2740-1", "{}", chiban);
+        }
+        assert_eq!(normalize_chiban("2740番地"), "2740");
+        assert_eq!(strip_prefecture("鹿児島県熊毛郡屋久島町"), "熊毛郡屋久島町");
+        assert_eq!(strip_prefecture("東京都府中市"), "府中市");
+        assert_eq!(strip_prefecture("京都府京都市"), "京都市");
+        assert_eq!(strip_prefecture("千葉県千葉市"), "千葉市");
+        assert_eq!(
+            normalize(strip_prefecture("千葉県千葉市")),
+            normalize("千葉市")
+        );
+    }
+
+    #[test]
+    fn test_lookup() -> Result<()> {
+        let index = sample_index(false)?;
+        assert_eq!(index.parcel_count(), 3);
+        assert_eq!(
+            chiban(index.lookup("屋久島町", "安房", "2740番地1")),
+            ["2740-1"]
+        );
+        assert_eq!(
+            chiban(index.lookup("鹿児島県熊毛郡屋久島町", "大字安房", "2740")),
+            ["2740"]
+        );
+        assert_eq!(
+            chiban(index.lookup("屋久島町", "宮之浦3丁目", "二十五")),
+            ["25"]
+        );
+        assert!(index.lookup("屋久島町", "宮之浦", "25").is_empty());
+        assert!(index.lookup("中種子町", "安房", "2740").is_empty());
+
+        assert_eq!(
+            chiban(index.lookup_address("鹿児島県熊毛郡屋久島町安房2740番地1")),
+            ["2740-1"]
+        );
+        assert_eq!(
+            chiban(index.lookup_address("屋久島町大字安房２７４０")),
+            ["2740"]
+        );
+        assert_eq!(
+            chiban(index.lookup_address("屋久島町安房松峯2740-1")),
+            ["2740-1"]
+        );
+        assert_eq!(
+            chiban(index.lookup_address("屋久島町宮之浦三丁目25番")),
+            ["25"]
+        );
+        assert!(index.lookup_address("屋久島町安房9999").is_empty());
+        assert!(index.lookup_address("安房2740").is_empty());
+
+        let feature = index.lookup_address("屋久島町宮之浦三丁目25")[0].feature_json();
+        assert_eq!(feature["properties"]["丁目名"], "三丁目");
+        assert_eq!(feature["geometry"]["type"], "Point");
+        Ok(())
+    }
+
+    #[test]
+    fn test_geocode_csv() -> Result<()> {
+        let index = sample_index(true)?;
+        let input = "id,市区町村名,大字名,丁目名,地番\n\
+                     a,屋久島町,安房,,2740番地\n\
+                     b,屋久島町,宮之浦,3丁目,25\n\
+                     c,屋久島町,安房,,1\n";
+        let mut out = Vec::new();
+        assert_eq!(index.geocode_csv(input.as_bytes(), &mut out)?, (3, 2));
+        let out = String::from_utf8(out)?;
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            lines[0],
+            "id,市区町村名,大字名,丁目名,地番,global_id,lon,lat,matches,geometry"
+        );
+        assert!(lines[1].starts_with("a,屋久島町,安房,,2740番地,46505-"));
+        assert!(lines[1].contains(",1,\"MULTIPOLYGON((("));
+        assert_eq!(lines[3], "c,屋久島町,安房,,1,,,,0,");
+
+        let input = "住所\n屋久島町安房2740-1\n";
+        let mut out = Vec::new();
+        assert_eq!(index.geocode_csv(input.as_bytes(), &mut out)?, (1, 1));
+        assert!(
+            index
+                .geocode_csv("name\nx\n".as_bytes(), Vec::new())
+                .is_err()
+        );
+        Ok(())
+    }
+}
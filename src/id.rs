@@ -0,0 +1,89 @@
+//! Deterministic feature IDs.
+//!
+//! `筆id` (e.g. `H000000001`) is only unique within one XML file. The global ID is
+//! derived from the canonical parcel key 市区町村コード + 地図名 + 筆id, so the same
+//! parcel gets the same ID in every run and every output format.
+//!
+//! The IDs are hashes of the key and so not guaranteed to be unique: two parcels of
+//! the same 市区町村 get the same ID if their keys collide in 63 bits. That is unlikely
+//! even for a national run, but code that must tell parcels apart for certain should
+//! compare [`parcel_key`] instead.
+
+use crate::parse::{CommonProperties, FeatureProperties};
+use std::hash::Hasher;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// 64-bit FNV-1a. Used instead of `DefaultHasher`, whose output may change between
+/// Rust releases.
+struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Fnv1a(FNV_OFFSET_BASIS)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// The canonical parcel key: 市区町村コード, 地図名 and 筆id, separated by NUL bytes.
+pub fn parcel_key(common: &CommonProperties, props: &FeatureProperties) -> Vec<u8> {
+    [
+        common.市区町村コード.as_bytes(),
+        common.地図名.as_bytes(),
+        props.筆id.as_bytes(),
+    ]
+    .join(&0)
+}
+
+/// Numeric ID: a hash of the canonical parcel key, kept positive so it fits a signed
+/// 64-bit integer column. Not guaranteed to be unique, see the module docs.
+pub fn fid(common: &CommonProperties, props: &FeatureProperties) -> i64 {
+    let mut hasher = Fnv1a::default();
+    hasher.write(&parcel_key(common, props));
+    (hasher.finish() >> 1) as i64
+}
+
+/// String ID of the form `<市区町村コード>-<hex of fid>`, e.g. `46505-7e76b21c23eb5993`.
+/// Unique unless the fids of two parcels of the same 市区町村 collide.
+pub fn global_id(common: &CommonProperties, props: &FeatureProperties) -> String {
+    format!("{}-{:016x}", common.市区町村コード, fid(common, props))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_global_id() {
+        let common = CommonProperties {
+            地図名: "テスト地図".to_string(),
+            市区町村コード: "46505".to_string(),
+            ..Default::default()
+        };
+        let props = FeatureProperties {
+            筆id: "H000000001".to_string(),
+            ..Default::default()
+        };
+        // Fixed value: the ID must not change between releases.
+        assert_eq!(global_id(&common, &props), "46505-7e76b21c23eb5993");
+        assert!(fid(&common, &props) >= 0);
+
+        let other_map = CommonProperties {
+            地図名: "別の地図".to_string(),
+            ..common.clone()
+        };
+        assert_ne!(global_id(&common, &props), global_id(&other_map, &props));
+    }
+}
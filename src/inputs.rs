@@ -0,0 +1,200 @@
+//! Expanding the input arguments into the list of files to read.
+//!
+//! An input can be a file, a directory (walked recursively), or a glob pattern such as
+//! `data/**/*.zip` (for shells that don't expand patterns themselves, or to get around
+//! argument length limits). More inputs can be listed in a file with `--from-file`.
+
+use crate::reader::is_stdin;
+use log::warn;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// Skipped paths listed by [`ExpandedInputs::print_warnings`] before the rest are counted.
+const MAX_LISTED_SKIPPED: usize = 10;
+
+#[derive(Debug, Default)]
+pub struct ExpandedInputs {
+    /// Files to read, in argument order. Files found in a directory or by a pattern are
+    /// sorted by path.
+    pub paths: Vec<PathBuf>,
+    /// Files that were named or found but can't be read.
+    pub skipped: Vec<PathBuf>,
+    /// Patterns that matched nothing.
+    pub unmatched_patterns: Vec<String>,
+}
+
+impl ExpandedInputs {
+    pub fn print_warnings(&self) {
+        for path in &self.skipped {
+            warn!("Skipping unsupported input: {}", path.display());
+        }
+        for (i, path) in self.skipped.iter().enumerate() {
+            if i == MAX_LISTED_SKIPPED {
+                eprintln!("  ... and {} more", self.skipped.len() - MAX_LISTED_SKIPPED);
+                break;
+            }
+            eprintln!("Skipping unsupported input: {}", path.display());
+        }
+        for pattern in &self.unmatched_patterns {
+            warn!("No files match pattern: {}", pattern);
+            eprintln!("No files match pattern: {}", pattern);
+        }
+    }
+}
+
+/// Expands directories and glob patterns. `accept` decides which files can be read.
+///
+/// Paths that don't exist and aren't patterns are kept if `accept` takes them, so that
+/// the error is reported when the input is read.
+pub fn expand_inputs(inputs: &[PathBuf], accept: impl Fn(&Path) -> bool) -> ExpandedInputs {
+    let mut expanded = ExpandedInputs::default();
+    for input in inputs {
+        if input.is_dir() {
+            walk_dir(input, &accept, &mut expanded);
+        } else if !input.exists() && is_pattern(input) {
+            let pattern = input.to_string_lossy();
+            let matches: Vec<PathBuf> = match glob::glob(&pattern) {
+                Ok(paths) => paths.filter_map(Result::ok).collect(),
+                Err(e) => {
+                    warn!("Invalid pattern {}: {}", pattern, e);
+                    Vec::new()
+                }
+            };
+            if matches.is_empty() {
+                expanded.unmatched_patterns.push(pattern.into_owned());
+            }
+            for path in matches {
+                if path.is_dir() {
+                    walk_dir(&path, &accept, &mut expanded);
+                } else {
+                    add_file(path, &accept, &mut expanded);
+                }
+            }
+        } else {
+            add_file(input.clone(), &accept, &mut expanded);
+        }
+    }
+    expanded
+}
+
+fn walk_dir(dir: &Path, accept: &impl Fn(&Path) -> bool, expanded: &mut ExpandedInputs) {
+    for entry in WalkDir::new(dir).follow_links(true).sort_by_file_name() {
+        match entry {
+            Ok(entry) if entry.file_type().is_file() => {
+                add_file(entry.into_path(), accept, expanded);
+            }
+            Ok(_) => {}
+            Err(e) => {
+                // Unreadable subdirectories are kept as inputs so they show up as failed.
+                warn!("Failed to read directory entry: {}", e);
+                if let Some(path) = e.path() {
+                    expanded.paths.push(path.to_path_buf());
+                }
+            }
+        }
+    }
+}
+
+fn add_file(path: PathBuf, accept: &impl Fn(&Path) -> bool, expanded: &mut ExpandedInputs) {
+    if accept(&path) {
+        expanded.paths.push(path);
+    } else {
+        expanded.skipped.push(path);
+    }
+}
+
+fn is_pattern(path: &Path) -> bool {
+    path.to_string_lossy().contains(['*', '?', '['])
+}
+
+/// Reads an input list: one path or pattern per line. Blank lines and lines starting
+/// with `#` are ignored. Relative entries are relative to the directory of the list.
+pub fn read_input_list(path: &Path) -> io::Result<Vec<PathBuf>> {
+    let base = path.parent().unwrap_or(Path::new(""));
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let entry = Path::new(line);
+            if is_stdin(entry) {
+                entry.to_path_buf()
+            } else {
+                base.join(entry)
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::is_supported;
+
+    #[test]
+    fn test_expand_inputs() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("b/nested")).unwrap();
+        for name in [
+            "a.zip",
+            "b/nested/c.xml",
+            "b/d.XML",
+            "b/readme.txt",
+            "e.pdf",
+        ] {
+            fs::write(root.join(name), "").unwrap();
+        }
+
+        let expanded = expand_inputs(
+            &[
+                root.join("e.pdf"),
+                root.join("b"),
+                root.join("*.zip"),
+                root.join("*.gml"),
+                root.join("missing.zip"),
+                root.join("missing.pdf"),
+            ],
+            is_supported,
+        );
+        assert_eq!(
+            expanded.paths,
+            vec![
+                root.join("b/d.XML"),
+                root.join("b/nested/c.xml"),
+                root.join("a.zip"),
+                root.join("missing.zip"),
+            ]
+        );
+        assert_eq!(
+            expanded.skipped,
+            vec![
+                root.join("e.pdf"),
+                root.join("b/readme.txt"),
+                root.join("missing.pdf")
+            ]
+        );
+        assert_eq!(
+            expanded.unmatched_patterns,
+            vec![root.join("*.gml").to_string_lossy()]
+        );
+    }
+
+    #[test]
+    fn test_read_input_list() {
+        let dir = tempfile::tempdir().unwrap();
+        let list = dir.path().join("list.txt");
+        fs::write(&list, "# 2024\na.zip\n\n  data/*.zip  \n/abs/b.zip\n-\n").unwrap();
+        assert_eq!(
+            read_input_list(&list).unwrap(),
+            vec![
+                dir.path().join("a.zip"),
+                dir.path().join("data/*.zip"),
+                PathBuf::from("/abs/b.zip"),
+                PathBuf::from("-"),
+            ]
+        );
+    }
+}
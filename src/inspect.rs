@@ -0,0 +1,300 @@
+//! Summarising a delivery of MOJ XML files before converting it: which municipalities
+//! and coordinate systems it covers and how many 筆 it holds, without writing geodata.
+
+use crate::constants::get_proj;
+use crate::parse::{ParseOptions, parse_xml_content, summarize_xml_content};
+use crate::reader::{ReaderOptions, map_xml_files};
+use crate::report::ErrorReport;
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::PathBuf;
+
+/// Key used in [`Inspection::精度区分`] for 筆 without the element.
+const MISSING: &str = "(なし)";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum InspectFormat {
+    /// Human-readable tables.
+    #[default]
+    Table,
+    Json,
+}
+
+#[derive(Debug, Clone)]
+pub struct InspectOptions {
+    /// Also parse the geometries, to count the features a conversion would produce.
+    pub parse_features: bool,
+    pub threads: usize,
+    pub reader: ReaderOptions,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Inspection {
+    pub input_count: usize,
+    pub xml_file_count: usize,
+    /// Inputs or XML files that could not be read or parsed.
+    pub failures: Vec<Failure>,
+    /// "市区町村コード 市区町村名" → counts.
+    pub municipalities: BTreeMap<String, Counts>,
+    /// 座標系 → counts.
+    pub 座標系: BTreeMap<String, Counts>,
+    /// Files in 任意座標系, which are only converted with --arbitrary.
+    pub arbitrary_crs_files: usize,
+    /// Files with a 座標系 this tool cannot transform.
+    pub unsupported_crs_files: usize,
+    pub fude_count: usize,
+    pub chikugai_count: usize,
+    pub betsuzu_count: usize,
+    pub 精度区分: BTreeMap<String, usize>,
+    /// Only with [`InspectOptions::parse_features`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub features: Option<FeatureCounts>,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct Counts {
+    pub files: usize,
+    pub fude: usize,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct FeatureCounts {
+    /// Features with a valid geometry, excluding 地区外 / 別図.
+    pub parsed: usize,
+    /// Elements (points, curves, surfaces or 筆) that could not be parsed.
+    pub dropped_elements: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Failure {
+    /// The input path, or the XML file name for parse errors.
+    pub file: String,
+    pub error: ErrorReport,
+}
+
+/// Reads all XML files of the inputs and summarises them.
+pub fn inspect(paths: &[PathBuf], options: &InspectOptions) -> Result<Inspection> {
+    let mut inspection = Inspection {
+        input_count: paths.len(),
+        features: options.parse_features.then(FeatureCounts::default),
+        ..Default::default()
+    };
+    // Every geometry is parsed, 任意座標系 files included, to find all broken elements.
+    let parse_options = ParseOptions {
+        include_arbitrary_crs: true,
+        include_chikugai: false,
+        lenient: true,
+    };
+    let results = map_xml_files(paths, &options.reader, options.threads, |file_data| {
+        let summary = summarize_xml_content(&file_data);
+        // Only the counts are kept, not the features.
+        let parsed = match &summary {
+            Ok(_) if options.parse_features => Some(
+                parse_xml_content(&file_data, &parse_options)
+                    .map(|xml| (xml.features.len(), xml.warnings.len())),
+            ),
+            _ => None,
+        };
+        (file_data.file_name, summary, parsed)
+    });
+    for result in results {
+        let (file_name, summary, parsed) = match result {
+            Ok(result) => result,
+            Err((path, e)) => {
+                inspection.failures.push(Failure {
+                    file: path.display().to_string(),
+                    error: ErrorReport::new(e.kind(), &e),
+                });
+                continue;
+            }
+        };
+        inspection.xml_file_count += 1;
+        let summary = match summary {
+            Ok(summary) => summary,
+            Err(e) => {
+                inspection.failures.push(Failure {
+                    file: file_name,
+                    error: ErrorReport::from(&e),
+                });
+                continue;
+            }
+        };
+        let common = &summary.common_props;
+        let municipality = format!("{} {}", common.市区町村コード, common.市区町村名);
+        for counts in [
+            inspection.municipalities.entry(municipality).or_default(),
+            inspection.座標系.entry(common.座標系.clone()).or_default(),
+        ] {
+            counts.files += 1;
+            counts.fude += summary.fude_count;
+        }
+        match get_proj(&common.座標系) {
+            Ok(Some(_)) => {}
+            Ok(None) => inspection.arbitrary_crs_files += 1,
+            Err(_) => inspection.unsupported_crs_files += 1,
+        }
+        inspection.fude_count += summary.fude_count;
+        inspection.chikugai_count += summary.chikugai_count;
+        inspection.betsuzu_count += summary.betsuzu_count;
+        for (value, count) in summary.精度区分 {
+            let value = value.unwrap_or_else(|| MISSING.to_string());
+            *inspection.精度区分.entry(value).or_default() += count;
+        }
+        match parsed {
+            Some(Ok((parsed, dropped_elements))) => {
+                let features = inspection.features.get_or_insert_default();
+                features.parsed += parsed;
+                features.dropped_elements += dropped_elements;
+            }
+            Some(Err(e)) => inspection.failures.push(Failure {
+                file: file_name,
+                error: ErrorReport::from(&e),
+            }),
+            None => {}
+        }
+    }
+    Ok(inspection)
+}
+
+impl Inspection {
+    pub fn write(&self, format: InspectFormat, out: &mut impl Write) -> Result<()> {
+        match format {
+            InspectFormat::Table => self.write_table(out)?,
+            InspectFormat::Json => {
+                serde_json::to_writer_pretty(&mut *out, self)?;
+                writeln!(out)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_table(&self, out: &mut impl Write) -> std::io::Result<()> {
+        writeln!(out, "Inputs:    {}", self.input_count)?;
+        writeln!(out, "XML files: {}", self.xml_file_count)?;
+        writeln!(out, "筆:        {}", self.fude_count)?;
+        writeln!(out, "  地区外:  {}", self.chikugai_count)?;
+        writeln!(out, "  別図:    {}", self.betsuzu_count)?;
+        if let Some(features) = &self.features {
+            writeln!(
+                out,
+                "Parsed features (excluding 地区外 / 別図): {}",
+                features.parsed
+            )?;
+            writeln!(out, "Dropped elements: {}", features.dropped_elements)?;
+        }
+
+        let counts_table = |out: &mut dyn Write, title: &str, rows: &BTreeMap<String, Counts>| {
+            writeln!(out)?;
+            writeln!(out, "{} {:>8} {:>10}", pad(title, 32), "files", "筆")?;
+            for (name, counts) in rows {
+                writeln!(
+                    out,
+                    "{} {:>8} {:>10}",
+                    pad(name, 32),
+                    counts.files,
+                    counts.fude
+                )?;
+            }
+            Ok::<_, std::io::Error>(())
+        };
+        counts_table(out, "Municipality", &self.municipalities)?;
+        counts_table(out, "座標系", &self.座標系)?;
+        if self.arbitrary_crs_files > 0 {
+            writeln!(
+                out,
+                "{} file(s) in 任意座標系 (converted only with --arbitrary).",
+                self.arbitrary_crs_files
+            )?;
+        }
+        if self.unsupported_crs_files > 0 {
+            writeln!(
+                out,
+                "{} file(s) with an unsupported 座標系.",
+                self.unsupported_crs_files
+            )?;
+        }
+
+        writeln!(out)?;
+        writeln!(out, "{} {:>10}", pad("精度区分", 32), "筆")?;
+        for (value, count) in &self.精度区分 {
+            writeln!(out, "{} {:>10}", pad(value, 32), count)?;
+        }
+
+        if !self.failures.is_empty() {
+            writeln!(out)?;
+            writeln!(out, "{} failure(s):", self.failures.len())?;
+            for failure in &self.failures {
+                writeln!(out, "  {}: {}", failure.file, failure.error.message)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Pads `text` to `width` terminal columns, counting non-ASCII characters (kanji and
+/// kana) as two columns.
+fn pad(text: &str, width: usize) -> String {
+    let text_width: usize = text.chars().map(|c| if c.is_ascii() { 1 } else { 2 }).sum();
+    format!("{}{}", text, " ".repeat(width.saturating_sub(text_width)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::tests::SAMPLE_XML;
+    use std::fs;
+
+    #[test]
+    fn test_inspect() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let sample = dir.path().join("a.xml");
+        fs::write(&sample, SAMPLE_XML)?;
+        let chikugai = dir.path().join("b.xml");
+        let fude =
+            r#"<筆 id="H2"><精度区分>甲三</精度区分><地番>地区外</地番><形状 idref="S1"/></筆>"#;
+        fs::write(
+            &chikugai,
+            SAMPLE_XML
+                .replace("任意座標系", "公共座標2系")
+                .replace("</主題属性>", &format!("{}</主題属性>", fude)),
+        )?;
+        let broken = dir.path().join("c.xml");
+        fs::write(&broken, "<地図>")?;
+        let paths = vec![sample, chikugai, broken];
+
+        let mut options = InspectOptions {
+            parse_features: false,
+            threads: 2,
+            reader: ReaderOptions::default(),
+        };
+        let inspection = inspect(&paths, &options)?;
+        assert_eq!(inspection.xml_file_count, 3);
+        assert_eq!(inspection.failures.len(), 1);
+        assert_eq!(inspection.failures[0].file, "c.xml");
+        assert_eq!(inspection.fude_count, 3);
+        assert_eq!(inspection.chikugai_count, 1);
+        assert_eq!(inspection.betsuzu_count, 0);
+        assert_eq!(inspection.arbitrary_crs_files, 1);
+        assert_eq!(inspection.座標系["公共座標2系"].fude, 2);
+        assert_eq!(inspection.municipalities["46505 熊毛郡屋久島町"].files, 2);
+        assert_eq!(inspection.精度区分[MISSING], 2);
+        assert_eq!(inspection.精度区分["甲三"], 1);
+        assert!(inspection.features.is_none());
+
+        options.parse_features = true;
+        let inspection = inspect(&paths, &options)?;
+        let features = inspection.features.unwrap();
+        assert_eq!(features.parsed, 2);
+        assert_eq!(features.dropped_elements, 0);
+
+        let mut table = Vec::new();
+        inspection.write(InspectFormat::Table, &mut table)?;
+        let table = String::from_utf8(table)?;
+        assert!(table.contains("1 file(s) in 任意座標系"));
+        assert!(table.contains("1 failure(s):"));
+        Ok(())
+    }
+}
@@ -0,0 +1,195 @@
+//! Finding elements of a MOJ XML file that are well-formed but not wired up the way the
+//! 地図XML format intends: geometries no one refers to, and surfaces shared by several 筆.
+//!
+//! Parsing only fails when a referenced id is missing, so these go unnoticed during a
+//! conversion. They usually point to data-entry issues in the source data.
+
+use roxmltree::{Document, Node};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum AnomalyKind {
+    /// A GM_Point referenced by no curve, 筆界点 or 基準点.
+    OrphanedPoint,
+    /// A GM_Curve that is not part of the boundary of any GM_Surface.
+    UnusedCurve,
+    /// A GM_Surface that is the 形状 of no 筆.
+    UnreferencedSurface,
+    /// A GM_Surface that is the 形状 of more than one 筆.
+    SharedSurface,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Anomaly {
+    pub kind: AnomalyKind,
+    /// Id of the point, curve or surface.
+    pub id: String,
+    /// 1-based line and column of the element.
+    pub line: u32,
+    pub column: u32,
+    /// Ids of the 筆 sharing the surface, for [`AnomalyKind::SharedSurface`].
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fude: Vec<String>,
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            AnomalyKind::OrphanedPoint => write!(
+                f,
+                "GM_Point {} is not referenced by any curve, 筆界点 or 基準点",
+                self.id
+            ),
+            AnomalyKind::UnusedCurve => {
+                write!(f, "GM_Curve {} is not part of any GM_Surface", self.id)
+            }
+            AnomalyKind::UnreferencedSurface => {
+                write!(f, "GM_Surface {} is not the 形状 of any 筆", self.id)
+            }
+            AnomalyKind::SharedSurface => write!(
+                f,
+                "GM_Surface {} is the 形状 of several 筆: {}",
+                self.id,
+                self.fude.join(", ")
+            ),
+        }
+    }
+}
+
+/// Returns the anomalies of a parsed file, in document order.
+///
+/// Only references by id are followed; references to missing ids are left to
+/// [`crate::validate`].
+pub fn find_anomalies(doc: &Document) -> Vec<Anomaly> {
+    // Any reference keeps a point alive; a curve only counts as used when a surface is
+    // built from it, and GM_OrientablePrimitive.primitive refers a curve to itself.
+    let mut referenced = HashSet::new();
+    let mut generators = HashSet::new();
+    let mut shapes: HashMap<&str, Vec<&str>> = HashMap::new();
+    for node in doc.descendants().filter(Node::is_element) {
+        let Some(idref) = node.attribute("idref") else {
+            continue;
+        };
+        match node.tag_name().name() {
+            "GM_CompositeCurve.generator" => {
+                generators.insert(idref);
+            }
+            "形状"
+                if node
+                    .parent_element()
+                    .is_some_and(|p| p.tag_name().name() == "筆") =>
+            {
+                let fude = node.parent_element().and_then(|p| p.attribute("id"));
+                shapes
+                    .entry(idref)
+                    .or_default()
+                    .push(fude.unwrap_or("(no id)"));
+            }
+            "GM_OrientablePrimitive.primitive" => continue,
+            _ => {}
+        }
+        referenced.insert(idref);
+    }
+
+    let spatial = doc
+        .root_element()
+        .children()
+        .find(|n| n.tag_name().name() == "空間属性");
+    let elements = spatial
+        .iter()
+        .flat_map(|spatial| spatial.children().filter(Node::is_element));
+    let mut anomalies = Vec::new();
+    for node in elements {
+        let Some(id) = node.attribute("id") else {
+            continue;
+        };
+        let anomaly = |kind, fude: &[&str]| {
+            let pos = doc.text_pos_at(node.range().start);
+            Anomaly {
+                kind,
+                id: id.to_string(),
+                line: pos.row,
+                column: pos.col,
+                fude: fude.iter().map(|id| id.to_string()).collect(),
+            }
+        };
+        match node.tag_name().name() {
+            "GM_Point" if !referenced.contains(id) => {
+                anomalies.push(anomaly(AnomalyKind::OrphanedPoint, &[]));
+            }
+            "GM_Curve" if !generators.contains(id) => {
+                anomalies.push(anomaly(AnomalyKind::UnusedCurve, &[]));
+            }
+            "GM_Surface" => match shapes.get(id).map(Vec::as_slice) {
+                None => anomalies.push(anomaly(AnomalyKind::UnreferencedSurface, &[])),
+                Some(fude @ [_, _, ..]) => {
+                    anomalies.push(anomaly(AnomalyKind::SharedSurface, fude));
+                }
+                Some(_) => {}
+            },
+            _ => {}
+        }
+    }
+    anomalies
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::tests::SAMPLE_XML;
+
+    fn anomalies(xml: &str) -> Vec<(AnomalyKind, String)> {
+        find_anomalies(&Document::parse(xml).unwrap())
+            .into_iter()
+            .map(|a| (a.kind, a.id))
+            .collect()
+    }
+
+    #[test]
+    fn test_sample_has_no_anomalies() {
+        assert!(anomalies(SAMPLE_XML).is_empty());
+    }
+
+    #[test]
+    fn test_find_anomalies() {
+        let point = r#"<zmn:GM_Point id="P2"><zmn:GM_Point.position><zmn:DirectPosition><zmn:X>5</zmn:X><zmn:Y>5</zmn:Y></zmn:DirectPosition></zmn:GM_Point.position></zmn:GM_Point>"#;
+        let curve = r#"<zmn:GM_Curve id="C5"><zmn:GM_OrientablePrimitive.primitive idref="C5"/><zmn:GM_Curve.segment/></zmn:GM_Curve>"#;
+        let surface = r#"<zmn:GM_Surface id="S2"><zmn:GM_Surface.patch/></zmn:GM_Surface>"#;
+        let xml = SAMPLE_XML
+            .replace(
+                "</空間属性>",
+                &format!("{}\n{}\n{}\n</空間属性>", point, curve, surface),
+            )
+            .replace(
+                "</主題属性>",
+                "<筆 id=\"H2\"><地番>2</地番><形状 idref=\"S1\"/></筆>\n</主題属性>",
+            );
+        let found = find_anomalies(&Document::parse(&xml).unwrap());
+        let kinds: Vec<(AnomalyKind, &str)> =
+            found.iter().map(|a| (a.kind, a.id.as_str())).collect();
+        assert_eq!(
+            kinds,
+            [
+                (AnomalyKind::SharedSurface, "S1"),
+                (AnomalyKind::OrphanedPoint, "P2"),
+                (AnomalyKind::UnusedCurve, "C5"),
+                (AnomalyKind::UnreferencedSurface, "S2"),
+            ]
+        );
+        assert_eq!(found[0].fude, ["H1", "H2"]);
+        assert_eq!(found[0].line, 15);
+        assert_eq!(
+            found[0].to_string(),
+            "GM_Surface S1 is the 形状 of several 筆: H1, H2"
+        );
+
+        // A point used only by a 筆界点 is not orphaned.
+        let xml = xml.replace(
+            "</主題属性>",
+            "<筆界点 id=\"K1\"><点番名>1</点番名><形状 idref=\"P2\"/></筆界点>\n</主題属性>",
+        );
+        assert!(!anomalies(&xml).contains(&(AnomalyKind::OrphanedPoint, "P2".to_string())));
+    }
+}
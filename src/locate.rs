@@ -0,0 +1,194 @@
+//! Reverse geocoding: finding the parcel at a coordinate and returning its address
+//! (市区町村名, 大字名, 丁目名, 小字名 and 地番).
+//!
+//! Candidates come from the spatial index of the FlatGeobuf file and are checked with an
+//! exact point-in-polygon test, so each lookup reads only a few features.
+
+use crate::query::{self, Filter};
+use anyhow::{Context, Result, bail};
+use flatgeobuf::FgbReader;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// The columns a [`Location`] is made of, in output order.
+pub const LOCATION_COLUMNS: [&str; 5] = ["市区町村名", "大字名", "丁目名", "小字名", "地番"];
+
+/// Accepted (lowercase) header names of the coordinate columns of a CSV batch.
+const LON_COLUMNS: [&str; 5] = ["lon", "lng", "longitude", "x", "経度"];
+const LAT_COLUMNS: [&str; 4] = ["lat", "latitude", "y", "緯度"];
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Location {
+    pub 市区町村名: Option<String>,
+    pub 大字名: Option<String>,
+    pub 丁目名: Option<String>,
+    pub 小字名: Option<String>,
+    pub 地番: Option<String>,
+}
+
+impl Location {
+    fn values(&self) -> [Option<&str>; 5] {
+        [
+            self.市区町村名.as_deref(),
+            self.大字名.as_deref(),
+            self.丁目名.as_deref(),
+            self.小字名.as_deref(),
+            self.地番.as_deref(),
+        ]
+    }
+}
+
+/// Looks up parcels in a FlatGeobuf file written by this tool.
+pub struct Locator {
+    path: PathBuf,
+    /// Positions of [`LOCATION_COLUMNS`] among the columns of the file.
+    indices: [usize; 5],
+}
+
+impl Locator {
+    /// Fails if the file has no spatial index or lacks one of [`LOCATION_COLUMNS`].
+    pub fn open(path: &Path) -> Result<Self> {
+        let fgb = FgbReader::open(BufReader::new(File::open(path)?))?;
+        if fgb.header().index_node_size() == 0 {
+            bail!(
+                "{} has no spatial index; convert it again without --disable-fgb-index",
+                path.display()
+            );
+        }
+        let columns = query::columns(path)?;
+        let mut indices = [0; 5];
+        for (index, name) in indices.iter_mut().zip(LOCATION_COLUMNS) {
+            *index = columns
+                .iter()
+                .position(|column| column == name)
+                .with_context(|| format!("{} has no {} column", path.display(), name))?;
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            indices,
+        })
+    }
+
+    /// The parcel containing (lon, lat). Where parcels overlap, the first one in the file
+    /// is returned.
+    pub fn locate(&self, lon: f64, lat: f64) -> Result<Option<Location>> {
+        let filter = Filter {
+            point: Some([lon, lat]),
+            ..Default::default()
+        };
+        let mut location = None;
+        query::find_features(&self.path, &filter, Some(1), |found| {
+            let [市区町村名, 大字名, 丁目名, 小字名, 地番] =
+                self.indices.map(|index| found.values[index].clone());
+            location = Some(Location {
+                市区町村名,
+                大字名,
+                丁目名,
+                小字名,
+                地番,
+            });
+            Ok(())
+        })?;
+        Ok(location)
+    }
+
+    /// Reads points from a CSV file with a header row and writes it back with
+    /// [`LOCATION_COLUMNS`] appended; the added columns stay empty where no parcel
+    /// contains the point. Returns the number of points and how many of them were found.
+    pub fn locate_csv(&self, input: impl Read, out: impl Write) -> Result<(usize, usize)> {
+        let mut reader = csv::Reader::from_reader(input);
+        let headers = reader.headers()?.clone();
+        let find_column = |names: &[&str]| {
+            headers
+                .iter()
+                .position(|header| names.contains(&header.trim().to_lowercase().as_str()))
+        };
+        let (Some(lon_column), Some(lat_column)) =
+            (find_column(&LON_COLUMNS), find_column(&LAT_COLUMNS))
+        else {
+            bail!(
+                "The input needs a longitude column ({}) and a latitude column ({})",
+                LON_COLUMNS.join(", "),
+                LAT_COLUMNS.join(", ")
+            );
+        };
+
+        let mut writer = csv::Writer::from_writer(out);
+        writer.write_record(headers.iter().chain(LOCATION_COLUMNS))?;
+        let (mut total, mut found) = (0, 0);
+        for record in reader.records() {
+            let record = record?;
+            let line = record.position().map_or(0, |p| p.line());
+            let coordinate = |column: usize| -> Result<f64> {
+                let value = record.get(column).unwrap_or("").trim();
+                value
+                    .parse()
+                    .with_context(|| format!("Line {}: invalid coordinate {:?}", line, value))
+            };
+            let location = self.locate(coordinate(lon_column)?, coordinate(lat_column)?)?;
+            total += 1;
+            found += location.is_some() as usize;
+            let location = location.unwrap_or_default();
+            let values = location.values().map(|value| value.unwrap_or(""));
+            writer.write_record(record.iter().chain(values))?;
+        }
+        writer.flush()?;
+        Ok((total, found))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::tests::sample_fgb;
+
+    #[test]
+    fn test_locate() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("out.fgb");
+        sample_fgb(&path)?;
+        let locator = Locator::open(&path)?;
+
+        assert_eq!(
+            locator.locate(130.5015, 31.5005)?,
+            Some(Location {
+                市区町村名: Some("屋久島町".to_string()),
+                大字名: Some("宮之浦".to_string()),
+                地番: Some("2".to_string()),
+                ..Default::default()
+            })
+        );
+        assert_eq!(locator.locate(130.5015, 31.502)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_locate_csv() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("out.fgb");
+        sample_fgb(&path)?;
+        let locator = Locator::open(&path)?;
+
+        let input = "id,Lat,Lon\na,31.5005,130.5005\nb,31.6,130.6\n";
+        let mut out = Vec::new();
+        assert_eq!(locator.locate_csv(input.as_bytes(), &mut out)?, (2, 1));
+        assert_eq!(
+            String::from_utf8(out)?,
+            "id,Lat,Lon,市区町村名,大字名,丁目名,小字名,地番\n\
+             a,31.5005,130.5005,屋久島町,宮之浦,,,1\n\
+             b,31.6,130.6,,,,,\n"
+        );
+
+        let error = locator
+            .locate_csv("id,lat\na,31.5\n".as_bytes(), Vec::new())
+            .unwrap_err();
+        assert!(error.to_string().contains("longitude column"));
+        let error = locator
+            .locate_csv("lat,lon\nN31.5,130.5\n".as_bytes(), Vec::new())
+            .unwrap_err();
+        assert!(error.to_string().starts_with("Line 2:"));
+        Ok(())
+    }
+}
@@ -5,29 +5,66 @@ use tikv_jemallocator::Jemalloc;
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
 
+mod cache;
 mod constants;
+mod dataset;
+mod dedup;
+mod diff;
 mod error;
+mod geocode;
+mod id;
+mod inputs;
+mod inspect;
+mod integrity;
+mod locate;
+mod memory;
+mod mvt;
+mod order;
 mod parse;
+mod pmtiles;
 mod processor;
+mod query;
 mod reader;
+mod report;
+mod serve;
+mod stats;
+mod tiles;
+mod topology;
+mod validate;
 mod writer;
 
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
 use parse::ParseOptions;
 use std::{
     fs::{self, File},
-    path::PathBuf,
+    io::{BufWriter, Write},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    process::ExitCode,
 }; // Import ParseOptions
 
+/// Exit code used when the run completed but some inputs or XML files failed.
+const EXIT_FILES_FAILED: u8 = 2;
+/// Exit code used when the run was aborted by `--strict`.
+const EXIT_ABORTED: u8 = 3;
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Cli {
-    /// Output FlatGeobuf file path.
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Output FlatGeobuf file path. With a .pmtiles or .mbtiles extension, vector tiles
+    /// are written instead; every tile is held in memory until the end of the run, which
+    /// --max-memory doesn't count.
     #[arg(required = true)]
-    dst_file: PathBuf,
+    dst_file: Option<PathBuf>,
 
-    /// Input MOJ XML file paths (.xml or .zip).
-    #[arg(required = true, num_args = 1..)]
+    /// Input MOJ XML files (.xml, .xml.gz, .zip, .tar or .tar.gz), directories (searched
+    /// recursively) or glob patterns such as "data/**/*.zip". Use "-" to read a zip, tar or
+    /// XML stream from standard input.
+    #[arg(required_unless_present = "from_file", num_args = 1..)]
     src_files: Vec<PathBuf>,
 
     /// Include features from arbitrary coordinate systems (unmapped files) ("任意座標系").
@@ -39,10 +76,19 @@ struct Cli {
     #[arg(short, long, default_value_t = false)]
     chikugai: bool,
 
+    /// Drop individual features (or points, curves, surfaces) that fail to parse instead of
+    /// skipping the whole XML file. Dropped elements are logged and listed in the report.
+    #[arg(short, long, default_value_t = false)]
+    lenient: bool,
+
     /// Disable FlatGeobuf index creation (turn this off for large exports).
     #[arg(short, long, default_value_t = false)]
     disable_fgb_index: bool,
 
+    /// Also write the global feature ID as a numeric "fid" primary key column.
+    #[arg(long, default_value_t = false)]
+    fid: bool,
+
     /// Enable logging. Will log to mojxml.log in the current directory.
     #[arg(short, long, default_value_t = false)]
     verbose: bool,
@@ -52,11 +98,362 @@ struct Cli {
     /// Use this option if your /tmp directory doesn't have enough space.
     #[arg(short, long)]
     temp_dir: Option<PathBuf>,
+
+    /// Nested zips up to this size (in MiB) are extracted in memory instead of being copied
+    /// to the temporary directory first.
+    #[arg(long, value_name = "MIB", default_value_t = reader::DEFAULT_IN_MEMORY_ZIP_LIMIT >> 20)]
+    in_memory_zip_limit: u64,
+
+    /// Limit the memory held by XML files, nested zips extracted in memory and parsed
+    /// features waiting between the unzip, parse and write stages, e.g. "4G" or "512M".
+    /// Reading and parsing pause while the limit is reached.
+    #[arg(long, value_name = "SIZE", value_parser = memory::parse_size)]
+    max_memory: Option<usize>,
+
+    /// Number of CPU threads to use. Defaults to all CPUs. The stage thread counts and
+    /// queue sizes below are derived from it unless they are given.
+    #[arg(long, value_name = "N")]
+    threads: Option<NonZeroUsize>,
+
+    /// Threads reading and unzipping the inputs (default: a quarter of --threads).
+    /// Increase it for slow network storage. Always 1 with --deterministic.
+    #[arg(long, value_name = "N")]
+    unzip_threads: Option<NonZeroUsize>,
+
+    /// Threads parsing XML files (default: --threads minus one, at least 2).
+    #[arg(long, value_name = "N")]
+    parse_threads: Option<NonZeroUsize>,
+
+    /// Number of unzipped XML files that can wait for a parser (default: --threads).
+    #[arg(long, value_name = "N")]
+    parse_queue: Option<usize>,
+
+    /// Number of parsed files that can wait for the writer (default: 1).
+    #[arg(long, value_name = "N")]
+    write_queue: Option<usize>,
+
+    /// Lowest zoom level of the vector tiles (.pmtiles / .mbtiles output).
+    #[arg(long, value_name = "ZOOM", default_value_t = tiles::DEFAULT_MIN_ZOOM,
+          value_parser = clap::value_parser!(u8).range(0..=tiles::MAX_ZOOM as i64))]
+    min_zoom: u8,
+
+    /// Highest zoom level of the vector tiles. It keeps the full detail of the parcels.
+    #[arg(long, value_name = "ZOOM", default_value_t = tiles::DEFAULT_MAX_ZOOM,
+          value_parser = clap::value_parser!(u8).range(0..=tiles::MAX_ZOOM as i64))]
+    max_zoom: u8,
+
+    /// Attributes to write to the vector tiles, separated by commas (default: all of them),
+    /// e.g. "地番,大字名,丁目名".
+    #[arg(long, value_name = "NAMES", value_delimiter = ',', value_parser = tiles::parse_attribute)]
+    tile_attributes: Vec<String>,
+
+    /// How much the vector tiles below --max-zoom are simplified, in tile units (a tile is
+    /// 4096 units across). 0 turns simplification off.
+    #[arg(long, value_name = "UNITS", default_value_t = tiles::DEFAULT_SIMPLIFY)]
+    simplify: f64,
+
+    /// Read more inputs from this file, one path or pattern per line. Blank lines and
+    /// lines starting with "#" are ignored. Relative paths are relative to the file.
+    #[arg(long, value_name = "LIST")]
+    from_file: Option<PathBuf>,
+
+    /// Write a JSON report with per-input and per-XML outcomes to this path.
+    #[arg(long)]
+    report: Option<PathBuf>,
+
+    /// Stop on the first read, parse or write error instead of skipping the failed file.
+    #[arg(long, default_value_t = false)]
+    strict: bool,
+
+    /// Cache parsed results in this directory. On later runs, inputs that haven't changed
+    /// since they were cached are read from the cache instead of being parsed again.
+    #[arg(long)]
+    cache_dir: Option<PathBuf>,
+
+    /// Drop features that appear in more than one input, keyed on 市区町村コード + 地図名 + 筆id
+    /// ("id") or on all attributes and the geometry ("content"). Files parsed ahead of the
+    /// input being written are held in memory until their turn; only --max-memory limits this.
+    #[arg(long, value_enum)]
+    dedup: Option<dedup::DedupKey>,
+
+    /// Which copy of a duplicate feature is kept (with --dedup).
+    #[arg(long, value_enum, default_value_t, requires = "dedup")]
+    dedup_policy: dedup::DedupPolicy,
+
+    /// Write features in a fixed order (by input path and position in the archive), so that
+    /// identical input gives byte-identical output. Reads one input at a time.
+    #[arg(long, default_value_t = false)]
+    deterministic: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Compare two datasets (e.g. two annual releases) and write the added, removed, split,
+    /// merged and changed parcels to a FlatGeobuf change layer.
+    Diff(DiffArgs),
+    /// Serve a FlatGeobuf file written by this tool on a local web server, as vector tiles
+    /// and GeoJSON bounding box queries, with a map viewer for checking the conversion.
+    Serve(ServeArgs),
+    /// Find parcels in a FlatGeobuf file written by this tool by bounding box, by a point
+    /// they contain or by attribute values, and print them as GeoJSON or CSV.
+    Query(QueryArgs),
+    /// Look up the parcel at a coordinate (or at each coordinate of a CSV file) and print
+    /// its address: 市区町村名, 大字名, 丁目名, 小字名 and 地番.
+    Locate(LocateArgs),
+    /// Find parcels by address (市区町村 + 大字・丁目 + 地番), given as one string or as a CSV
+    /// batch, and return their representative points or geometries.
+    Geocode(GeocodeArgs),
+    /// Summarise MOJ XML inputs without converting them: municipalities, 座標系, 筆
+    /// counts, 地区外 / 別図 and 精度区分.
+    Inspect(InspectArgs),
+    /// Check MOJ XML inputs against the rules of the 地図XML format (required elements,
+    /// namespaces, ids and idrefs) and report every violation with its location.
+    Validate(ValidateArgs),
+    /// Check the parcels of each 地図 for overlaps and for slivers or gaps between
+    /// neighbours, and write them to a FlatGeobuf error layer.
+    Topology(TopologyArgs),
+}
+
+#[derive(Args, Debug)]
+struct DiffArgs {
+    /// Output FlatGeobuf file path for the change layer.
+    dst_file: PathBuf,
+
+    /// Inputs of the old dataset (MOJ XML inputs or a .fgb written by this tool, directories
+    /// or glob patterns).
+    #[arg(long, required = true, num_args = 1..)]
+    old: Vec<PathBuf>,
+
+    /// Inputs of the new dataset (MOJ XML inputs or a .fgb written by this tool, directories
+    /// or glob patterns).
+    #[arg(long, required = true, num_args = 1..)]
+    new: Vec<PathBuf>,
+
+    /// Include features from arbitrary coordinate systems when parsing XML inputs ("任意座標系").
+    #[arg(short, long, default_value_t = false)]
+    arbitrary: bool,
+
+    /// Also write parcels whose geometry did not change.
+    #[arg(long, default_value_t = false)]
+    include_unchanged: bool,
+}
+
+#[derive(Args, Debug)]
+struct ServeArgs {
+    /// FlatGeobuf file to serve. It needs the spatial index (don't convert it with
+    /// --disable-fgb-index).
+    file: PathBuf,
+
+    /// Address to listen on. Use 0.0.0.0 to allow access from other machines.
+    #[arg(long, default_value = "127.0.0.1")]
+    host: String,
+
+    /// Port to listen on.
+    #[arg(short, long, default_value_t = serve::DEFAULT_PORT)]
+    port: u16,
+
+    /// Lowest zoom level tiles are served for; lower zoom levels would hold too many parcels.
+    #[arg(long, value_name = "ZOOM", default_value_t = tiles::DEFAULT_MIN_ZOOM,
+          value_parser = clap::value_parser!(u8).range(0..=tiles::MAX_ZOOM as i64))]
+    min_zoom: u8,
+
+    /// Zoom level from which tiles keep the full detail of the parcels. The viewer
+    /// enlarges these tiles when zooming in further.
+    #[arg(long, value_name = "ZOOM", default_value_t = tiles::DEFAULT_MAX_ZOOM,
+          value_parser = clap::value_parser!(u8).range(0..=tiles::MAX_ZOOM as i64))]
+    max_zoom: u8,
+
+    /// Most features returned by one GeoJSON bounding box query.
+    #[arg(long, value_name = "N", default_value_t = serve::DEFAULT_FEATURE_LIMIT)]
+    limit: usize,
+
+    /// Threads answering requests (default: all CPUs).
+    #[arg(long, value_name = "N")]
+    threads: Option<NonZeroUsize>,
+
+    /// Enable logging. Will log to mojxml.log in the current directory.
+    #[arg(short, long, default_value_t = false)]
+    verbose: bool,
+}
+
+#[derive(Args, Debug)]
+struct QueryArgs {
+    /// FlatGeobuf file to search. --bbox and --point need the spatial index (don't convert
+    /// it with --disable-fgb-index).
+    file: PathBuf,
+
+    /// Find parcels intersecting this bounding box.
+    #[arg(long, value_name = "MIN_LON,MIN_LAT,MAX_LON,MAX_LAT", allow_hyphen_values = true,
+          value_parser = query::parse_bbox)]
+    bbox: Option<[f64; 4]>,
+
+    /// Find parcels containing this point.
+    #[arg(long, value_name = "LON,LAT", allow_hyphen_values = true,
+          value_parser = query::parse_point)]
+    point: Option<[f64; 2]>,
+
+    /// Find parcels whose attribute equals the value (e.g. 地番=123-4). Can be given more
+    /// than once; all conditions have to match.
+    #[arg(short = 'w', long = "where", value_name = "COLUMN=VALUE", value_parser = query::parse_condition)]
+    conditions: Vec<(String, String)>,
+
+    /// Output format.
+    #[arg(short, long, value_enum, default_value_t)]
+    format: query::OutputFormat,
+
+    /// Stop after this many parcels.
+    #[arg(long, value_name = "N")]
+    limit: Option<usize>,
+
+    /// Write to this file instead of standard output.
+    #[arg(short, long, value_name = "FILE")]
+    output: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+#[command(group = clap::ArgGroup::new("points").required(true))]
+struct LocateArgs {
+    /// FlatGeobuf file to search. It needs the spatial index (don't convert it with
+    /// --disable-fgb-index).
+    file: PathBuf,
+
+    /// Coordinate to look up. The address is printed as JSON.
+    #[arg(long, value_name = "LON,LAT", allow_hyphen_values = true,
+          value_parser = query::parse_point, group = "points")]
+    point: Option<[f64; 2]>,
+
+    /// CSV file with a header row and longitude and latitude columns (lon/lat,
+    /// longitude/latitude, x/y or 経度/緯度). It is written back as CSV with the address
+    /// columns appended.
+    #[arg(short, long, value_name = "CSV", group = "points")]
+    input: Option<PathBuf>,
+
+    /// Write to this file instead of standard output.
+    #[arg(short, long, value_name = "FILE")]
+    output: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+#[command(group = clap::ArgGroup::new("addresses").required(true))]
+struct GeocodeArgs {
+    /// FlatGeobuf file written by this tool.
+    file: PathBuf,
+
+    /// Address to look up (e.g. 屋久島町安房2740番地1). The parcels found are printed as a
+    /// GeoJSON FeatureCollection.
+    #[arg(short, long, value_name = "ADDRESS", group = "addresses")]
+    address: Option<String>,
+
+    /// CSV file with a header row and either 市区町村名, 大字名, 丁目名 (optional) and 地番
+    /// columns or an address column (address, 住所 or 所在). It is written back as CSV with
+    /// global_id, lon, lat (a point inside the parcel) and matches columns appended.
+    #[arg(short, long, value_name = "CSV", group = "addresses")]
+    input: Option<PathBuf>,
+
+    /// Also output the parcel geometry (as WKT in CSV output). Keeps all geometries of the
+    /// file in memory.
+    #[arg(long, default_value_t = false)]
+    geometry: bool,
+
+    /// Write to this file instead of standard output.
+    #[arg(short, long, value_name = "FILE")]
+    output: Option<PathBuf>,
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+#[derive(Args, Debug)]
+struct InspectArgs {
+    /// Input MOJ XML files (.xml, .xml.gz, .zip, .tar or .tar.gz), directories or glob
+    /// patterns, as for a conversion.
+    #[arg(required = true, num_args = 1..)]
+    src_files: Vec<PathBuf>,
+
+    /// Also parse the geometries, to count the features a conversion would produce and
+    /// the elements that fail to parse. Slower.
+    #[arg(long, default_value_t = false)]
+    features: bool,
+
+    /// Output format.
+    #[arg(short, long, value_enum, default_value_t)]
+    format: inspect::InspectFormat,
+
+    /// Number of threads parsing XML files (default: all CPUs).
+    #[arg(long, value_name = "N")]
+    threads: Option<NonZeroUsize>,
+
+    /// Write to this file instead of standard output.
+    #[arg(short, long, value_name = "FILE")]
+    output: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct ValidateArgs {
+    /// Input MOJ XML files (.xml, .xml.gz, .zip, .tar or .tar.gz), directories or glob
+    /// patterns, as for a conversion.
+    #[arg(required = true, num_args = 1..)]
+    src_files: Vec<PathBuf>,
+
+    /// Output format.
+    #[arg(short, long, value_enum, default_value_t)]
+    format: validate::ValidateFormat,
+
+    /// Also report orphaned GM_Points, GM_Curves outside every GM_Surface, and
+    /// GM_Surfaces that are the 形状 of no 筆 or of several. These don't fail the check.
+    #[arg(long, default_value_t = false)]
+    integrity: bool,
+
+    /// Number of threads checking XML files (default: all CPUs).
+    #[arg(long, value_name = "N")]
+    threads: Option<NonZeroUsize>,
+
+    /// Write the violations to this file instead of standard output.
+    #[arg(short, long, value_name = "FILE")]
+    output: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct TopologyArgs {
+    /// Output FlatGeobuf file path for the error layer.
+    dst_file: PathBuf,
+
+    /// Input MOJ XML files (.xml, .xml.gz, .zip, .tar or .tar.gz), directories or glob
+    /// patterns, as for a conversion.
+    #[arg(required = true, num_args = 1..)]
+    src_files: Vec<PathBuf>,
+
+    /// Include files in arbitrary coordinate systems ("任意座標系").
+    #[arg(short, long, default_value_t = false)]
+    arbitrary: bool,
+
+    /// Ignore overlaps and gaps smaller than this, in square meters.
+    #[arg(long, value_name = "M2", default_value_t = topology::DEFAULT_MIN_AREA)]
+    min_area: f64,
+
+    /// Report holes between two neighbours as gaps up to this mean width, in meters.
+    /// Wider holes are taken to be roads or other land without a 地番.
+    #[arg(long, value_name = "M", default_value_t = topology::DEFAULT_MAX_GAP_WIDTH)]
+    max_gap_width: f64,
+
+    /// Number of threads checking XML files (default: all CPUs).
+    #[arg(long, value_name = "N")]
+    threads: Option<NonZeroUsize>,
+}
+
+fn main() -> Result<ExitCode, Box<dyn std::error::Error>> {
     let cli = Cli::parse();
 
+    match cli.command {
+        Some(Command::Diff(args)) => return run_diff(args),
+        Some(Command::Serve(args)) => return run_serve(args),
+        Some(Command::Query(args)) => return run_query(args),
+        Some(Command::Locate(args)) => return run_locate(args),
+        Some(Command::Geocode(args)) => return run_geocode(args),
+        Some(Command::Inspect(args)) => return run_inspect(args),
+        Some(Command::Validate(args)) => return run_validate(args),
+        Some(Command::Topology(args)) => return run_topology(args),
+        None => {}
+    }
+    let dst_file = cli.dst_file.expect("DST_FILE is required");
+
     if cli.verbose {
         simplelog::WriteLogger::init(
             simplelog::LevelFilter::Info,
@@ -73,18 +470,369 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let parse_options = ParseOptions {
         include_arbitrary_crs: cli.arbitrary,
         include_chikugai: cli.chikugai,
+        lenient: cli.lenient,
     };
     let write_options = writer::WriterOptions {
         write_index: !cli.disable_fgb_index,
+        write_fid: cli.fid,
     };
 
+    let tile_options = tiles::TileFormat::from_path(&dst_file).map(|format| tiles::TileOptions {
+        format,
+        min_zoom: cli.min_zoom,
+        max_zoom: cli.max_zoom,
+        attributes: cli.tile_attributes,
+        simplify: cli.simplify,
+    });
+    // Tiles are projected from longitude / latitude, which 任意座標系 coordinates aren't.
+    if tile_options.is_some() && cli.arbitrary {
+        return Err("--arbitrary can't be used with tile output (.pmtiles, .mbtiles)".into());
+    }
+
+    let mut src_files = cli.src_files;
+    if let Some(list) = &cli.from_file {
+        src_files.extend(inputs::read_input_list(list)?);
+    }
+    let src_files = expand_inputs(&src_files, reader::is_supported)?;
+
     println!("Starting processing files...");
 
-    let file_count =
-        processor::process_files(&cli.dst_file, cli.src_files, parse_options, write_options)?;
+    let process_options = processor::ProcessOptions {
+        strict: cli.strict,
+        cache_dir: cli.cache_dir,
+        dedup: cli.dedup.map(|key| dedup::DedupOptions {
+            key,
+            policy: cli.dedup_policy,
+        }),
+        deterministic: cli.deterministic,
+        reader: reader::ReaderOptions {
+            in_memory_zip_limit: cli.in_memory_zip_limit.saturating_mul(1024 * 1024),
+            ..Default::default()
+        },
+        max_memory: cli.max_memory,
+        threads: processor::ThreadOptions {
+            threads: cli.threads.map(NonZeroUsize::get),
+            unzip_threads: cli.unzip_threads.map(NonZeroUsize::get),
+            parse_threads: cli.parse_threads.map(NonZeroUsize::get),
+            parse_queue: cli.parse_queue,
+            write_queue: cli.write_queue,
+        },
+        tiles: tile_options,
+    };
+
+    let report = match processor::process_files(
+        &dst_file,
+        src_files,
+        parse_options,
+        write_options,
+        process_options,
+    ) {
+        Ok(report) => report,
+        Err(failure) => {
+            // Still write the report, so the inputs handled before the failure are recorded.
+            if let Some(report_path) = &cli.report {
+                match failure.report.write_json(report_path) {
+                    Ok(()) => println!("Report: {}", report_path.display()),
+                    Err(e) => eprintln!("Failed to write report {}: {}", report_path.display(), e),
+                }
+            }
+            return Err(failure.error.into());
+        }
+    };
+
+    println!(
+        "Finished processing {} XML file(s).",
+        report.xml_file_count()
+    );
+    println!("Destination: {}", dst_file.display());
+    print_stage_summary(&report.stages);
 
-    println!("Finished processing {} XML file(s).", file_count);
-    println!("Destination: {}", cli.dst_file.display());
+    if let Some(report_path) = &cli.report {
+        report.write_json(report_path)?;
+        println!("Report: {}", report_path.display());
+    }
+
+    let warnings = report.warning_count();
+    if warnings > 0 {
+        eprintln!(
+            "{} element(s) dropped because of parse errors (--lenient).",
+            warnings
+        );
+    }
+
+    let duplicates = report.duplicate_count();
+    if duplicates > 0 {
+        println!("{} duplicate feature(s) dropped (--dedup).", duplicates);
+    }
+
+    let failures = report.failure_counts();
+    if !failures.is_empty() {
+        let total: usize = failures.values().sum();
+        eprintln!("{} failure(s):", total);
+        for (kind, count) in &failures {
+            eprintln!("  {}: {}", kind, count);
+        }
+    }
+    if report.aborted {
+        eprintln!("Aborted on first error (--strict); no output was written.");
+        return Ok(ExitCode::from(EXIT_ABORTED));
+    }
+    if !failures.is_empty() {
+        return Ok(ExitCode::from(EXIT_FILES_FAILED));
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
 
-    Ok(())
+fn run_serve(args: ServeArgs) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    if args.verbose {
+        simplelog::WriteLogger::init(
+            simplelog::LevelFilter::Info,
+            simplelog::Config::default(),
+            File::create("mojxml.log")?,
+        )?;
+    }
+    if args.min_zoom > args.max_zoom {
+        return Err(format!(
+            "--min-zoom ({}) is higher than --max-zoom ({})",
+            args.min_zoom, args.max_zoom
+        )
+        .into());
+    }
+    serve::serve(
+        &args.file,
+        serve::ServeOptions {
+            host: args.host,
+            port: args.port,
+            min_zoom: args.min_zoom,
+            max_zoom: args.max_zoom,
+            feature_limit: args.limit,
+            threads: args.threads.map_or_else(num_cpus::get, NonZeroUsize::get),
+        },
+    )?;
+    Ok(ExitCode::SUCCESS)
+}
+
+fn run_query(args: QueryArgs) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let filter = query::Filter {
+        bbox: args.bbox,
+        point: args.point,
+        conditions: args.conditions,
+    };
+    let (count, truncated) = match &args.output {
+        Some(path) => query::write_features(
+            &args.file,
+            &filter,
+            args.limit,
+            args.format,
+            BufWriter::new(File::create(path)?),
+        ),
+        None => query::write_features(
+            &args.file,
+            &filter,
+            args.limit,
+            args.format,
+            std::io::stdout().lock(),
+        ),
+    }
+    .map_err(|e| format!("{}: {:#}", args.file.display(), e))?;
+    if truncated {
+        eprintln!("{} parcel(s) found (stopped at --limit).", count);
+    } else {
+        eprintln!("{} parcel(s) found.", count);
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn run_locate(args: LocateArgs) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let locator = locate::Locator::open(&args.file)?;
+    let out: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(std::io::stdout().lock()),
+    };
+    if let Some([lon, lat]) = args.point {
+        let location = locator.locate(lon, lat)?;
+        let mut out = out;
+        serde_json::to_writer_pretty(&mut out, &location)?;
+        writeln!(out)?;
+        out.flush()?;
+        if location.is_none() {
+            eprintln!("No parcel contains {},{}.", lon, lat);
+            return Ok(ExitCode::FAILURE);
+        }
+    } else if let Some(input) = &args.input {
+        let (total, found) = locator
+            .locate_csv(File::open(input)?, out)
+            .map_err(|e| format!("{}: {:#}", input.display(), e))?;
+        eprintln!("{} of {} point(s) located.", found, total);
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn run_geocode(args: GeocodeArgs) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let index = geocode::AddressIndex::build(&args.file, args.geometry)
+        .map_err(|e| format!("{}: {:#}", args.file.display(), e))?;
+    eprintln!("{} parcel(s) indexed.", index.parcel_count());
+    let mut out: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(std::io::stdout().lock()),
+    };
+    if let Some(address) = &args.address {
+        let parcels = index.lookup_address(address);
+        let features: Vec<_> = parcels.iter().map(|p| p.feature_json()).collect();
+        let collection = serde_json::json!({ "type": "FeatureCollection", "features": features });
+        serde_json::to_writer_pretty(&mut out, &collection)?;
+        writeln!(out)?;
+        out.flush()?;
+        if parcels.is_empty() {
+            eprintln!("No parcel found at {}.", address);
+            return Ok(ExitCode::FAILURE);
+        }
+    } else if let Some(input) = &args.input {
+        let (total, found) = index
+            .geocode_csv(File::open(input)?, out)
+            .map_err(|e| format!("{}: {:#}", input.display(), e))?;
+        eprintln!("{} of {} address(es) found.", found, total);
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn run_inspect(args: InspectArgs) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let paths = expand_inputs(&args.src_files, reader::is_supported)?;
+    let inspection = inspect::inspect(
+        &paths,
+        &inspect::InspectOptions {
+            parse_features: args.features,
+            threads: args.threads.map_or_else(num_cpus::get, NonZeroUsize::get),
+            reader: reader::ReaderOptions::default(),
+        },
+    )?;
+    match &args.output {
+        Some(path) => inspection.write(args.format, &mut BufWriter::new(File::create(path)?))?,
+        None => inspection.write(args.format, &mut std::io::stdout().lock())?,
+    }
+    if !inspection.failures.is_empty() {
+        return Ok(ExitCode::from(EXIT_FILES_FAILED));
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn run_validate(args: ValidateArgs) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let paths = expand_inputs(&args.src_files, reader::is_supported)?;
+    let options = validate::ValidateOptions {
+        threads: args.threads.map_or_else(num_cpus::get, NonZeroUsize::get),
+        integrity: args.integrity,
+        reader: reader::ReaderOptions::default(),
+    };
+    let validation = validate::validate(&paths, &options)?;
+    match &args.output {
+        Some(path) => validation.write(args.format, &mut BufWriter::new(File::create(path)?))?,
+        None => validation.write(args.format, &mut std::io::stdout().lock())?,
+    }
+    eprintln!(
+        "{} XML file(s) checked, {} violation(s) in {} file(s).",
+        validation.xml_file_count,
+        validation.violation_count(),
+        validation.invalid_file_count()
+    );
+    if args.integrity {
+        eprintln!("{} anomalies found.", validation.anomaly_count());
+    }
+    if validation.invalid_file_count() > 0 {
+        return Ok(ExitCode::from(EXIT_FILES_FAILED));
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn run_topology(args: TopologyArgs) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let paths = expand_inputs(&args.src_files, reader::is_supported)?;
+    let options = topology::TopologyOptions {
+        min_area: args.min_area,
+        max_gap_width: args.max_gap_width,
+        include_arbitrary_crs: args.arbitrary,
+        threads: args.threads.map_or_else(num_cpus::get, NonZeroUsize::get),
+        reader: reader::ReaderOptions::default(),
+    };
+    let summary = topology::check_inputs(&args.dst_file, &paths, &options)?;
+    println!(
+        "{} XML file(s) checked: {} overlap(s), {} gap(s).",
+        summary.xml_file_count, summary.overlap_count, summary.gap_count
+    );
+    if summary.output_written {
+        println!("Destination: {}", args.dst_file.display());
+    } else {
+        println!("No topology errors found.");
+    }
+    if !summary.failures.is_empty() {
+        eprintln!("{} failure(s):", summary.failures.len());
+        for (file, error) in &summary.failures {
+            eprintln!("  {}: {}", file, error.message);
+        }
+        return Ok(ExitCode::from(EXIT_FILES_FAILED));
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn run_diff(args: DiffArgs) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let parse_options = ParseOptions {
+        include_arbitrary_crs: args.arbitrary,
+        include_chikugai: false,
+        lenient: false,
+    };
+    let diff_options = diff::DiffOptions {
+        include_unchanged: args.include_unchanged,
+    };
+    let is_supported = |path: &Path| reader::is_supported(path) || dataset::is_fgb(path);
+    let old = expand_inputs(&args.old, is_supported)?;
+    let new = expand_inputs(&args.new, is_supported)?;
+    let summary = diff::diff_datasets(&args.dst_file, &old, &new, &parse_options, &diff_options)?;
+
+    for (change_type, count) in &summary.counts {
+        println!("  {}: {}", change_type, count);
+    }
+    if summary.unkeyed > 0 {
+        println!(
+            "{} feature(s) without 地番 were not compared.",
+            summary.unkeyed
+        );
+    }
+    if summary.output_written {
+        println!("Destination: {}", args.dst_file.display());
+    } else {
+        println!("No changes found.");
+    }
+    if summary.failed_files > 0 {
+        eprintln!("{} file(s) failed to load.", summary.failed_files);
+        return Ok(ExitCode::from(EXIT_FILES_FAILED));
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Expands directories and glob patterns in the inputs and warns about skipped paths.
+fn expand_inputs(
+    inputs: &[PathBuf],
+    accept: impl Fn(&Path) -> bool,
+) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let expanded = inputs::expand_inputs(inputs, accept);
+    expanded.print_warnings();
+    if expanded.paths.is_empty() {
+        return Err("No input files found".into());
+    }
+    Ok(expanded.paths)
+}
+
+/// Prints how the pipeline stages spent their time, to help choose thread counts and
+/// queue sizes. A stage that mostly waits for output is limited by the stages after it.
+fn print_stage_summary(stages: &[stats::StageReport]) {
+    println!("Stage utilisation (working / waiting for input / waiting for output):");
+    for stage in stages {
+        println!(
+            "  {:<5} {:>3} thread(s): {:>3.0}% / {:>3.0}% / {:>3.0}%",
+            stage.stage,
+            stage.threads,
+            stage.percent(stage.busy_ms()),
+            stage.percent(stage.input_wait_ms),
+            stage.percent(stage.output_wait_ms),
+        );
+    }
 }
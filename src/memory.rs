@@ -0,0 +1,282 @@
+//! Bounding the memory held by files in flight between the pipeline stages.
+//!
+//! The unzip threads reserve the size of every XML file (and of every nested zip they
+//! extract in memory) before sending it on, and the parsers reserve the (estimated) size
+//! of the parsed features before sending them to the writer. A [`Reservation`] is
+//! released when the file it belongs to is dropped.
+//!
+//! A thread waits while the budget is exceeded, except when no other files are in
+//! flight, when its file belongs to the input the writer is waiting on, and when it is
+//! the last parser that isn't waiting: the file the writer needs next may still be
+//! queued for the parsers, and nothing else would free any memory.
+
+use crate::parse::{Feature, ParsedXML};
+use geo::CoordsIter;
+use geo_types::Coord;
+use log::info;
+use std::sync::{Arc, Condvar, Mutex};
+
+/// Estimated size of the attribute strings of a feature (codes and short names).
+const PROPERTY_SIZE_ESTIMATE: usize = 128;
+
+#[derive(Debug, Clone)]
+pub struct MemoryBudget(Arc<(Mutex<BudgetState>, Condvar)>);
+
+#[derive(Debug)]
+struct BudgetState {
+    limit: usize,
+    used: usize,
+    peak: usize,
+    /// The part of `used` held by nested zips extracted in memory. An unzip thread keeps
+    /// holding its zip while it waits for the files in it, so these don't count as files
+    /// in flight.
+    archives: usize,
+    /// Rank of the input the writer is waiting on, when files are written in order.
+    frontier: Option<usize>,
+    parsers: usize,
+    /// Parsers waiting on the budget.
+    waiting_parsers: usize,
+}
+
+impl BudgetState {
+    fn must_wait(&self, bytes: usize, rank: Option<usize>) -> bool {
+        self.used > self.archives
+            && self.used + bytes > self.limit
+            && (rank.is_none() || rank != self.frontier)
+    }
+}
+
+impl MemoryBudget {
+    /// A budget of `limit` bytes, shared by `parsers` parser threads.
+    pub fn new(limit: usize, parsers: usize) -> Self {
+        MemoryBudget(Arc::new((
+            Mutex::new(BudgetState {
+                limit,
+                used: 0,
+                peak: 0,
+                archives: 0,
+                frontier: None,
+                parsers,
+                waiting_parsers: 0,
+            }),
+            Condvar::new(),
+        )))
+    }
+
+    /// Blocks until `bytes` of an XML file fit into the budget. A file larger than the
+    /// whole budget is let through once nothing else is in flight, and files of the input
+    /// the writer is waiting on (by `rank`) never block.
+    pub fn reserve(&self, bytes: usize, rank: Option<usize>) -> Reservation {
+        self.wait_for(bytes, rank, false, false)
+    }
+
+    /// Blocks until a nested zip of `bytes` fits into the budget, like [`Self::reserve`].
+    pub fn reserve_archive(&self, bytes: usize, rank: Option<usize>) -> Reservation {
+        self.wait_for(bytes, rank, true, false)
+    }
+
+    /// Blocks until the parsed features of a file fit into the budget, like
+    /// [`Self::reserve`]. The last parser that isn't waiting goes ahead as well.
+    pub fn reserve_parsed(&self, bytes: usize, rank: Option<usize>) -> Reservation {
+        self.wait_for(bytes, rank, false, true)
+    }
+
+    fn wait_for(
+        &self,
+        bytes: usize,
+        rank: Option<usize>,
+        archive: bool,
+        parser: bool,
+    ) -> Reservation {
+        let (state, condvar) = &*self.0;
+        let mut state = state.lock().unwrap();
+        if parser {
+            state.waiting_parsers += 1;
+        }
+        while state.must_wait(bytes, rank) && !(parser && state.waiting_parsers >= state.parsers) {
+            state = condvar.wait(state).unwrap();
+        }
+        if parser {
+            state.waiting_parsers -= 1;
+        }
+        state.used += bytes;
+        state.peak = state.peak.max(state.used);
+        if archive {
+            state.archives += bytes;
+        }
+        Reservation {
+            budget: self.clone(),
+            bytes,
+            archive,
+        }
+    }
+
+    pub fn set_frontier(&self, rank: usize) {
+        let (state, condvar) = &*self.0;
+        state.lock().unwrap().frontier = Some(rank);
+        condvar.notify_all();
+    }
+
+    /// The most memory that was reserved at once.
+    pub fn peak(&self) -> usize {
+        self.0.0.lock().unwrap().peak
+    }
+
+    fn release(&self, bytes: usize, archive: bool) {
+        let (state, condvar) = &*self.0;
+        let mut state = state.lock().unwrap();
+        state.used = state.used.saturating_sub(bytes);
+        if archive {
+            state.archives = state.archives.saturating_sub(bytes);
+        }
+        condvar.notify_all();
+    }
+}
+
+/// Memory reserved for a file, released when dropped.
+pub struct Reservation {
+    budget: MemoryBudget,
+    bytes: usize,
+    archive: bool,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.release(self.bytes, self.archive);
+    }
+}
+
+/// The budget as seen by a reader: nested zips it extracts in memory are reserved for
+/// the input it is reading.
+#[derive(Debug, Clone)]
+pub struct InputBudget {
+    pub budget: MemoryBudget,
+    /// Rank of the input, when files are written in order.
+    pub rank: Option<usize>,
+}
+
+impl InputBudget {
+    pub fn reserve_archive(&self, bytes: usize) -> Reservation {
+        self.budget.reserve_archive(bytes, self.rank)
+    }
+}
+
+/// Reserves `bytes` if there is a budget, blocking until they fit. See [`MemoryBudget::reserve`].
+pub fn reserve(
+    budget: Option<&MemoryBudget>,
+    bytes: usize,
+    rank: Option<usize>,
+) -> Option<Reservation> {
+    budget.map(|budget| budget.reserve(bytes, rank))
+}
+
+/// Reserves the parsed features of a file if there is a budget. See
+/// [`MemoryBudget::reserve_parsed`].
+pub fn reserve_parsed(
+    budget: Option<&MemoryBudget>,
+    bytes: usize,
+    rank: Option<usize>,
+) -> Option<Reservation> {
+    budget.map(|budget| budget.reserve_parsed(bytes, rank))
+}
+
+pub fn log_peak(budget: Option<&MemoryBudget>) {
+    if let Some(budget) = budget {
+        info!(
+            "Peak memory held by files in flight: {} MiB",
+            budget.peak() >> 20
+        );
+    }
+}
+
+/// Estimated memory used by the features of a parsed file.
+pub fn parsed_size(parsed: &ParsedXML) -> usize {
+    parsed.features.iter().map(feature_size).sum()
+}
+
+fn feature_size(feature: &Feature) -> usize {
+    size_of::<Feature>()
+        + feature.geometry.coords_count() * size_of::<Coord>()
+        + PROPERTY_SIZE_ESTIMATE
+}
+
+/// Parses a size such as `8G`, `512M` or `1048576` (bytes). Suffixes are binary
+/// (`1K` = 1024 bytes) and case-insensitive; a trailing `B` or `iB` is allowed.
+pub fn parse_size(s: &str) -> Result<usize, String> {
+    let upper = s.trim().to_uppercase();
+    let number = upper
+        .strip_suffix("IB")
+        .or_else(|| upper.strip_suffix('B'))
+        .unwrap_or(&upper);
+    let (digits, shift) = match number.chars().last() {
+        Some('K') => (&number[..number.len() - 1], 10),
+        Some('M') => (&number[..number.len() - 1], 20),
+        Some('G') => (&number[..number.len() - 1], 30),
+        Some('T') => (&number[..number.len() - 1], 40),
+        _ => (number, 0),
+    };
+    let value: f64 = digits
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid size: {}", s))?;
+    if !value.is_finite() || value <= 0.0 {
+        return Err(format!("size must be positive: {}", s));
+    }
+    Ok((value * (1u64 << shift) as f64) as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1048576"), Ok(1 << 20));
+        assert_eq!(parse_size("512M"), Ok(512 << 20));
+        assert_eq!(parse_size("8g"), Ok(8 << 30));
+        assert_eq!(parse_size("1.5GiB"), Ok(3 << 29));
+        assert_eq!(parse_size("64KB"), Ok(64 << 10));
+        assert!(parse_size("lots").is_err());
+        assert!(parse_size("0").is_err());
+    }
+
+    #[test]
+    fn test_reserve() {
+        let budget = MemoryBudget::new(100, 2);
+        let a = budget.reserve(60, Some(1));
+        // Doesn't fit yet; released once `a` is dropped.
+        let waiter = {
+            let budget = budget.clone();
+            std::thread::spawn(move || budget.reserve(60, Some(1)))
+        };
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!waiter.is_finished());
+        drop(a);
+        let b = waiter.join().unwrap();
+        // The input the writer is waiting on goes through.
+        budget.set_frontier(0);
+        let c = budget.reserve(60, Some(0));
+        drop((b, c));
+        // Larger than the whole budget, but nothing else is in flight.
+        let d = budget.reserve(1000, None);
+        assert_eq!(budget.peak(), 1000);
+        drop(d);
+
+        // A reader waiting for a file doesn't wait on the zip it is reading it from.
+        let zip = budget.reserve_archive(80, None);
+        let e = budget.reserve(50, None);
+        drop(zip);
+
+        // One parser waits, the last one that isn't waiting goes ahead.
+        let waiter = {
+            let budget = budget.clone();
+            std::thread::spawn(move || budget.reserve_parsed(60, None))
+        };
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!waiter.is_finished());
+        let f = budget.reserve_parsed(60, None);
+        drop((e, f));
+        waiter.join().unwrap();
+    }
+}
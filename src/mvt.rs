@@ -0,0 +1,218 @@
+//! Encoding of Mapbox Vector Tiles (version 2.1).
+//!
+//! Only what the tile writer needs: one layer of polygons with string attributes.
+//! The protobuf messages are small enough to write by hand.
+
+use std::collections::HashMap;
+
+/// Size of a tile in tile coordinates.
+pub const EXTENT: u32 = 4096;
+
+const COMMAND_MOVE_TO: u32 = 1;
+const COMMAND_LINE_TO: u32 = 2;
+const COMMAND_CLOSE_PATH: u32 = 7;
+const GEOM_TYPE_POLYGON: u64 = 3;
+
+const WIRE_VARINT: u32 = 0;
+const WIRE_LEN: u32 = 2;
+
+/// A closed ring in tile coordinates, without the repeated first point.
+pub type Ring = Vec<(i32, i32)>;
+
+/// The features of one layer of a tile, encoded as they are added.
+pub struct LayerBuilder {
+    features: Vec<u8>,
+    /// Keys used by the features of this tile, by attribute index.
+    keys: Vec<Option<u32>>,
+    key_names: Vec<u32>,
+    values: Vec<String>,
+    value_index: HashMap<String, u32>,
+}
+
+impl LayerBuilder {
+    pub fn new(attribute_count: usize) -> Self {
+        LayerBuilder {
+            features: Vec::new(),
+            keys: vec![None; attribute_count],
+            key_names: Vec::new(),
+            values: Vec::new(),
+            value_index: HashMap::new(),
+        }
+    }
+
+    /// Adds a polygon feature. `polygons` are lists of rings, the exterior ring first;
+    /// rings are reoriented as the specification requires. `attributes` are
+    /// (attribute index, value) pairs.
+    pub fn add_polygons(&mut self, id: u64, polygons: &[Vec<Ring>], attributes: &[(usize, &str)]) {
+        let mut tags = Vec::with_capacity(attributes.len() * 2);
+        for &(attribute, value) in attributes {
+            let key = *self.keys[attribute].get_or_insert_with(|| {
+                self.key_names.push(attribute as u32);
+                self.key_names.len() as u32 - 1
+            });
+            let value = match self.value_index.get(value) {
+                Some(&index) => index,
+                None => {
+                    let index = self.values.len() as u32;
+                    self.values.push(value.to_string());
+                    self.value_index.insert(value.to_string(), index);
+                    index
+                }
+            };
+            tags.push(key);
+            tags.push(value);
+        }
+
+        let mut feature = Vec::new();
+        write_varint_field(&mut feature, 1, id);
+        write_packed_field(&mut feature, 2, &tags);
+        write_varint_field(&mut feature, 3, GEOM_TYPE_POLYGON);
+        write_packed_field(&mut feature, 4, &polygon_commands(polygons));
+        write_bytes_field(&mut self.features, 2, &feature);
+    }
+
+    /// Encodes the tile with this as its only layer. `attribute_names` are the names of
+    /// the attribute indexes used in [`LayerBuilder::add_polygons`].
+    pub fn finish(self, layer_name: &str, attribute_names: &[&str]) -> Vec<u8> {
+        let mut layer = Vec::with_capacity(self.features.len() + 256);
+        write_varint_field(&mut layer, 15, 2);
+        write_bytes_field(&mut layer, 1, layer_name.as_bytes());
+        layer.extend_from_slice(&self.features);
+        for key in self.key_names {
+            write_bytes_field(&mut layer, 3, attribute_names[key as usize].as_bytes());
+        }
+        for value in self.values {
+            let mut message = Vec::with_capacity(value.len() + 2);
+            write_bytes_field(&mut message, 1, value.as_bytes());
+            write_bytes_field(&mut layer, 4, &message);
+        }
+        write_varint_field(&mut layer, 5, EXTENT as u64);
+
+        let mut tile = Vec::with_capacity(layer.len() + 8);
+        write_bytes_field(&mut tile, 3, &layer);
+        tile
+    }
+}
+
+/// Geometry commands of a polygon or multipolygon.
+fn polygon_commands(polygons: &[Vec<Ring>]) -> Vec<u32> {
+    let mut commands = Vec::new();
+    let mut cursor = (0, 0);
+    for polygon in polygons {
+        for (i, ring) in polygon.iter().enumerate() {
+            // Exterior rings have a positive area in tile coordinates (y pointing down),
+            // interior rings a negative one.
+            let reverse = (ring_area(ring) > 0) != (i == 0);
+            let mut points: Box<dyn Iterator<Item = &(i32, i32)>> = if reverse {
+                Box::new(ring.iter().rev())
+            } else {
+                Box::new(ring.iter())
+            };
+            let first = points.next().expect("rings have at least three points");
+            commands.push(command(COMMAND_MOVE_TO, 1));
+            push_point(&mut commands, &mut cursor, *first);
+            commands.push(command(COMMAND_LINE_TO, ring.len() as u32 - 1));
+            for point in points {
+                push_point(&mut commands, &mut cursor, *point);
+            }
+            commands.push(command(COMMAND_CLOSE_PATH, 1));
+        }
+    }
+    commands
+}
+
+fn push_point(commands: &mut Vec<u32>, cursor: &mut (i32, i32), point: (i32, i32)) {
+    commands.push(zigzag(point.0 - cursor.0));
+    commands.push(zigzag(point.1 - cursor.1));
+    *cursor = point;
+}
+
+/// Twice the signed area of a ring, positive if it is clockwise on screen.
+pub fn ring_area(ring: &[(i32, i32)]) -> i64 {
+    let mut area = 0;
+    for (i, &(x0, y0)) in ring.iter().enumerate() {
+        let (x1, y1) = ring[(i + 1) % ring.len()];
+        area += x0 as i64 * y1 as i64 - x1 as i64 * y0 as i64;
+    }
+    area
+}
+
+fn command(id: u32, count: u32) -> u32 {
+    (id & 0x7) | (count << 3)
+}
+
+fn zigzag(n: i32) -> u32 {
+    ((n << 1) ^ (n >> 31)) as u32
+}
+
+pub fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn write_tag(buf: &mut Vec<u8>, field: u32, wire_type: u32) {
+    write_varint(buf, ((field << 3) | wire_type) as u64);
+}
+
+fn write_varint_field(buf: &mut Vec<u8>, field: u32, value: u64) {
+    write_tag(buf, field, WIRE_VARINT);
+    write_varint(buf, value);
+}
+
+fn write_bytes_field(buf: &mut Vec<u8>, field: u32, bytes: &[u8]) {
+    write_tag(buf, field, WIRE_LEN);
+    write_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn write_packed_field(buf: &mut Vec<u8>, field: u32, values: &[u32]) {
+    let mut packed = Vec::with_capacity(values.len() * 2);
+    for &value in values {
+        write_varint(&mut packed, value as u64);
+    }
+    write_bytes_field(buf, field, &packed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_varint_and_zigzag() {
+        let mut buf = Vec::new();
+        write_varint(&mut buf, 300);
+        assert_eq!(buf, [0xac, 0x02]);
+        assert_eq!(zigzag(0), 0);
+        assert_eq!(zigzag(-1), 1);
+        assert_eq!(zigzag(1), 2);
+        assert_eq!(zigzag(-2), 3);
+    }
+
+    #[test]
+    fn test_polygon_commands() {
+        // The example from the specification (4.3.4.4), given counter-clockwise here.
+        let exterior = vec![(20, 34), (8, 12), (3, 6)];
+        assert!(ring_area(&exterior) < 0);
+        let commands = polygon_commands(&[vec![exterior]]);
+        assert_eq!(commands, [9, 6, 12, 18, 10, 12, 24, 44, 15]);
+
+        let mut layer = LayerBuilder::new(2);
+        layer.add_polygons(1, &[vec![vec![(0, 0), (10, 0), (10, 10)]]], &[(1, "a")]);
+        layer.add_polygons(2, &[vec![vec![(0, 0), (10, 0), (10, 10)]]], &[(1, "a")]);
+        let tile = layer.finish("fude", &["x", "地番"]);
+        // One key and one value are shared by both features.
+        assert_eq!(
+            tile.windows("地番".len())
+                .filter(|w| *w == "地番".as_bytes())
+                .count(),
+            1
+        );
+        assert_eq!(
+            tile.windows(3).filter(|w| *w == [0x22, 0x03, 0x0a]).count(),
+            1
+        );
+    }
+}
@@ -0,0 +1,239 @@
+//! Writing parsed files in a fixed order.
+//!
+//! The parser threads finish files in whatever order they happen to, so for
+//! `--deterministic` (and `--dedup`, where the winning copy has to be written first)
+//! the writer puts them back into order before writing: inputs by rank, and the XML
+//! files of an input by their entry index. [`Permits`] bound how far the producers
+//! can run ahead of the writer, which bounds the reorder buffer.
+
+use std::collections::{BTreeMap, HashSet};
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/// How long the writer waits for a new file before checking the tracker again,
+/// since failed entries and finished inputs don't send anything to the writer.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Default, Clone)]
+struct InputProgress {
+    /// XML files sent on to be parsed or written. Entry indices are `0..queued`.
+    queued: usize,
+    /// Entries that failed to parse and will never reach the writer.
+    failed: HashSet<usize>,
+    read_done: bool,
+}
+
+/// Thread-safe record of the XML files read from each input.
+#[derive(Clone)]
+pub struct InputTracker(Arc<Mutex<Vec<InputProgress>>>);
+
+impl InputTracker {
+    pub fn new(input_count: usize) -> Self {
+        InputTracker(Arc::new(Mutex::new(vec![
+            InputProgress::default();
+            input_count
+        ])))
+    }
+
+    /// Records an XML file sent on from the input and returns its entry index.
+    pub fn queued(&self, input: usize) -> usize {
+        let mut inputs = self.0.lock().unwrap();
+        inputs[input].queued += 1;
+        inputs[input].queued - 1
+    }
+
+    /// The entry will never reach the writer (e.g. it failed to parse).
+    pub fn failed(&self, input: usize, seq: usize) {
+        self.0.lock().unwrap()[input].failed.insert(seq);
+    }
+
+    /// All XML files of the input have been queued.
+    pub fn read_done(&self, input: usize) {
+        self.0.lock().unwrap()[input].read_done = true;
+    }
+}
+
+/// Limits the number of XML files in flight between the producers and the writer.
+///
+/// Files of the input the writer is currently waiting on never block, so a producer
+/// that is behind can't be starved by one that is ahead and filled the buffer. This
+/// relies on every producer reading its inputs in rank order.
+#[derive(Clone)]
+pub struct Permits(Arc<(Mutex<PermitState>, Condvar)>);
+
+struct PermitState {
+    capacity: usize,
+    in_flight: usize,
+    /// Rank of the input the writer is waiting on.
+    frontier: usize,
+    closed: bool,
+}
+
+impl Permits {
+    pub fn new(capacity: usize) -> Self {
+        Permits(Arc::new((
+            Mutex::new(PermitState {
+                capacity,
+                in_flight: 0,
+                frontier: 0,
+                closed: false,
+            }),
+            Condvar::new(),
+        )))
+    }
+
+    /// Blocks until a file of the input with the given rank may be sent.
+    pub fn acquire(&self, rank: usize) {
+        let (state, condvar) = &*self.0;
+        let mut state = state.lock().unwrap();
+        while state.in_flight >= state.capacity && rank != state.frontier && !state.closed {
+            state = condvar.wait(state).unwrap();
+        }
+        state.in_flight += 1;
+    }
+
+    fn release(&self) {
+        let (state, condvar) = &*self.0;
+        let mut state = state.lock().unwrap();
+        state.in_flight = state.in_flight.saturating_sub(1);
+        condvar.notify_all();
+    }
+
+    fn set_frontier(&self, rank: usize) {
+        let (state, condvar) = &*self.0;
+        state.lock().unwrap().frontier = rank;
+        condvar.notify_all();
+    }
+
+    /// Unblocks all producers for good. Called when the writer stops.
+    pub fn close(&self) {
+        let (state, condvar) = &*self.0;
+        state.lock().unwrap().closed = true;
+        condvar.notify_all();
+    }
+}
+
+/// Reorder buffer in front of the writer.
+pub struct OrderGate<T> {
+    order: Vec<usize>,
+    /// Position of every input in `order`.
+    rank: Vec<usize>,
+    /// Rank of the input being written, and the next entry index expected from it.
+    frontier: usize,
+    next_seq: usize,
+    pending: BTreeMap<(usize, usize), T>,
+    tracker: InputTracker,
+    permits: Option<Permits>,
+}
+
+impl<T> OrderGate<T> {
+    pub fn new(order: Vec<usize>, tracker: InputTracker, permits: Option<Permits>) -> Self {
+        OrderGate {
+            rank: ranks(&order),
+            order,
+            frontier: 0,
+            next_seq: 0,
+            pending: BTreeMap::new(),
+            tracker,
+            permits,
+        }
+    }
+
+    /// Adds an item that reached the writer.
+    pub fn push(&mut self, input: usize, seq: usize, item: T) {
+        self.pending.insert((self.rank[input], seq), item);
+    }
+
+    /// Returns the items that can be written now, in order.
+    pub fn pop_ready(&mut self) -> Vec<T> {
+        let mut ready = Vec::new();
+        while self.frontier < self.order.len() {
+            if let Some(item) = self.pending.remove(&(self.frontier, self.next_seq)) {
+                ready.push(item);
+            } else {
+                let inputs = self.tracker.0.lock().unwrap();
+                let progress = &inputs[self.order[self.frontier]];
+                if progress.failed.contains(&self.next_seq) {
+                    // Skip it.
+                } else if progress.read_done && self.next_seq >= progress.queued {
+                    self.frontier += 1;
+                    self.next_seq = 0;
+                    if let Some(permits) = &self.permits {
+                        permits.set_frontier(self.frontier);
+                    }
+                    continue;
+                } else {
+                    break;
+                }
+            }
+            self.next_seq += 1;
+            if let Some(permits) = &self.permits {
+                permits.release();
+            }
+        }
+        ready
+    }
+
+    /// Rank of the input the writer is waiting on.
+    pub fn frontier(&self) -> usize {
+        self.frontier
+    }
+
+    /// Returns all remaining items, in order. Call once nothing more can arrive.
+    pub fn drain(&mut self) -> Vec<T> {
+        std::mem::take(&mut self.pending).into_values().collect()
+    }
+}
+
+impl<T> Drop for OrderGate<T> {
+    fn drop(&mut self) {
+        if let Some(permits) = &self.permits {
+            permits.close();
+        }
+    }
+}
+
+/// Input order for `--deterministic`: sorted by path, then by position on the command line.
+pub fn path_order(src_files: &[PathBuf]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..src_files.len()).collect();
+    order.sort_by(|a, b| src_files[*a].cmp(&src_files[*b]));
+    order
+}
+
+/// Position of every input in `order`.
+pub fn ranks(order: &[usize]) -> Vec<usize> {
+    let mut rank = vec![0; order.len()];
+    for (i, input) in order.iter().enumerate() {
+        rank[*input] = i;
+    }
+    rank
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_order_gate() {
+        let tracker = InputTracker::new(3);
+        // Input 2 is written first, then 1, then 0.
+        let mut gate = OrderGate::new(vec![2, 1, 0], tracker.clone(), None);
+        let a = tracker.queued(0);
+        let c0 = tracker.queued(2);
+        let c1 = tracker.queued(2);
+        let c2 = tracker.queued(2);
+        gate.push(0, a, "a");
+        assert!(gate.pop_ready().is_empty());
+        gate.push(2, c1, "c1");
+        assert!(gate.pop_ready().is_empty());
+        gate.push(2, c0, "c0");
+        assert_eq!(gate.pop_ready(), vec!["c0", "c1"]);
+        // c2 failed to parse, and input 1 had no XML files.
+        tracker.failed(2, c2);
+        tracker.read_done(2);
+        tracker.read_done(1);
+        assert_eq!(gate.pop_ready(), vec!["a"]);
+        assert!(gate.drain().is_empty());
+    }
+}
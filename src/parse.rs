@@ -17,7 +17,7 @@ pub struct Feature {
     pub props: FeatureProperties,
 }
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, Hash)]
 pub struct FeatureProperties {
     pub 筆id: String,
     pub 精度区分: Option<String>,
@@ -34,6 +34,7 @@ pub struct FeatureProperties {
     pub 筆界未定構成筆: Option<String>,
 }
 
+#[derive(Debug, Clone, Default, Hash)]
 pub struct CommonProperties {
     pub 地図名: String,
     pub 市区町村コード: String,
@@ -46,56 +47,163 @@ pub struct CommonProperties {
 pub struct ParseOptions {
     pub include_arbitrary_crs: bool,
     pub include_chikugai: bool,
+    /// Drop individual points, curves, surfaces and 筆 that fail to parse (recording
+    /// a warning) instead of failing the whole file.
+    pub lenient: bool,
 }
 
 // --- Helper Functions ---
 fn get_child_element<'a>(node: &'a Node<'a, 'a>, name: &str) -> Result<Node<'a, 'a>> {
     node.children()
         .find(|child| child.tag_name().name() == name)
-        .ok_or_else(|| Error::MissingElement(name.to_string()))
+        .ok_or_else(|| Error::MissingElement(name.to_string()).at(node))
+}
+
+fn parse_number(node: &Node, text: &str) -> Result<f64> {
+    text.parse::<f64>().map_err(|e| Error::from(e).at(node))
+}
+
+/// In lenient mode, turns a per-element error into a warning and returns `None`.
+fn recover<T>(
+    result: Result<T>,
+    options: &ParseOptions,
+    warnings: &mut Vec<Error>,
+) -> Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if options.lenient => {
+            warnings.push(e);
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
 }
 
 // -- Accessory parsing functions --
-fn parse_points(spatial_element: &Node) -> Result<HashMap<String, Point>> {
+fn parse_point(point: &Node) -> Result<(String, Point)> {
+    let pos = point
+        .descendants()
+        .find(|child| {
+            child.tag_name().name() == "DirectPosition"
+                && child.tag_name().namespace() == get_xml_namespace(Some("zmn"))
+        })
+        .ok_or_else(|| Error::MissingElement("pos".to_string()))?;
+    let mut x: Option<f64> = None;
+    let mut y: Option<f64> = None;
+    for xy in pos.children() {
+        if xy.tag_name().name() == "X" {
+            x = Some(parse_number(&xy, xy.text().unwrap_or("0"))?);
+        } else if xy.tag_name().name() == "Y" {
+            y = Some(parse_number(&xy, xy.text().unwrap_or("0"))?);
+        }
+    }
+    let x = x.ok_or_else(|| Error::MissingElement("X".to_string()).at(&pos))?;
+    let y = y.ok_or_else(|| Error::MissingElement("Y".to_string()).at(&pos))?;
+    let pos = Point::new(x, y);
+    let point_id = point
+        .attribute("id")
+        .ok_or_else(|| Error::MissingAttribute {
+            element: "GM_Point".to_string(),
+            attribute: "id".to_string(),
+        })?;
+    Ok((point_id.to_string(), pos))
+}
+
+fn parse_points(
+    spatial_element: &Node,
+    options: &ParseOptions,
+    warnings: &mut Vec<Error>,
+) -> Result<HashMap<String, Point>> {
     let mut points = HashMap::new();
     let gm_point_iter = spatial_element.children().filter(|child| {
         child.tag_name().name() == "GM_Point"
             && child.tag_name().namespace() == get_xml_namespace(Some("zmn"))
     });
     for point in gm_point_iter {
-        let pos = point
-            .descendants()
-            .find(|child| {
-                child.tag_name().name() == "DirectPosition"
-                    && child.tag_name().namespace() == get_xml_namespace(Some("zmn"))
-            })
-            .ok_or_else(|| Error::MissingElement("pos".to_string()))?;
-        let mut x: Option<f64> = None;
-        let mut y: Option<f64> = None;
-        for xy in pos.children() {
-            if xy.tag_name().name() == "X" {
-                x = Some(xy.text().unwrap_or("0").parse::<f64>()?);
-            } else if xy.tag_name().name() == "Y" {
-                y = Some(xy.text().unwrap_or("0").parse::<f64>()?);
-            }
+        let result =
+            parse_point(&point).map_err(|e| e.at(&point).within("GM_Point", point.attribute("id")));
+        if let Some((point_id, pos)) = recover(result, options, warnings)? {
+            points.insert(point_id, pos);
         }
-        let x = x.ok_or_else(|| Error::MissingElement("X".to_string()))?;
-        let y = y.ok_or_else(|| Error::MissingElement("Y".to_string()))?;
-        let pos = Point::new(x, y);
-        let point_id = point
-            .attribute("id")
-            .ok_or_else(|| Error::MissingAttribute {
-                element: "GM_Point".to_string(),
-                attribute: "id".to_string(),
-            })?;
-        points.insert(point_id.to_string(), pos);
     }
     Ok(points)
 }
 
+fn parse_curve(curve: &Node, points: &HashMap<String, Point>) -> Result<(String, Curve)> {
+    let zmn_ns = get_xml_namespace(Some("zmn"));
+    let curve_id = curve
+        .attribute("id")
+        .ok_or_else(|| Error::MissingAttribute {
+            element: "GM_Curve".to_string(),
+            attribute: "id".to_string(),
+        })?;
+
+    let segment = curve
+        .children()
+        .find(|child| {
+            child.tag_name().name() == "GM_Curve.segment" && child.tag_name().namespace() == zmn_ns
+        })
+        .ok_or_else(|| Error::MissingElement("GM_Curve.segment".to_string()))?;
+
+    let column = segment
+        .descendants()
+        .find(|child| {
+            child.tag_name().name() == "GM_PointArray.column"
+                && child.tag_name().namespace() == zmn_ns
+        })
+        .ok_or_else(|| Error::MissingElement("GM_PointArray.column".to_string()).at(&segment))?;
+    let pos = column
+        .first_element_child()
+        .ok_or_else(|| Error::MissingElement("GM_Position.*".to_string()).at(&column))?;
+
+    let (x, y) = if pos.tag_name().name() == "GM_Position.indirect" {
+        let r#ref = pos
+            .first_element_child()
+            .ok_or_else(|| Error::MissingElement("GM_Position.indirect".to_string()).at(&pos))?;
+        let idref = r#ref.attribute("idref").ok_or_else(|| {
+            Error::MissingAttribute {
+                element: "GM_Position.indirect".to_string(),
+                attribute: "idref".to_string(),
+            }
+            .at(&r#ref)
+        })?;
+        let point = points
+            .get(idref)
+            .ok_or_else(|| Error::PointNotFound(idref.to_string()).at(&r#ref))?;
+
+        (point.x(), point.y())
+    } else if pos.tag_name().name() == "GM_Position.direct" {
+        let x = pos
+            .children()
+            .find(|child| child.tag_name().name() == "X")
+            .ok_or_else(|| Error::MissingElement("X".to_string()).at(&pos))?;
+        let x = parse_number(
+            &x,
+            x.text()
+                .ok_or_else(|| Error::MissingElement("X".to_string()).at(&x))?,
+        )?;
+        let y = pos
+            .children()
+            .find(|child| child.tag_name().name() == "Y")
+            .ok_or_else(|| Error::MissingElement("Y".to_string()).at(&pos))?;
+        let y = parse_number(
+            &y,
+            y.text()
+                .ok_or_else(|| Error::MissingElement("Y".to_string()).at(&y))?,
+        )?;
+        (x, y)
+    } else {
+        return Err(Error::UnexpectedElement(pos.tag_name().name().to_string()).at(&pos));
+    };
+
+    Ok((curve_id.to_string(), Curve::new(y, x)))
+}
+
 fn parse_curves(
     spatial_element: &Node,
     points: &HashMap<String, Point>,
+    options: &ParseOptions,
+    warnings: &mut Vec<Error>,
 ) -> Result<HashMap<String, Curve>> {
     let mut curves = HashMap::new();
     let zmn_ns = get_xml_namespace(Some("zmn"));
@@ -103,69 +211,11 @@ fn parse_curves(
     for curve in spatial_element.children().filter(|child| {
         child.tag_name().name() == "GM_Curve" && child.tag_name().namespace() == zmn_ns
     }) {
-        let curve_id = curve
-            .attribute("id")
-            .ok_or_else(|| Error::MissingAttribute {
-                element: "GM_Curve".to_string(),
-                attribute: "id".to_string(),
-            })?;
-
-        let segment = curve
-            .children()
-            .find(|child| {
-                child.tag_name().name() == "GM_Curve.segment"
-                    && child.tag_name().namespace() == zmn_ns
-            })
-            .ok_or_else(|| Error::MissingElement("GM_Curve.segment".to_string()))?;
-
-        let column = segment
-            .descendants()
-            .find(|child| {
-                child.tag_name().name() == "GM_PointArray.column"
-                    && child.tag_name().namespace() == zmn_ns
-            })
-            .ok_or_else(|| Error::MissingElement("GM_PointArray.column".to_string()))?;
-        let pos = column
-            .first_element_child()
-            .ok_or_else(|| Error::MissingElement("GM_Position.*".to_string()))?;
-
-        let (x, y) = if pos.tag_name().name() == "GM_Position.indirect" {
-            let r#ref = pos
-                .first_element_child()
-                .ok_or_else(|| Error::MissingElement("GM_Position.indirect".to_string()))?;
-            let idref = r#ref
-                .attribute("idref")
-                .ok_or_else(|| Error::MissingAttribute {
-                    element: "GM_Position.indirect".to_string(),
-                    attribute: "idref".to_string(),
-                })?;
-            let point = points
-                .get(idref)
-                .ok_or_else(|| Error::PointNotFound(idref.to_string()))?;
-
-            (point.x(), point.y())
-        } else if pos.tag_name().name() == "GM_Position.direct" {
-            let x = pos
-                .children()
-                .find(|child| child.tag_name().name() == "X")
-                .ok_or_else(|| Error::MissingElement("X".to_string()))?
-                .text()
-                .ok_or_else(|| Error::MissingElement("X".to_string()))?
-                .parse::<f64>()?;
-            let y = pos
-                .children()
-                .find(|child| child.tag_name().name() == "Y")
-                .ok_or_else(|| Error::MissingElement("Y".to_string()))?
-                .text()
-                .ok_or_else(|| Error::MissingElement("Y".to_string()))?
-                .parse::<f64>()?;
-            (x, y)
-        } else {
-            return Err(Error::UnexpectedElement(pos.tag_name().name().to_string()));
-        };
-
-        let curve_point = Curve::new(y, x);
-        curves.insert(curve_id.to_string(), curve_point);
+        let result = parse_curve(&curve, points)
+            .map_err(|e| e.at(&curve).within("GM_Curve", curve.attribute("id")));
+        if let Some((curve_id, curve_point)) = recover(result, options, warnings)? {
+            curves.insert(curve_id, curve_point);
+        }
     }
 
     Ok(curves)
@@ -188,9 +238,89 @@ fn transform_curves_crs(
     Ok(())
 }
 
+/// Resolves the `GM_CompositeCurve.generator` references of every `GM_Ring` below `boundary`.
+fn parse_ring(boundary: &Node, curves: &HashMap<String, Curve>) -> Result<LineString> {
+    let zmn_ns = get_xml_namespace(Some("zmn"));
+    let mut ring: Vec<Point> = Vec::new();
+    for cc in boundary
+        .descendants()
+        .filter(|child| {
+            child.tag_name().name() == "GM_Ring" && child.tag_name().namespace() == zmn_ns
+        })
+        .flat_map(|ring| ring.children().filter(|child| child.is_element()))
+    {
+        let curve_id = cc.attribute("idref").ok_or_else(|| {
+            Error::MissingAttribute {
+                element: cc.tag_name().name().to_string(),
+                attribute: "idref".to_string(),
+            }
+            .at(&cc)
+        })?;
+        let curve = curves
+            .get(curve_id)
+            .ok_or_else(|| Error::PointNotFound(curve_id.to_string()).at(&cc))?;
+        ring.push(*curve);
+    }
+    Ok(LineString::from(ring))
+}
+
+fn parse_surface(surface: &Node, curves: &HashMap<String, Curve>) -> Result<(String, Surface)> {
+    let zmn_ns = get_xml_namespace(Some("zmn"));
+    let polygons = surface
+        .children()
+        .filter(|child| {
+            child.tag_name().name() == "GM_Surface.patch" && child.tag_name().namespace() == zmn_ns
+        })
+        .flat_map(|patch| {
+            patch.children().filter(|child| {
+                child.tag_name().name() == "GM_Polygon" && child.tag_name().namespace() == zmn_ns
+            })
+        })
+        .collect::<Vec<_>>();
+    let polygon = polygons
+        .first()
+        .ok_or_else(|| Error::MissingElement("GM_Surface.patch".to_string()))?;
+    let surface_id = surface
+        .attribute("id")
+        .ok_or_else(|| Error::MissingAttribute {
+            element: "GM_Surface".to_string(),
+            attribute: "id".to_string(),
+        })?;
+
+    let exterior = polygon
+        .descendants()
+        .find(|child| {
+            child.tag_name().name() == "GM_SurfaceBoundary.exterior"
+                && child.tag_name().namespace() == zmn_ns
+        })
+        .ok_or_else(|| {
+            Error::MissingElement("GM_SurfaceBoundary.exterior".to_string()).at(polygon)
+        })?;
+    let exterior_ring = parse_ring(&exterior, curves)?;
+
+    let mut interior_rings: Vec<LineString> = Vec::new();
+    for interior in polygon
+        .descendants()
+        .filter(|child| {
+            child.tag_name().name() == "GM_SurfaceBoundary.interior"
+                && child.tag_name().namespace() == zmn_ns
+        })
+        .flat_map(|ring| ring.children().filter(|child| child.is_element()))
+    {
+        interior_rings.push(parse_ring(&interior, curves)?);
+    }
+
+    Ok((
+        surface_id.to_string(),
+        MultiPolygon::new(vec![Polygon::new(exterior_ring, interior_rings)]),
+    ))
+}
+
 fn parse_surfaces(
     spatial_element: &Node,
     curves: &HashMap<String, Curve>,
+    options: &ParseOptions,
+    warnings: &mut Vec<Error>,
 ) -> Result<HashMap<String, Surface>> {
     let mut surfaces = HashMap::new();
     let zmn_ns = get_xml_namespace(Some("zmn"));
@@ -198,161 +328,100 @@ fn parse_surfaces(
     for surface in spatial_element.children().filter(|child| {
         child.tag_name().name() == "GM_Surface" && child.tag_name().namespace() == zmn_ns
     }) {
-        let polygons = surface
-            .children()
-            .filter(|child| {
-                child.tag_name().name() == "GM_Surface.patch"
-                    && child.tag_name().namespace() == zmn_ns
-            })
-            .flat_map(|patch| {
-                patch.children().filter(|child| {
-                    child.tag_name().name() == "GM_Polygon"
-                        && child.tag_name().namespace() == zmn_ns
-                })
-            })
-            .collect::<Vec<_>>();
-        let polygon = polygons
-            .first()
-            .ok_or_else(|| Error::MissingElement("GM_Surface.patch".to_string()))?;
-        let surface_id = surface
-            .attribute("id")
-            .ok_or_else(|| Error::MissingAttribute {
-                element: "GM_Surface".to_string(),
-                attribute: "id".to_string(),
-            })?;
+        let result = parse_surface(&surface, curves)
+            .map_err(|e| e.at(&surface).within("GM_Surface", surface.attribute("id")));
+        if let Some((surface_id, geometry)) = recover(result, options, warnings)? {
+            surfaces.insert(surface_id, geometry);
+        }
+    }
 
-        let exterior = polygon
-            .descendants()
-            .find(|child| {
-                child.tag_name().name() == "GM_SurfaceBoundary.exterior"
-                    && child.tag_name().namespace() == zmn_ns
-            })
-            .ok_or_else(|| Error::MissingElement("GM_SurfaceBoundary.exterior".to_string()))?;
+    Ok(surfaces)
+}
 
-        let mut ring: Vec<Point> = Vec::new();
-        for cc in exterior
-            .descendants()
-            .filter(|child| {
-                child.tag_name().name() == "GM_Ring" && child.tag_name().namespace() == zmn_ns
-            })
-            .flat_map(|ring| ring.children().filter(|child| child.is_element()))
-        {
-            let curve_id = cc
-                .attribute("idref")
-                .ok_or_else(|| Error::MissingAttribute {
-                    element: cc.tag_name().name().to_string(),
+/// Parses a single 筆. Returns `None` if the feature was filtered out as 地区外 / 別図.
+fn parse_fude(
+    fude: &Node,
+    surfaces: &HashMap<String, Surface>,
+    options: &ParseOptions,
+) -> Result<Option<Feature>> {
+    let fude_id = fude
+        .attribute("id")
+        .ok_or_else(|| Error::MissingAttribute {
+            element: "筆".to_string(),
+            attribute: "id".to_string(),
+        })?;
+
+    let mut geometry: Option<MultiPolygon> = None;
+    let mut prop_map: HashMap<String, String> = HashMap::new();
+    for entry in fude.children().filter(|child| child.is_element()) {
+        let name = entry.tag_name().name();
+        if name == "形状" {
+            let idref = entry.attribute("idref").ok_or_else(|| {
+                Error::MissingAttribute {
+                    element: "形状".to_string(),
                     attribute: "idref".to_string(),
-                })?;
-            let curve = curves
-                .get(curve_id)
-                .ok_or_else(|| Error::PointNotFound(curve_id.to_string()))?;
-            ring.push(*curve);
-        }
-        let exterior_ring = LineString::from(ring);
-
-        let mut interior_rings: Vec<LineString> = Vec::new();
-        for interior in polygon
-            .descendants()
-            .filter(|child| {
-                child.tag_name().name() == "GM_SurfaceBoundary.interior"
-                    && child.tag_name().namespace() == zmn_ns
-            })
-            .flat_map(|ring| ring.children().filter(|child| child.is_element()))
-        {
-            let mut ring: Vec<Point> = Vec::new();
-            for cc in interior
-                .descendants()
-                .filter(|child| {
-                    child.tag_name().name() == "GM_Ring" && child.tag_name().namespace() == zmn_ns
-                })
-                .flat_map(|ring| ring.children().filter(|child| child.is_element()))
-            {
-                let curve_id = cc
-                    .attribute("idref")
-                    .ok_or_else(|| Error::MissingAttribute {
-                        element: cc.tag_name().name().to_string(),
-                        attribute: "idref".to_string(),
-                    })?;
-                let curve = curves
-                    .get(curve_id)
-                    .ok_or_else(|| Error::PointNotFound(curve_id.to_string()))?;
-                ring.push(*curve);
-            }
-            interior_rings.push(LineString::from(ring));
+                }
+                .at(&entry)
+            })?;
+            geometry = surfaces.get(idref).cloned();
+        } else {
+            let value = entry.text().unwrap_or("").to_string();
+            prop_map.insert(name.to_string(), value);
         }
+    }
 
-        surfaces.insert(
-            surface_id.to_string(),
-            MultiPolygon::new(vec![Polygon::new(exterior_ring, interior_rings)]),
-        );
+    if !options.include_chikugai {
+        let chiban = prop_map
+            .get("地番")
+            .ok_or_else(|| Error::MissingElement("地番".to_string()))?;
+        if chiban.contains("地区外") || chiban.contains("別図") {
+            return Ok(None);
+        }
     }
 
-    Ok(surfaces)
+    Ok(Some(Feature {
+        geometry: geometry.ok_or_else(|| Error::MissingElement("geometry".to_string()))?,
+        props: FeatureProperties {
+            筆id: fude_id.to_string(),
+            精度区分: prop_map.remove("精度区分"),
+            大字コード: prop_map.remove("大字コード"),
+            丁目コード: prop_map.remove("丁目コード"),
+            小字コード: prop_map.remove("小字コード"),
+            予備コード: prop_map.remove("予備コード"),
+            大字名: prop_map.remove("大字名"),
+            丁目名: prop_map.remove("丁目名"),
+            小字名: prop_map.remove("小字名"),
+            予備名: prop_map.remove("予備名"),
+            地番: prop_map.remove("地番"),
+            座標値種別: prop_map.remove("座標値種別"),
+            筆界未定構成筆: prop_map.remove("筆界未定構成筆"),
+        },
+    }))
 }
 
+/// Returns the parsed features and the number of features that were filtered out
+/// as 地区外 / 別図.
 fn parse_features(
     subject_elem: &Node,
     surfaces: &HashMap<String, Surface>,
     options: &ParseOptions,
-) -> Result<Vec<Feature>> {
+    warnings: &mut Vec<Error>,
+) -> Result<(Vec<Feature>, usize)> {
     let mut features: Vec<Feature> = Vec::new();
+    let mut filtered_chikugai = 0;
     for fude in subject_elem.children().filter(|child| {
         child.tag_name().name() == "筆" && child.tag_name().namespace() == get_xml_namespace(None)
     }) {
-        let fude_id = fude
-            .attribute("id")
-            .ok_or_else(|| Error::MissingAttribute {
-                element: "筆".to_string(),
-                attribute: "id".to_string(),
-            })?;
-
-        let mut geometry: Option<MultiPolygon> = None;
-        let mut prop_map: HashMap<String, String> = HashMap::new();
-        for entry in fude.children().filter(|child| child.is_element()) {
-            let name = entry.tag_name().name();
-            if name == "形状" {
-                let idref = entry
-                    .attribute("idref")
-                    .ok_or_else(|| Error::MissingAttribute {
-                        element: "形状".to_string(),
-                        attribute: "idref".to_string(),
-                    })?;
-                geometry = surfaces.get(idref).cloned();
-            } else {
-                let value = entry.text().unwrap_or("").to_string();
-                prop_map.insert(name.to_string(), value);
-            }
-        }
-
-        if !options.include_chikugai {
-            let chiban = prop_map
-                .get("地番")
-                .ok_or_else(|| Error::MissingElement("地番".to_string()))?;
-            if chiban.contains("地区外") || chiban.contains("別図") {
-                continue;
-            }
+        let result = parse_fude(&fude, surfaces, options)
+            .map_err(|e| e.at(&fude).within("筆", fude.attribute("id")));
+        match recover(result, options, warnings)? {
+            Some(Some(feature)) => features.push(feature),
+            Some(None) => filtered_chikugai += 1,
+            // Dropped in lenient mode
+            None => {}
         }
-
-        features.push(Feature {
-            geometry: geometry.ok_or_else(|| Error::MissingElement("geometry".to_string()))?,
-            props: FeatureProperties {
-                筆id: fude_id.to_string(),
-                精度区分: prop_map.remove("精度区分"),
-                大字コード: prop_map.remove("大字コード"),
-                丁目コード: prop_map.remove("丁目コード"),
-                小字コード: prop_map.remove("小字コード"),
-                予備コード: prop_map.remove("予備コード"),
-                大字名: prop_map.remove("大字名"),
-                丁目名: prop_map.remove("丁目名"),
-                小字名: prop_map.remove("小字名"),
-                予備名: prop_map.remove("予備名"),
-                地番: prop_map.remove("地番"),
-                座標値種別: prop_map.remove("座標値種別"),
-                筆界未定構成筆: prop_map.remove("筆界未定構成筆"),
-            },
-        });
     }
-    Ok(features)
+    Ok((features, filtered_chikugai))
 }
 
 fn parse_base_properties(root: &Node) -> Result<CommonProperties> {
@@ -380,16 +449,45 @@ fn parse_base_properties(root: &Node) -> Result<CommonProperties> {
     })
 }
 
+/// Why a whole XML file produced no features.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum SkipReason {
+    /// The file uses 任意座標系 and arbitrary CRS files were not requested.
+    #[serde(rename = "任意座標系")]
+    ArbitraryCrs,
+}
+
 pub struct ParsedXML {
     pub file_name: String,
     pub features: Vec<Feature>,
     pub common_props: CommonProperties,
+    pub skip_reason: Option<SkipReason>,
+    /// Number of 地区外 / 別図 features that were dropped.
+    pub filtered_chikugai: usize,
+    /// Errors for individual elements that were dropped in lenient mode.
+    pub warnings: Vec<Error>,
 }
 
 // --- Main Parsing Function ---
 pub fn parse_xml_content(file: &FileData, options: &ParseOptions) -> Result<ParsedXML> {
-    let file_name = file.file_name.clone();
-    let doc = Document::parse(&file.contents)?;
+    parse_document(&file.file_name, &read_document(file)?, options)
+}
+
+/// Parses the XML of a file, for callers that look at the document themselves as well.
+pub fn read_document(file: &FileData) -> Result<Document<'_>> {
+    Document::parse(&file.contents).map_err(|e| Error::from(e).in_file(&file.file_name))
+}
+
+/// Like [`parse_xml_content`], for a file whose XML has been parsed already.
+pub fn parse_document(
+    file_name: &str,
+    doc: &Document,
+    options: &ParseOptions,
+) -> Result<ParsedXML> {
+    build_parsed_xml(file_name, doc, options).map_err(|e| e.in_file(file_name))
+}
+
+fn build_parsed_xml(file_name: &str, doc: &Document, options: &ParseOptions) -> Result<ParsedXML> {
     let root = doc.root_element();
 
     let common_props = parse_base_properties(&root)?;
@@ -400,33 +498,91 @@ pub fn parse_xml_content(file: &FileData, options: &ParseOptions) -> Result<Pars
     let crs = get_proj(crs_string)?;
     if crs.is_none() && !options.include_arbitrary_crs {
         return Ok(ParsedXML {
-            file_name,
+            file_name: file_name.to_string(),
             features: vec![],
             common_props,
+            skip_reason: Some(SkipReason::ArbitraryCrs),
+            filtered_chikugai: 0,
+            warnings: vec![],
         });
     }
 
     let spatial_element = get_child_element(&root, "空間属性")?;
-    let points = parse_points(&spatial_element)?;
-    let mut curves = parse_curves(&spatial_element, &points)?;
+    let mut warnings = Vec::new();
+    let points = parse_points(&spatial_element, options, &mut warnings)?;
+    let mut curves = parse_curves(&spatial_element, &points, options, &mut warnings)?;
     if let Some(crs) = crs {
         let tgt_crs = get_proj("WGS84")?.expect("WGS84 CRS not found");
         transform_curves_crs(&mut curves, &crs, &tgt_crs)?;
     }
 
-    let surfaces = parse_surfaces(&spatial_element, &curves)?;
+    let surfaces = parse_surfaces(&spatial_element, &curves, options, &mut warnings)?;
     let subject_elem = get_child_element(&root, "主題属性")?;
 
-    let features = parse_features(&subject_elem, &surfaces, options)?;
+    let (features, filtered_chikugai) =
+        parse_features(&subject_elem, &surfaces, options, &mut warnings)?;
     Ok(ParsedXML {
-        file_name,
+        file_name: file_name.to_string(),
         features,
         common_props,
+        skip_reason: None,
+        filtered_chikugai,
+        warnings: warnings.into_iter().map(|w| w.in_file(file_name)).collect(),
     })
 }
 
+/// What a file holds, read from its header and 主題属性 without building geometries.
+#[derive(Debug, Clone)]
+pub struct XmlSummary {
+    pub common_props: CommonProperties,
+    /// Number of 筆 elements, including 地区外 and 別図.
+    pub fude_count: usize,
+    /// 筆 whose 地番 contains 地区外.
+    pub chikugai_count: usize,
+    /// 筆 whose 地番 contains 別図.
+    pub betsuzu_count: usize,
+    /// 精度区分 values and the number of 筆 with each; `None` when the element is missing.
+    pub 精度区分: HashMap<Option<String>, usize>,
+}
+
+pub fn summarize_xml_content(file: &FileData) -> Result<XmlSummary> {
+    summarize_document(file).map_err(|e| e.in_file(&file.file_name))
+}
+
+fn summarize_document(file: &FileData) -> Result<XmlSummary> {
+    let doc = Document::parse(&file.contents)?;
+    let root = doc.root_element();
+    let mut summary = XmlSummary {
+        common_props: parse_base_properties(&root)?,
+        fude_count: 0,
+        chikugai_count: 0,
+        betsuzu_count: 0,
+        精度区分: HashMap::new(),
+    };
+    let subject_elem = get_child_element(&root, "主題属性")?;
+    for fude in subject_elem.children().filter(|child| {
+        child.tag_name().name() == "筆" && child.tag_name().namespace() == get_xml_namespace(None)
+    }) {
+        summary.fude_count += 1;
+        let text = |name: &str| {
+            fude.children()
+                .find(|child| child.tag_name().name() == name)
+                .map(|child| child.text().unwrap_or("").to_string())
+        };
+        let chiban = text("地番").unwrap_or_default();
+        if chiban.contains("地区外") {
+            summary.chikugai_count += 1;
+        }
+        if chiban.contains("別図") {
+            summary.betsuzu_count += 1;
+        }
+        *summary.精度区分.entry(text("精度区分")).or_default() += 1;
+    }
+    Ok(summary)
+}
+
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     use std::fs;
     use std::path::Path;
@@ -440,11 +596,12 @@ mod tests {
         let options = ParseOptions {
             include_arbitrary_crs: true,
             include_chikugai: true,
+            lenient: false,
         };
         let ParsedXML {
-            file_name: _,
             features,
             common_props,
+            ..
         } = parse_xml_content(
             &FileData {
                 file_name: "46505-3411-56.xml".to_string(),
@@ -462,4 +619,110 @@ mod tests {
         assert_eq!(feature.props.筆id, "H000000001");
         assert_eq!(feature.props.地番, Some("1".to_string()));
     }
+
+    /// A minimal 地図XML document with one square 筆 in 任意座標系.
+    pub(crate) const SAMPLE_XML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<地図 xmlns="http://www.moj.go.jp/MINJI/tizuxml" xmlns:zmn="http://www.moj.go.jp/MINJI/tizuzumen">
+<地図名>テスト地図</地図名>
+<市区町村コード>46505</市区町村コード>
+<市区町村名>熊毛郡屋久島町</市区町村名>
+<座標系>任意座標系</座標系>
+<空間属性>
+<zmn:GM_Point id="P1"><zmn:GM_Point.position><zmn:DirectPosition><zmn:X>0</zmn:X><zmn:Y>0</zmn:Y></zmn:DirectPosition></zmn:GM_Point.position></zmn:GM_Point>
+<zmn:GM_Curve id="C1"><zmn:GM_Curve.segment><zmn:GM_LineString><zmn:GM_LineString.controlPoint><zmn:GM_PointArray.column>
+<zmn:GM_Position.indirect><zmn:GM_PointRef.point idref="P1"/></zmn:GM_Position.indirect>
+</zmn:GM_PointArray.column></zmn:GM_LineString.controlPoint></zmn:GM_LineString></zmn:GM_Curve.segment></zmn:GM_Curve>
+<zmn:GM_Curve id="C2"><zmn:GM_Curve.segment><zmn:GM_LineString><zmn:GM_LineString.controlPoint><zmn:GM_PointArray.column><zmn:GM_Position.direct><zmn:X>10</zmn:X><zmn:Y>0</zmn:Y></zmn:GM_Position.direct></zmn:GM_PointArray.column></zmn:GM_LineString.controlPoint></zmn:GM_LineString></zmn:GM_Curve.segment></zmn:GM_Curve>
+<zmn:GM_Curve id="C3"><zmn:GM_Curve.segment><zmn:GM_LineString><zmn:GM_LineString.controlPoint><zmn:GM_PointArray.column><zmn:GM_Position.direct><zmn:X>10</zmn:X><zmn:Y>10</zmn:Y></zmn:GM_Position.direct></zmn:GM_PointArray.column></zmn:GM_LineString.controlPoint></zmn:GM_LineString></zmn:GM_Curve.segment></zmn:GM_Curve>
+<zmn:GM_Curve id="C4"><zmn:GM_Curve.segment><zmn:GM_LineString><zmn:GM_LineString.controlPoint><zmn:GM_PointArray.column><zmn:GM_Position.direct><zmn:X>0</zmn:X><zmn:Y>10</zmn:Y></zmn:GM_Position.direct></zmn:GM_PointArray.column></zmn:GM_LineString.controlPoint></zmn:GM_LineString></zmn:GM_Curve.segment></zmn:GM_Curve>
+<zmn:GM_Surface id="S1"><zmn:GM_Surface.patch><zmn:GM_Polygon><zmn:GM_Polygon.boundary><zmn:GM_SurfaceBoundary><zmn:GM_SurfaceBoundary.exterior><zmn:GM_Ring>
+<zmn:GM_CompositeCurve.generator idref="C1"/>
+<zmn:GM_CompositeCurve.generator idref="C2"/>
+<zmn:GM_CompositeCurve.generator idref="C3"/>
+<zmn:GM_CompositeCurve.generator idref="C4"/>
+<zmn:GM_CompositeCurve.generator idref="C1"/>
+</zmn:GM_Ring></zmn:GM_SurfaceBoundary.exterior></zmn:GM_SurfaceBoundary></zmn:GM_Polygon.boundary></zmn:GM_Polygon></zmn:GM_Surface.patch></zmn:GM_Surface>
+</空間属性>
+<主題属性>
+<筆 id="H1"><大字コード>010</大字コード><大字名>安房</大字名><地番>1</地番><形状 idref="S1"/></筆>
+</主題属性>
+</地図>
+"#;
+
+    pub(crate) fn sample_file(contents: &str) -> FileData {
+        FileData {
+            file_name: "sample.xml".to_string(),
+            contents: contents.to_string(),
+        }
+    }
+
+    pub(crate) fn arbitrary_options() -> ParseOptions {
+        ParseOptions {
+            include_arbitrary_crs: true,
+            include_chikugai: false,
+            lenient: false,
+        }
+    }
+
+    #[test]
+    fn test_parse_sample_xml() {
+        let parsed = parse_xml_content(&sample_file(SAMPLE_XML), &arbitrary_options())
+            .expect("Failed to parse XML");
+        assert_eq!(parsed.features.len(), 1);
+        assert_eq!(parsed.features[0].props.地番.as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn test_error_location() {
+        let xml = SAMPLE_XML.replace(r#"idref="P1""#, r#"idref="P9""#);
+        let err = parse_xml_content(&sample_file(&xml), &arbitrary_options())
+            .err()
+            .expect("Expected an error");
+        assert_eq!(err.kind(), "PointNotFound");
+        let location = err.location().expect("Expected a location");
+        assert_eq!(location.file_name.as_deref(), Some("sample.xml"));
+        assert_eq!(location.position.map(|(line, _)| line), Some(10));
+        assert_eq!(location.element.as_deref(), Some("GM_Curve C1"));
+        assert!(err.to_string().contains("sample.xml:10:"));
+    }
+
+    #[test]
+    fn test_lenient_drops_bad_feature() {
+        let xml = SAMPLE_XML.replace(r#"<形状 idref="S1"/>"#, r#"<形状 idref="S9"/>"#);
+        let strict = parse_xml_content(&sample_file(&xml), &arbitrary_options());
+        assert!(strict.is_err());
+
+        let options = ParseOptions {
+            lenient: true,
+            ..arbitrary_options()
+        };
+        let parsed = parse_xml_content(&sample_file(&xml), &options).expect("Lenient parse failed");
+        assert!(parsed.features.is_empty());
+        assert_eq!(parsed.warnings.len(), 1);
+        let location = parsed.warnings[0].location().unwrap();
+        assert_eq!(location.element.as_deref(), Some("筆 H1"));
+        assert_eq!(location.file_name.as_deref(), Some("sample.xml"));
+    }
+
+    #[test]
+    fn test_lenient_drops_bad_point() {
+        let xml = SAMPLE_XML.replacen("<zmn:X>0</zmn:X>", "<zmn:X>abc</zmn:X>", 1);
+        let options = ParseOptions {
+            lenient: true,
+            ..arbitrary_options()
+        };
+        let parsed = parse_xml_content(&sample_file(&xml), &options).expect("Lenient parse failed");
+        // The broken point cascades to the curve, surface and 筆 that reference it.
+        assert!(parsed.features.is_empty());
+        let kinds = parsed.warnings.iter().map(|w| w.kind()).collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                "ParseFloat",
+                "PointNotFound",
+                "PointNotFound",
+                "MissingElement"
+            ]
+        );
+    }
 }
@@ -0,0 +1,261 @@
+//! Writing PMTiles (version 3) archives: a header, a directory of tile offsets and the
+//! tile data in one file, readable with HTTP range requests.
+//!
+//! Tiles are given already compressed. The directories and metadata are gzipped; if
+//! the root directory gets too large for the first 16 KiB of the file, the entries are
+//! moved to leaf directories.
+
+use crate::mvt::write_varint;
+use anyhow::Result;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use std::io::Write;
+
+const HEADER_SIZE: usize = 127;
+/// The header and root directory have to fit into the first request a reader makes.
+const MAX_ROOT_SIZE: usize = 16384 - HEADER_SIZE;
+const FIRST_LEAF_SIZE: usize = 4096;
+const COMPRESSION_GZIP: u8 = 2;
+const TILE_TYPE_MVT: u8 = 1;
+
+/// Everything in the header besides the section offsets.
+pub struct ArchiveInfo {
+    pub min_zoom: u8,
+    pub max_zoom: u8,
+    /// Bounds as (min lon, min lat, max lon, max lat).
+    pub bounds: [f64; 4],
+    /// JSON metadata (the TileJSON-like `vector_layers` and so on).
+    pub metadata: serde_json::Value,
+}
+
+struct Entry {
+    tile_id: u64,
+    offset: u64,
+    length: u32,
+    run_length: u32,
+}
+
+/// Writes an archive. `tiles` are (tile ID, gzipped MVT) pairs sorted by tile ID.
+pub fn write_archive(
+    out: &mut impl Write,
+    tiles: &[(u64, Vec<u8>)],
+    info: &ArchiveInfo,
+) -> Result<()> {
+    let mut entries = Vec::with_capacity(tiles.len());
+    let mut offset = 0;
+    for (tile_id, data) in tiles {
+        entries.push(Entry {
+            tile_id: *tile_id,
+            offset,
+            length: data.len() as u32,
+            run_length: 1,
+        });
+        offset += data.len() as u64;
+    }
+    let tile_data_length = offset;
+    let (root, leaves) = build_directories(&entries)?;
+    let metadata = gzip(&serde_json::to_vec(&info.metadata)?)?;
+
+    let root_offset = HEADER_SIZE as u64;
+    let metadata_offset = root_offset + root.len() as u64;
+    let leaves_offset = metadata_offset + metadata.len() as u64;
+    let tile_data_offset = leaves_offset + leaves.len() as u64;
+
+    let mut header = Vec::with_capacity(HEADER_SIZE);
+    header.extend_from_slice(b"PMTiles");
+    header.push(3);
+    for value in [
+        root_offset,
+        root.len() as u64,
+        metadata_offset,
+        metadata.len() as u64,
+        leaves_offset,
+        leaves.len() as u64,
+        tile_data_offset,
+        tile_data_length,
+        // Addressed tiles, tile entries and tile contents: no tile is stored twice.
+        tiles.len() as u64,
+        tiles.len() as u64,
+        tiles.len() as u64,
+    ] {
+        header.extend_from_slice(&value.to_le_bytes());
+    }
+    // Clustered: tiles are in tile ID order.
+    header.push(1);
+    header.push(COMPRESSION_GZIP);
+    header.push(COMPRESSION_GZIP);
+    header.push(TILE_TYPE_MVT);
+    header.push(info.min_zoom);
+    header.push(info.max_zoom);
+    let [min_lon, min_lat, max_lon, max_lat] = info.bounds;
+    for value in [min_lon, min_lat, max_lon, max_lat] {
+        header.extend_from_slice(&e7(value).to_le_bytes());
+    }
+    header.push(info.min_zoom);
+    header.extend_from_slice(&e7((min_lon + max_lon) / 2.0).to_le_bytes());
+    header.extend_from_slice(&e7((min_lat + max_lat) / 2.0).to_le_bytes());
+    debug_assert_eq!(header.len(), HEADER_SIZE);
+
+    out.write_all(&header)?;
+    out.write_all(&root)?;
+    out.write_all(&metadata)?;
+    out.write_all(&leaves)?;
+    for (_, data) in tiles {
+        out.write_all(data)?;
+    }
+    Ok(())
+}
+
+fn e7(degrees: f64) -> i32 {
+    (degrees * 1e7).round() as i32
+}
+
+/// Returns the root directory and the leaf directories, gzipped.
+fn build_directories(entries: &[Entry]) -> Result<(Vec<u8>, Vec<u8>)> {
+    let root = gzip(&serialize_directory(entries))?;
+    if root.len() <= MAX_ROOT_SIZE {
+        return Ok((root, Vec::new()));
+    }
+    let mut leaf_size = FIRST_LEAF_SIZE;
+    loop {
+        let mut leaves = Vec::new();
+        let mut root_entries = Vec::new();
+        for chunk in entries.chunks(leaf_size) {
+            let leaf = gzip(&serialize_directory(chunk))?;
+            root_entries.push(Entry {
+                tile_id: chunk[0].tile_id,
+                offset: leaves.len() as u64,
+                length: leaf.len() as u32,
+                // A run length of 0 points to a leaf directory.
+                run_length: 0,
+            });
+            leaves.extend_from_slice(&leaf);
+        }
+        let root = gzip(&serialize_directory(&root_entries))?;
+        if root.len() <= MAX_ROOT_SIZE {
+            return Ok((root, leaves));
+        }
+        leaf_size *= 2;
+    }
+}
+
+/// Entries are stored column by column, tile IDs as deltas and offsets as 0 when an
+/// entry directly follows the one before it.
+fn serialize_directory(entries: &[Entry]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    write_varint(&mut buf, entries.len() as u64);
+    let mut last_id = 0;
+    for entry in entries {
+        write_varint(&mut buf, entry.tile_id - last_id);
+        last_id = entry.tile_id;
+    }
+    for entry in entries {
+        write_varint(&mut buf, entry.run_length as u64);
+    }
+    for entry in entries {
+        write_varint(&mut buf, entry.length as u64);
+    }
+    for (i, entry) in entries.iter().enumerate() {
+        if i > 0 && entry.offset == entries[i - 1].offset + entries[i - 1].length as u64 {
+            write_varint(&mut buf, 0);
+        } else {
+            write_varint(&mut buf, entry.offset + 1);
+        }
+    }
+    buf
+}
+
+pub fn gzip(data: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+/// Position of a tile on the Hilbert curve of its zoom level, after all tiles of the
+/// lower zoom levels.
+pub fn tile_id(z: u8, x: u32, y: u32) -> u64 {
+    let base = ((1u64 << (2 * z as u64)) - 1) / 3;
+    let (mut x, mut y) = (x as u64, y as u64);
+    let mut d = 0;
+    let mut s = (1u64 << z) / 2;
+    while s > 0 {
+        let rx = (x & s > 0) as u64;
+        let ry = (y & s > 0) as u64;
+        d += s * s * ((3 * rx) ^ ry);
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - (x & (s - 1));
+                y = s - 1 - (y & (s - 1));
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+    base + d
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn test_tile_id() {
+        assert_eq!(tile_id(0, 0, 0), 0);
+        assert_eq!(tile_id(1, 0, 0), 1);
+        assert_eq!(tile_id(1, 0, 1), 2);
+        assert_eq!(tile_id(1, 1, 1), 3);
+        assert_eq!(tile_id(1, 1, 0), 4);
+        assert_eq!(tile_id(2, 0, 0), 5);
+        assert_eq!(tile_id(3, 7, 0), 84);
+        assert_eq!(tile_id(14, 14500, 6600), 293505487);
+        assert_eq!(tile_id(20, 0, 0), 366503875925);
+    }
+
+    #[test]
+    fn test_write_archive() {
+        // Irregular IDs and sizes, so the directory doesn't compress into the root.
+        let mut random = 1u64;
+        let mut next = |max: u64| {
+            random = random
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (random >> 33) % max
+        };
+        let mut id = 0;
+        let tiles: Vec<(u64, Vec<u8>)> = (0..20_000)
+            .map(|_| {
+                id += 1 + next(1000);
+                (id, vec![0; 20 + next(1000) as usize])
+            })
+            .collect();
+        let info = ArchiveInfo {
+            min_zoom: 0,
+            max_zoom: 10,
+            bounds: [130.0, 31.0, 131.0, 32.0],
+            metadata: serde_json::json!({ "name": "test" }),
+        };
+        let mut out = Vec::new();
+        write_archive(&mut out, &tiles, &info).unwrap();
+
+        let u64_at =
+            |offset: usize| u64::from_le_bytes(out[offset..offset + 8].try_into().unwrap());
+        assert_eq!(&out[..7], b"PMTiles");
+        let (root_offset, root_length) = (u64_at(8) as usize, u64_at(16) as usize);
+        assert!(root_offset + root_length <= 16384);
+        // Too many entries for the root directory, so there are leaves.
+        assert!(u64_at(48) > 0);
+        assert_eq!(u64_at(80), 20_000);
+
+        let mut root = Vec::new();
+        GzDecoder::new(&out[root_offset..root_offset + root_length])
+            .read_to_end(&mut root)
+            .unwrap();
+        assert_eq!(root[0] as usize, 20_000usize.div_ceil(FIRST_LEAF_SIZE));
+
+        // The last tile is at the end of the file.
+        let last = &tiles.last().unwrap().1;
+        assert_eq!(&out[out.len() - last.len()..], &last[..]);
+    }
+}
@@ -1,24 +1,121 @@
+use crate::cache::{
+    Cache, CacheRecorder, CachedFile, ManifestEntry, load_cached_file, write_cache_file,
+};
+use crate::dedup::{DedupOptions, Deduplicator, priority_order};
+use crate::error::{PipelineError, PipelineFailure};
+use crate::memory::{self, InputBudget, MemoryBudget, Reservation, parsed_size};
+use crate::order::{self, InputTracker, OrderGate, Permits, path_order, ranks};
 use crate::parse::{ParseOptions, ParsedXML};
-use crate::reader::{FileData, iter_xml_contents};
-use crate::writer::WriterOptions;
-use anyhow::Result;
-use crossbeam_channel::{bounded, unbounded};
+use crate::reader::{FileData, ReaderOptions, ZipEntries, is_stdin, is_zip, iter_xml_contents};
+use crate::report::{ErrorReport, FileReport, FileStatus, ReportCollector, RunReport};
+use crate::stats::StageStats;
+use crate::tiles::TileOptions;
+use crate::writer::{Output, WriterOptions};
+use crossbeam_channel::{RecvTimeoutError, bounded, unbounded};
 use indicatif::{MultiProgress, ProgressStyle};
-use log::{error, info};
+use log::{error, info, warn};
+use std::collections::HashSet;
+use std::fs;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
-use std::sync::atomic::{AtomicI8, AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicI8, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
 use std::thread;
 use std::thread::JoinHandle;
-use std::time::Instant;
+use std::time::{Duration, Instant};
+
+/// An XML file on its way to the parsers.
+struct ParserItem {
+    input: usize,
+    /// Index of the XML file within its input.
+    seq: usize,
+    /// Zip entry the file was read from, if the input is read entry by entry.
+    entry: String,
+    file_data: FileData,
+    /// Memory reserved for the file, released when it is dropped.
+    _memory: Option<Reservation>,
+}
+
+/// A parsed file on its way to the writer.
+struct WriterItem {
+    input: usize,
+    /// Index of the XML file within its input.
+    seq: usize,
+    /// Index of the file in the report.
+    slot: usize,
+    parsed: ParsedXML,
+    /// Memory reserved for the parsed features, released when they are written.
+    _memory: Option<Reservation>,
+}
+
+/// Work for the unzip threads: a whole input, or one entry of a zip input.
+struct UnzipJob {
+    input: usize,
+    path: PathBuf,
+    entry: Option<(usize, Arc<SplitInput>)>,
+}
+
+/// A zip input whose entries are decompressed by several unzip threads at once.
+struct SplitInput {
+    /// Entries not read yet. The thread that reads the last one finishes the input.
+    remaining: AtomicUsize,
+    read_ok: AtomicBool,
+    start: OnceLock<Instant>,
+}
+
+/// Work for the cache thread.
+enum CacheJob {
+    /// An input whose parsed results are loaded from the cache.
+    Load(ManifestEntry),
+    /// An input to parse, handed on to the unzip threads when its turn comes.
+    Parse(PathBuf),
+}
+
+/// Thread counts and channel capacities. `None` picks a default based on `threads`.
+#[derive(Debug, Clone, Default)]
+pub struct ThreadOptions {
+    /// Number of CPUs to plan for (default: all of them).
+    pub threads: Option<usize>,
+    /// Threads reading and unzipping the inputs (default: a quarter of `threads`).
+    /// Always 1 in deterministic mode.
+    pub unzip_threads: Option<usize>,
+    /// Threads parsing XML files (default: `threads` - 1, at least 2).
+    pub parse_threads: Option<usize>,
+    /// XML files that can wait for a parser (default: `threads`).
+    pub parse_queue: Option<usize>,
+    /// Parsed files that can wait for the writer (default: 1).
+    pub write_queue: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ProcessOptions {
+    /// Stop the whole run on the first read, parse or write error.
+    pub strict: bool,
+    /// Cache parsed results in this directory and reuse them for unchanged inputs.
+    pub cache_dir: Option<PathBuf>,
+    /// Drop features that appear in more than one input.
+    pub dedup: Option<DedupOptions>,
+    /// Write files in a fixed order (by input path and entry index), so identical
+    /// input gives byte-identical output.
+    pub deterministic: bool,
+    /// How nested archives are extracted.
+    pub reader: ReaderOptions,
+    /// Block the unzip and parse stages while the files between the stages hold more
+    /// than this many bytes.
+    pub max_memory: Option<usize>,
+    pub threads: ThreadOptions,
+    /// Write vector tiles instead of a FlatGeobuf file.
+    pub tiles: Option<TileOptions>,
+}
 
 pub fn process_files(
     output_path: &Path,
     src_files: Vec<PathBuf>,
     parse_options: ParseOptions,
     write_options: WriterOptions,
-) -> Result<usize> {
-    let concurrency = num_cpus::get();
+    process_options: ProcessOptions,
+) -> Result<RunReport, PipelineFailure> {
+    let threads = &process_options.threads;
+    let concurrency = threads.threads.unwrap_or_else(num_cpus::get);
     let m = MultiProgress::with_draw_target(indicatif::ProgressDrawTarget::stdout_with_hz(2));
     let sty = ProgressStyle::with_template(
         "[{msg}] {elapsed_precise} {bar:40.cyan/blue} {pos:>7}/{len:7}",
@@ -26,112 +123,492 @@ pub fn process_files(
     .unwrap()
     .progress_chars("##-");
 
-    let xml_files = Arc::new(AtomicUsize::new(0));
     let has_features = Arc::new(AtomicI8::new(0));
+    // Set on the first error in strict mode. Stages keep draining their channels
+    // once this is set, but stop doing any real work.
+    let aborted = Arc::new(AtomicBool::new(false));
+    let strict = process_options.strict;
+    let report = ReportCollector::new(RunReport::new(output_path, &src_files));
+    let tracker = InputTracker::new(src_files.len());
+    // The order files are written in, if it matters. When deduplicating, inputs are
+    // processed (and written) winners first; that order is deterministic as well.
+    let order = match &process_options.dedup {
+        Some(dedup) => Some(priority_order(&src_files, dedup.policy)),
+        None if process_options.deterministic => Some(path_order(&src_files)),
+        None => None,
+    };
+    let rank = order.as_deref().map(ranks).unwrap_or_default();
+    // In deterministic mode the inputs are read one at a time and in order, so that
+    // the number of files waiting to be written can be bounded.
+    let deterministic = process_options.deterministic;
+    let reader_options = process_options.reader;
+    let unzip_threads = if deterministic {
+        1
+    } else {
+        threads
+            .unzip_threads
+            .unwrap_or(std::cmp::max(1, concurrency / 4))
+    };
+    let parse_threads = threads
+        .parse_threads
+        .unwrap_or(std::cmp::max(2, concurrency.saturating_sub(1)));
+    let budget = process_options
+        .max_memory
+        .map(|limit| MemoryBudget::new(limit, parse_threads));
+    if let (Some(budget), Some(_)) = (&budget, &order) {
+        budget.set_frontier(0);
+    }
+    // Split zip inputs into their entries so the unzip threads can share a large archive.
+    // Not done when the order matters, since the entries would reach the writer out of order.
+    let split_zips = unzip_threads > 1 && order.is_none();
+    let permits = deterministic.then(|| Permits::new(concurrency * 4));
+
+    let recorder = match &process_options.cache_dir {
+        Some(dir) => match Cache::open(dir, &parse_options) {
+            Ok(cache) => Some(CacheRecorder::new(cache)),
+            Err(e) => {
+                return Err(failure(
+                    report.finish(false, Duration::ZERO),
+                    PipelineError::Cache(e),
+                ));
+            }
+        },
+        None => None,
+    };
+    // Split the inputs into those that can be loaded from the cache and those to parse.
+    let mut to_parse = Vec::new();
+    let mut cached = Vec::new();
+    match &recorder {
+        Some(recorder) => {
+            let mut seen = HashSet::new();
+            for (input, path) in src_files.into_iter().enumerate() {
+                // An input given twice is parsed again instead of sharing its cache entry.
+                // Standard input can't be checked for changes, so it is never cached.
+                if !is_stdin(&path) && seen.insert(path.clone()) {
+                    if let Some(entry) = recorder.lookup(&path) {
+                        info!("[cache] Using cached results for {}", path.display());
+                        cached.push((input, CacheJob::Load(entry)));
+                        continue;
+                    }
+                    recorder.track(input, &path);
+                }
+                to_parse.push((input, path));
+            }
+        }
+        None => to_parse.extend(src_files.into_iter().enumerate()),
+    }
+    if order.is_some() {
+        // The cache thread hands the inputs to parse to the unzip threads in turn, so an
+        // input that fails to load from the cache is read before the inputs after it.
+        // Otherwise the unzip threads could be stuck on a later input while the writer
+        // waits for this one.
+        if !cached.is_empty() {
+            cached.extend(
+                to_parse
+                    .drain(..)
+                    .map(|(input, path)| (input, CacheJob::Parse(path))),
+            );
+        }
+        to_parse.sort_by_key(|(input, _)| rank[*input]);
+        cached.sort_by_key(|(input, _)| rank[*input]);
+    }
 
     // XML channels
-    let (xml_tx, xml_rx) = unbounded::<PathBuf>();
+    let (xml_tx, xml_rx) = unbounded::<UnzipJob>();
     let xml_pb = m.add(
         indicatif::ProgressBar::new(0)
             .with_style(sty.clone())
             .with_message("unzipping"),
     );
     // Parser channels
-    let (parser_tx, parser_rx) = bounded::<FileData>(concurrency);
+    let (parser_tx, parser_rx) = bounded::<ParserItem>(threads.parse_queue.unwrap_or(concurrency));
     let parser_pb = m.add(
         indicatif::ProgressBar::new(0)
             .with_style(sty.clone())
             .with_message("XML parse"),
     );
     // Writer channels
-    let (writer_tx, writer_rx) = bounded::<ParsedXML>(1);
+    let (writer_tx, writer_rx) = bounded::<WriterItem>(threads.write_queue.unwrap_or(1));
     let writer_pb = m.add(
         indicatif::ProgressBar::new(0)
             .with_style(sty.clone())
             .with_message("FGB write"),
     );
 
+    // Create the output before starting any threads, so a bad destination fails fast.
+    let mut fgb = match Output::create(output_path, &write_options, process_options.tiles.as_ref())
+    {
+        Ok(fgb) => fgb,
+        Err(e) => {
+            let error = PipelineError::CreateOutput {
+                path: output_path.to_path_buf(),
+                error: e,
+            };
+            return Err(failure(report.finish(false, Duration::ZERO), error));
+        }
+    };
+
+    // Every stage returns when its input channel is closed or its output channel is
+    // disconnected, so a failing stage shuts down the stages before it as well.
     let start = Instant::now();
-    let mut handles: Vec<JoinHandle<()>> = Vec::new();
+    let cache_stats = StageStats::new("cache", 1);
+    let unzip_stats = StageStats::new("unzip", unzip_threads);
+    let parse_stats = StageStats::new("parse", parse_threads);
+    let write_stats = StageStats::new("write", 1);
+    let mut handles: Vec<(&'static str, JoinHandle<Result<(), PipelineError>>)> = Vec::new();
     {
+        let xml_tx = xml_tx.clone();
         let xml_pb = xml_pb.clone();
-        handles.push(thread::spawn(move || {
-            for path in src_files {
-                info!("Input file: {}", path.display());
-                xml_pb.inc_length(1);
-                xml_tx.send(path).unwrap();
-            }
-        }));
+        let reader_options = reader_options.clone();
+        handles.push((
+            "input",
+            thread::spawn(move || {
+                for (input, path) in to_parse {
+                    info!("Input file: {}", path.display());
+                    xml_pb.inc_length(1);
+                    let jobs = unzip_jobs(input, path, split_zips, &reader_options);
+                    if jobs.into_iter().any(|job| xml_tx.send(job).is_err()) {
+                        break;
+                    }
+                }
+                Ok(())
+            }),
+        ));
     }
-    for i in 0..std::cmp::max(1, concurrency / 4) {
-        let xml_rx = xml_rx.clone();
-        let parser_tx = parser_tx.clone();
+    if let Some(recorder) = &recorder {
+        let xml_tx = xml_tx.clone();
+        let writer_tx = writer_tx.clone();
         let xml_pb = xml_pb.clone();
-        let parser_pb = parser_pb.clone();
-        let xml_files = xml_files.clone();
-        handles.push(thread::spawn(move || {
-            while let Ok(path) = xml_rx.recv() {
-                info!("[ZIP {:>2}] Opening file: {}", i, path.display());
-                for item in iter_xml_contents(&path) {
-                    match item {
-                        Ok(file_data) => {
-                            info!(
-                                "[ZIP {:>2}] Got XML: {}, size: {}",
-                                i,
-                                file_data.file_name,
-                                file_data.contents.len()
-                            );
-                            xml_files.fetch_add(1, Ordering::Relaxed);
-                            parser_pb.inc_length(1);
-                            parser_tx.send(file_data).unwrap();
+        let writer_pb = writer_pb.clone();
+        let recorder = recorder.clone();
+        let reader_options = reader_options.clone();
+        let report = report.clone();
+        let tracker = tracker.clone();
+        let permits = permits.clone();
+        let rank = rank.clone();
+        let aborted = aborted.clone();
+        let budget = budget.clone();
+        let stats = cache_stats.clone();
+        handles.push((
+            "cache",
+            thread::spawn(move || {
+                let _timer = stats.thread();
+                for (input, job) in cached {
+                    if aborted.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    let entry = match job {
+                        CacheJob::Load(entry) => entry,
+                        CacheJob::Parse(path) => {
+                            info!("Input file: {}", path.display());
+                            xml_pb.inc_length(1);
+                            let jobs = unzip_jobs(input, path, split_zips, &reader_options);
+                            if jobs.into_iter().any(|job| xml_tx.send(job).is_err()) {
+                                break;
+                            }
+                            continue;
                         }
+                    };
+                    let input_start = Instant::now();
+                    let loaded: anyhow::Result<Vec<ParsedXML>> = entry
+                        .files
+                        .iter()
+                        .map(|file| load_cached_file(recorder.dir(), file))
+                        .collect();
+                    let files = match loaded {
+                        Ok(files) => files,
                         Err(e) => {
-                            error!(
-                                "[ZIP {:>2}] Error reading file {}: {}",
-                                i,
-                                path.display(),
+                            warn!(
+                                "[cache] Failed to load {}, parsing it again: {}",
+                                entry.path.display(),
                                 e
                             );
-                            eprintln!("Error reading file {}: {}", path.display(), e);
+                            recorder.track(input, &entry.path);
+                            xml_pb.inc_length(1);
+                            let jobs = unzip_jobs(input, entry.path, split_zips, &reader_options);
+                            if jobs.into_iter().any(|job| xml_tx.send(job).is_err()) {
+                                break;
+                            }
+                            continue;
+                        }
+                    };
+                    report.input_cached(input, input_start.elapsed());
+                    for parsed in files {
+                        let reservation = stats.waiting_for_output(|| {
+                            if let Some(permits) = &permits {
+                                permits.acquire(rank[input]);
+                            }
+                            memory::reserve(
+                                budget.as_ref(),
+                                parsed_size(&parsed),
+                                rank.get(input).copied(),
+                            )
+                        });
+                        let seq = tracker.queued(input);
+                        let slot = report.add_file(input, parsed_file_report(&parsed, 0));
+                        writer_pb.inc_length(1);
+                        let item = WriterItem {
+                            input,
+                            seq,
+                            slot,
+                            parsed,
+                            _memory: reservation,
+                        };
+                        if stats.waiting_for_output(|| writer_tx.send(item).is_err()) {
+                            warn!("[cache] Writer stage stopped, exiting");
+                            return Ok(());
                         }
                     }
+                    tracker.read_done(input);
                 }
-                // Increment the unzipping progress bar when we're done with all the
-                // files in a file.
-                xml_pb.inc(1);
-            }
-        }));
+                Ok(())
+            }),
+        ));
+    }
+    drop(xml_tx);
+
+    for i in 0..unzip_threads {
+        let xml_rx = xml_rx.clone();
+        let parser_tx = parser_tx.clone();
+        let xml_pb = xml_pb.clone();
+        let parser_pb = parser_pb.clone();
+        let report = report.clone();
+        let recorder = recorder.clone();
+        let tracker = tracker.clone();
+        let permits = permits.clone();
+        let rank = rank.clone();
+        let aborted = aborted.clone();
+        let budget = budget.clone();
+        let reader_options = reader_options.clone();
+        let stats = unzip_stats.clone();
+        handles.push((
+            "unzip",
+            thread::spawn(move || {
+                let _timer = stats.thread();
+                // The zip this thread is reading entries of, by input index.
+                let mut open_zip: Option<(usize, ZipEntries)> = None;
+                'inputs: while let Ok(UnzipJob { input, path, entry }) = stats.recv(&xml_rx) {
+                    if aborted.load(Ordering::Relaxed) {
+                        continue;
+                    }
+                    let reader_options = ReaderOptions {
+                        memory: budget.clone().map(|budget| InputBudget {
+                            budget,
+                            rank: rank.get(input).copied(),
+                        }),
+                        ..reader_options.clone()
+                    };
+                    let mut entry_name = String::new();
+                    let items = match &entry {
+                        None => {
+                            info!("[ZIP {:>2}] Opening file: {}", i, path.display());
+                            iter_xml_contents(&path, &reader_options)
+                        }
+                        Some((index, split)) => {
+                            split.start.get_or_init(Instant::now);
+                            let opened = match open_zip.take() {
+                                Some((open, zip)) if open == input => Ok(zip),
+                                _ => {
+                                    info!("[ZIP {:>2}] Opening file: {}", i, path.display());
+                                    ZipEntries::open(&path, &reader_options)
+                                }
+                            };
+                            match opened {
+                                Ok(mut zip) => {
+                                    entry_name = zip.entry_name(*index).to_string();
+                                    let items = zip.read(*index);
+                                    open_zip = Some((input, zip));
+                                    items
+                                }
+                                Err(e) => Box::new(std::iter::once(Err(e))),
+                            }
+                        }
+                    };
+                    let input_start = Instant::now();
+                    let mut read_ok = true;
+                    for item in items {
+                        if aborted.load(Ordering::Relaxed) {
+                            read_ok = false;
+                            break;
+                        }
+                        match item {
+                            Ok(file_data) => {
+                                info!(
+                                    "[ZIP {:>2}] Got XML: {}, size: {}",
+                                    i,
+                                    file_data.file_name,
+                                    file_data.contents.len()
+                                );
+                                parser_pb.inc_length(1);
+                                if let Some(recorder) = &recorder {
+                                    recorder.file_queued(input);
+                                }
+                                let reservation = stats.waiting_for_output(|| {
+                                    if let Some(permits) = &permits {
+                                        permits.acquire(rank[input]);
+                                    }
+                                    memory::reserve(
+                                        budget.as_ref(),
+                                        file_data.contents.len(),
+                                        rank.get(input).copied(),
+                                    )
+                                });
+                                let item = ParserItem {
+                                    input,
+                                    seq: tracker.queued(input),
+                                    entry: entry_name.clone(),
+                                    file_data,
+                                    _memory: reservation,
+                                };
+                                if stats.waiting_for_output(|| parser_tx.send(item).is_err()) {
+                                    warn!("[ZIP {:>2}] Parser stage stopped, exiting", i);
+                                    break 'inputs;
+                                }
+                            }
+                            Err(e) => {
+                                error!(
+                                    "[ZIP {:>2}] Error reading file {}: {}",
+                                    i,
+                                    path.display(),
+                                    e
+                                );
+                                eprintln!("Error reading file {}: {}", path.display(), e);
+                                report.input_error(input, ErrorReport::new(e.kind(), &e));
+                                read_ok = false;
+                                if strict {
+                                    aborted.store(true, Ordering::Relaxed);
+                                }
+                            }
+                        }
+                    }
+                    let input_start = match &entry {
+                        None => input_start,
+                        Some((_, split)) => {
+                            split.read_ok.fetch_and(read_ok, Ordering::Relaxed);
+                            if split.remaining.fetch_sub(1, Ordering::AcqRel) > 1 {
+                                continue;
+                            }
+                            read_ok = split.read_ok.load(Ordering::Relaxed);
+                            *split.start.get().unwrap()
+                        }
+                    };
+                    report.input_done(input, input_start.elapsed());
+                    if let Some(recorder) = &recorder {
+                        recorder.input_read(input, read_ok);
+                    }
+                    tracker.read_done(input);
+                    // Increment the unzipping progress bar when we're done with all the
+                    // files in a file.
+                    xml_pb.inc(1);
+                }
+                Ok(())
+            }),
+        ));
     }
     drop(parser_tx);
 
-    for i in 0..std::cmp::max(2, concurrency - 1) {
+    for i in 0..parse_threads {
         let parser_rx = parser_rx.clone();
         let writer_tx = writer_tx.clone();
         let parser_pb = parser_pb.clone();
         let writer_pb = writer_pb.clone();
         let options = parse_options.clone();
-        handles.push(thread::spawn(move || {
-            while let Ok(file_data) = parser_rx.recv() {
-                info!("[XML {:>2}] Parsing file: {}", i, file_data.file_name);
-                let parsed_xml = crate::parse::parse_xml_content(&file_data, &options);
-                match parsed_xml {
-                    Ok(parsed) => {
-                        info!("[XML {:>2}] Parsed file: {}", i, file_data.file_name);
-                        writer_pb.inc_length(1);
+        let report = report.clone();
+        let recorder = recorder.clone();
+        let tracker = tracker.clone();
+        let rank = rank.clone();
+        let aborted = aborted.clone();
+        let budget = budget.clone();
+        let stats = parse_stats.clone();
+        handles.push((
+            "parser",
+            thread::spawn(move || {
+                let _timer = stats.thread();
+                while let Ok(ParserItem {
+                    input,
+                    seq,
+                    entry,
+                    file_data,
+                    _memory: file_memory,
+                }) = stats.recv(&parser_rx)
+                {
+                    if aborted.load(Ordering::Relaxed) {
+                        tracker.failed(input, seq);
                         parser_pb.inc(1);
-                        writer_tx.send(parsed).unwrap();
+                        continue;
                     }
-                    Err(e) => {
-                        error!(
-                            "[XML {:>2}] Error parsing file {}: {}",
-                            i, file_data.file_name, e
-                        );
-                        eprintln!("Error parsing file {}: {}", file_data.file_name, e);
-                        parser_pb.inc(1);
+                    info!("[XML {:>2}] Parsing file: {}", i, file_data.file_name);
+                    let parse_start = Instant::now();
+                    let parsed_xml = crate::parse::parse_xml_content(&file_data, &options);
+                    let parse_ms = parse_start.elapsed().as_millis() as u64;
+                    drop(file_memory);
+                    match parsed_xml {
+                        Ok(parsed) => {
+                            info!("[XML {:>2}] Parsed file: {}", i, file_data.file_name);
+                            for warning in &parsed.warnings {
+                                warn!("[XML {:>2}] Dropped element: {}", i, warning);
+                            }
+                            let slot =
+                                report.add_file(input, parsed_file_report(&parsed, parse_ms));
+                            if let Some(recorder) = &recorder {
+                                cache_parsed_file(recorder, input, entry, seq, &parsed);
+                            }
+                            let reservation = stats.waiting_for_output(|| {
+                                memory::reserve_parsed(
+                                    budget.as_ref(),
+                                    parsed_size(&parsed),
+                                    rank.get(input).copied(),
+                                )
+                            });
+                            writer_pb.inc_length(1);
+                            parser_pb.inc(1);
+                            let item = WriterItem {
+                                input,
+                                seq,
+                                slot,
+                                parsed,
+                                _memory: reservation,
+                            };
+                            if stats.waiting_for_output(|| writer_tx.send(item).is_err()) {
+                                warn!("[XML {:>2}] Writer stage stopped, exiting", i);
+                                break;
+                            }
+                        }
+                        Err(e) => {
+                            error!(
+                                "[XML {:>2}] Error parsing file {}: {}",
+                                i, file_data.file_name, e
+                            );
+                            eprintln!("Error parsing file {}: {}", file_data.file_name, e);
+                            report.add_file(
+                                input,
+                                FileReport {
+                                    file_name: file_data.file_name.clone(),
+                                    status: FileStatus::ParseError,
+                                    feature_count: 0,
+                                    skip_reason: None,
+                                    filtered_chikugai: 0,
+                                    error: Some(ErrorReport::from(&e)),
+                                    warnings: vec![],
+                                    duplicates_dropped: 0,
+                                    parse_ms,
+                                    write_ms: 0,
+                                },
+                            );
+                            if let Some(recorder) = &recorder {
+                                recorder.file_failed(input);
+                            }
+                            tracker.failed(input, seq);
+                            if strict {
+                                aborted.store(true, Ordering::Relaxed);
+                            }
+                            parser_pb.inc(1);
+                        }
                     }
                 }
-            }
-        }));
+                Ok(())
+            }),
+        ));
     }
     drop(writer_tx);
 
@@ -139,50 +616,271 @@ pub fn process_files(
         let output_path = output_path.to_path_buf();
         let writer_pb = writer_pb.clone();
         let has_features = has_features.clone();
-        handles.push(thread::spawn(move || {
-            let mut fgb = crate::writer::FGBWriter::new(&output_path, &write_options).unwrap();
-            while let Ok(parsed_xml) = writer_rx.recv() {
-                info!("[FGB] Adding features from file: {}", parsed_xml.file_name);
-                let write_result = fgb.add_xml_features(parsed_xml);
-                match write_result {
-                    Ok(_) => {
-                        writer_pb.inc(1);
+        let report = report.clone();
+        let aborted = aborted.clone();
+        let mut dedup = process_options
+            .dedup
+            .as_ref()
+            .map(|dedup| Deduplicator::new(dedup.key));
+        let budget = budget.clone();
+        let stats = write_stats.clone();
+        let mut gate = order.map(|order| OrderGate::new(order, tracker.clone(), permits.clone()));
+        handles.push((
+            "writer",
+            thread::spawn(move || {
+                let _timer = stats.thread();
+                let mut write_file = |item: WriterItem| {
+                    let WriterItem {
+                        input,
+                        slot,
+                        parsed: mut parsed_xml,
+                        ..
+                    } = item;
+                    if aborted.load(Ordering::Relaxed) {
+                        return;
+                    }
+                    if let Some(dedup) = &mut dedup {
+                        let dropped = dedup.retain(&mut parsed_xml);
+                        if dropped > 0 {
+                            info!(
+                                "[FGB] Dropped {} duplicate feature(s) from file: {}",
+                                dropped, parsed_xml.file_name
+                            );
+                            report.duplicates_dropped(input, slot, dropped);
+                        }
                     }
+                    info!("[FGB] Adding features from file: {}", parsed_xml.file_name);
+                    let write_start = Instant::now();
+                    let write_result = fgb.add_xml_features(parsed_xml);
+                    match write_result {
+                        Ok(_) => {
+                            report.file_written(input, slot, write_start.elapsed());
+                            writer_pb.inc(1);
+                        }
+                        Err(e) => {
+                            eprintln!("Error writing file {}: {}", output_path.display(), e);
+                            report.file_write_error(input, slot, ErrorReport::new("Write", &e));
+                            if strict {
+                                aborted.store(true, Ordering::Relaxed);
+                            }
+                        }
+                    }
+                };
+                match &mut gate {
+                    None => {
+                        while let Ok(item) = stats.recv(&writer_rx) {
+                            write_file(item);
+                        }
+                    }
+                    // Put files back into order before writing them.
+                    Some(gate) => {
+                        loop {
+                            match stats.recv_timeout(&writer_rx, order::POLL_INTERVAL) {
+                                Ok(item) => gate.push(item.input, item.seq, item),
+                                Err(RecvTimeoutError::Timeout) => {}
+                                Err(RecvTimeoutError::Disconnected) => break,
+                            }
+                            gate.pop_ready().into_iter().for_each(&mut write_file);
+                            if let Some(budget) = &budget {
+                                budget.set_frontier(gate.frontier());
+                            }
+                        }
+                        gate.drain().into_iter().for_each(&mut write_file);
+                    }
+                }
+                if aborted.load(Ordering::Relaxed) {
+                    info!("[FGB] Run aborted, discarding output file");
+                    return fgb.discard().map_err(|e| PipelineError::Cleanup {
+                        path: output_path,
+                        error: e,
+                    });
+                }
+                info!("[FGB] Starting output file: {}", output_path.display());
+                let created_file = match fgb.flush() {
+                    Ok(created_file) => created_file,
                     Err(e) => {
-                        eprintln!("Error writing file {}: {}", output_path.display(), e);
+                        remove_partial_output(&output_path);
+                        return Err(PipelineError::Flush {
+                            path: output_path,
+                            error: e,
+                        });
                     }
+                };
+                if !created_file {
+                    info!("[FGB] No features written");
+                    has_features.fetch_sub(1, Ordering::Relaxed);
+                } else {
+                    info!("[FGB] Finished writing file: {}", output_path.display());
+                    has_features.fetch_add(1, Ordering::Relaxed);
                 }
+                Ok(())
+            }),
+        ));
+    }
+
+    // Join every thread, even after a failure, so that nothing is left running
+    // when we return. The first error wins.
+    let mut result = Ok(());
+    for (stage, handle) in handles {
+        let stage_result = handle
+            .join()
+            .unwrap_or(Err(PipelineError::ThreadPanicked(stage)));
+        if let Err(e) = stage_result {
+            error!("[{}] {}", stage, e);
+            if result.is_ok() {
+                result = Err(e);
             }
-            info!("[FGB] Starting output file: {}", output_path.display());
-            let created_file = fgb.flush().unwrap();
-            if !created_file {
-                info!("[FGB] No features written");
-                has_features.fetch_sub(1, Ordering::Relaxed);
-            } else {
-                info!("[FGB] Finished writing file: {}", output_path.display());
-                has_features.fetch_add(1, Ordering::Relaxed);
-            }
-        }));
+        }
     }
-    let _ = handles
-        .into_iter()
-        .map(|h| h.join().expect("Thread panicked"))
-        .collect::<Vec<_>>();
     let elapsed = start.elapsed();
 
+    // Save the manifest even after a failure, so the next run can reuse the inputs
+    // that were finished.
+    if let Some(Err(e)) = recorder.as_ref().map(CacheRecorder::finish) {
+        error!("[cache] Failed to save manifest: {}", e);
+        eprintln!("Failed to save cache manifest: {}", e);
+    }
+    memory::log_peak(budget.as_ref());
+
     xml_pb.finish();
     parser_pb.finish();
     writer_pb.finish();
 
+    let output_written = has_features.load(Ordering::Relaxed) > 0;
+    let aborted = aborted.load(Ordering::Relaxed);
+    let mut report = report.finish(output_written, elapsed);
+    report.aborted = aborted;
+    if recorder.is_some() {
+        report.stages.push(cache_stats.report());
+    }
+    report.stages.extend(
+        [unzip_stats, parse_stats, write_stats]
+            .iter()
+            .map(StageStats::report),
+    );
+
+    if let Err(e) = result {
+        if matches!(e, PipelineError::ThreadPanicked(_)) {
+            remove_partial_output(output_path);
+            report.output_written = false;
+        }
+        return Err(failure(report, e));
+    }
+
     println!(
         "\nFinished processing files in {}.{:03}",
         elapsed.as_secs(),
         elapsed.subsec_millis()
     );
-
-    if has_features.load(Ordering::Relaxed) <= 0 {
+    if !output_written && !aborted {
         eprintln!("Empty output file: {}", output_path.display());
     }
+    Ok(report)
+}
+
+/// Pairs the error that stopped the pipeline with the report of what was done before it.
+fn failure(mut report: RunReport, error: PipelineError) -> PipelineFailure {
+    report.error = Some(ErrorReport::new(error.kind(), &error));
+    PipelineFailure {
+        error,
+        report: Box::new(report),
+    }
+}
 
-    Ok(xml_files.load(Ordering::Relaxed))
+/// The unzip jobs for an input. With `split`, a zip input is read entry by entry.
+fn unzip_jobs(input: usize, path: PathBuf, split: bool, options: &ReaderOptions) -> Vec<UnzipJob> {
+    if split && is_zip(&path) {
+        // If the zip can't be opened here, the whole-input job reports the error.
+        if let Ok(zip) = ZipEntries::open(&path, options) {
+            let count = zip.entry_count();
+            if count > 0 {
+                let split = Arc::new(SplitInput {
+                    remaining: AtomicUsize::new(count),
+                    read_ok: AtomicBool::new(true),
+                    start: OnceLock::new(),
+                });
+                return (0..count)
+                    .map(|index| UnzipJob {
+                        input,
+                        path: path.clone(),
+                        entry: Some((index, split.clone())),
+                    })
+                    .collect();
+            }
+        }
+    }
+    vec![UnzipJob {
+        input,
+        path,
+        entry: None,
+    }]
+}
+
+fn parsed_file_report(parsed: &ParsedXML, parse_ms: u64) -> FileReport {
+    let status = if parsed.skip_reason.is_some() {
+        FileStatus::Skipped
+    } else {
+        FileStatus::Parsed
+    };
+    FileReport {
+        file_name: parsed.file_name.clone(),
+        status,
+        feature_count: parsed.features.len(),
+        skip_reason: parsed.skip_reason,
+        filtered_chikugai: parsed.filtered_chikugai,
+        error: None,
+        warnings: parsed.warnings.iter().map(ErrorReport::from).collect(),
+        duplicates_dropped: 0,
+        parse_ms,
+        write_ms: 0,
+    }
+}
+
+/// Writes a parsed XML file to the cache. Files with lenient-mode warnings are not
+/// cached, so their warnings are reported again on the next run.
+fn cache_parsed_file(
+    recorder: &CacheRecorder,
+    input: usize,
+    entry: String,
+    seq: usize,
+    parsed: &ParsedXML,
+) {
+    let Some(cache_file) = recorder.cache_file_name(input, seq) else {
+        return;
+    };
+    if !parsed.warnings.is_empty() {
+        recorder.file_failed(input);
+        return;
+    }
+    match write_cache_file(&recorder.dir().join(&cache_file), parsed) {
+        Ok(written) => recorder.file_cached(
+            input,
+            entry,
+            seq,
+            CachedFile {
+                file_name: parsed.file_name.clone(),
+                feature_count: parsed.features.len(),
+                skip_reason: parsed.skip_reason,
+                filtered_chikugai: parsed.filtered_chikugai,
+                cache_file: written.then_some(cache_file),
+            },
+        ),
+        Err(e) => {
+            warn!("[cache] Failed to cache {}: {}", parsed.file_name, e);
+            recorder.file_failed(input);
+        }
+    }
+}
+
+/// Best-effort removal of an incomplete output file after a fatal error.
+fn remove_partial_output(path: &Path) {
+    match fs::remove_file(path) {
+        Ok(_) => info!("Removed partial output file: {}", path.display()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => error!(
+            "Failed to remove partial output file {}: {}",
+            path.display(),
+            e
+        ),
+    }
 }
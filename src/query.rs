@@ -0,0 +1,479 @@
+//! Looking up parcels in a FlatGeobuf file written by this tool, by bounding box, by a
+//! point they contain or by attribute values.
+//!
+//! Bounding box and point lookups read only the features the packed R-tree of the file
+//! finds. Attribute conditions alone have no index to use and scan the whole file.
+
+use anyhow::{Result, anyhow, bail};
+use clap::ValueEnum;
+use flatgeobuf::{FallibleStreamingIterator, FgbFeature, FgbReader};
+use geo::Intersects;
+use geo_types::{Geometry, LineString, MultiPolygon, Point, Rect, coord};
+use geozero::{FeatureProperties as _, ToGeo};
+use serde_json::{Map, Value, json};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// A GeoJSON FeatureCollection.
+    #[default]
+    Geojson,
+    /// One row per parcel with the attribute columns and the geometry as WKT.
+    Csv,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    /// Parcels intersecting (min lon, min lat, max lon, max lat).
+    pub bbox: Option<[f64; 4]>,
+    /// Parcels containing (lon, lat).
+    pub point: Option<[f64; 2]>,
+    /// (column, value) pairs that all have to match exactly.
+    pub conditions: Vec<(String, String)>,
+}
+
+/// A parcel found by [`find_features`].
+pub struct Match {
+    /// Attribute values in column order; `None` for nulls.
+    pub values: Vec<Option<String>>,
+    pub geometry: MultiPolygon,
+}
+
+/// Names of the attribute columns of a FlatGeobuf file.
+pub fn columns(path: &Path) -> Result<Vec<String>> {
+    let reader = FgbReader::open(BufReader::new(File::open(path)?))?;
+    Ok(header_columns(&reader))
+}
+
+fn header_columns<R: Read>(reader: &FgbReader<R>) -> Vec<String> {
+    reader
+        .header()
+        .columns()
+        .map(|columns| columns.iter().map(|c| c.name().to_string()).collect())
+        .unwrap_or_default()
+}
+
+/// Calls `found` for each parcel matching `filter`, in file order, stopping after
+/// `limit` parcels. Returns whether more parcels would have matched.
+pub fn find_features(
+    path: &Path,
+    filter: &Filter,
+    limit: Option<usize>,
+    mut found: impl FnMut(Match) -> Result<()>,
+) -> Result<bool> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let fgb = FgbReader::open(&mut reader)?;
+    let columns = header_columns(&fgb);
+    check_columns(filter, &columns)?;
+
+    let search = match (filter.bbox, filter.point) {
+        (Some(bbox), _) => Some(bbox),
+        (None, Some([lon, lat])) => Some([lon, lat, lon, lat]),
+        (None, None) => None,
+    };
+    let mut count = 0;
+    let mut visit = |feature: &FgbFeature| -> Result<bool> {
+        let Some(found_match) = matching(feature, filter, &columns)? else {
+            return Ok(true);
+        };
+        if limit.is_some_and(|limit| count == limit) {
+            return Ok(false);
+        }
+        count += 1;
+        found(found_match)?;
+        Ok(true)
+    };
+    let mut iter = match search {
+        Some([min_lon, min_lat, max_lon, max_lat]) => {
+            if fgb.header().index_node_size() == 0 {
+                bail!(
+                    "{} has no spatial index; convert it again without --disable-fgb-index",
+                    path.display()
+                );
+            }
+            fgb.select_bbox(min_lon, min_lat, max_lon, max_lat)?
+        }
+        None => fgb.select_all()?,
+    };
+    while let Some(feature) = iter.next()? {
+        if !visit(feature)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn check_columns(filter: &Filter, columns: &[String]) -> Result<()> {
+    for (column, _) in &filter.conditions {
+        if !columns.contains(column) {
+            bail!(
+                "Unknown column: {} (the file has {})",
+                column,
+                columns.join(", ")
+            );
+        }
+    }
+    Ok(())
+}
+
+fn matching(feature: &FgbFeature, filter: &Filter, columns: &[String]) -> Result<Option<Match>> {
+    let mut properties: HashMap<String, String> = feature.properties()?;
+    let conditions_match = filter
+        .conditions
+        .iter()
+        .all(|(column, value)| properties.get(column) == Some(value));
+    if !conditions_match {
+        return Ok(None);
+    }
+    let geometry = match feature.to_geo()? {
+        Geometry::MultiPolygon(mp) => mp,
+        Geometry::Polygon(p) => MultiPolygon::new(vec![p]),
+        other => return Err(anyhow!("Unexpected geometry type: {:?}", other)),
+    };
+    // The index only compares bounding boxes.
+    if let Some([min_lon, min_lat, max_lon, max_lat]) = filter.bbox {
+        let rect = Rect::new(
+            coord! { x: min_lon, y: min_lat },
+            coord! { x: max_lon, y: max_lat },
+        );
+        if !geometry.intersects(&rect) {
+            return Ok(None);
+        }
+    }
+    if let Some([lon, lat]) = filter.point
+        && !geometry.intersects(&Point::new(lon, lat))
+    {
+        return Ok(None);
+    }
+    Ok(Some(Match {
+        values: columns
+            .iter()
+            .map(|column| properties.remove(column))
+            .collect(),
+        geometry,
+    }))
+}
+
+/// Writes the parcels matching `filter` to `out`. Returns the number of parcels written
+/// and whether `limit` cut the results short.
+pub fn write_features(
+    path: &Path,
+    filter: &Filter,
+    limit: Option<usize>,
+    format: OutputFormat,
+    out: impl Write,
+) -> Result<(usize, bool)> {
+    let columns = columns(path)?;
+    // Before anything is written.
+    check_columns(filter, &columns)?;
+    let mut count = 0;
+    let truncated = match format {
+        OutputFormat::Geojson => {
+            let mut out = out;
+            write!(out, "{{\"type\":\"FeatureCollection\",\"features\":[")?;
+            let truncated = find_features(path, filter, limit, |found| {
+                if count > 0 {
+                    writeln!(out, ",")?;
+                } else {
+                    writeln!(out)?;
+                }
+                count += 1;
+                serde_json::to_writer(&mut out, &feature_json(&columns, &found))?;
+                Ok(())
+            })?;
+            writeln!(out, "\n]}}")?;
+            out.flush()?;
+            truncated
+        }
+        OutputFormat::Csv => {
+            let mut csv = csv::Writer::from_writer(out);
+            csv.write_record(columns.iter().map(String::as_str).chain(["geometry"]))?;
+            let truncated = find_features(path, filter, limit, |found| {
+                count += 1;
+                let wkt = wkt(&found.geometry);
+                csv.write_record(
+                    found
+                        .values
+                        .iter()
+                        .map(|value| value.as_deref().unwrap_or(""))
+                        .chain([wkt.as_str()]),
+                )?;
+                Ok(())
+            })?;
+            csv.flush()?;
+            truncated
+        }
+    };
+    Ok((count, truncated))
+}
+
+/// A GeoJSON feature with the attributes of `found` as properties.
+pub fn feature_json(columns: &[String], found: &Match) -> Value {
+    let properties: Map<String, Value> = columns
+        .iter()
+        .zip(&found.values)
+        .map(|(column, value)| {
+            (
+                column.clone(),
+                value.clone().map_or(Value::Null, Value::String),
+            )
+        })
+        .collect();
+    json!({
+        "type": "Feature",
+        "properties": properties,
+        "geometry": geometry_json(&found.geometry),
+    })
+}
+
+pub fn geometry_json(geometry: &MultiPolygon) -> Value {
+    let ring = |ring: &LineString| -> Value { ring.coords().map(|c| json!([c.x, c.y])).collect() };
+    let polygons: Vec<Value> = geometry
+        .iter()
+        .map(|polygon| {
+            std::iter::once(polygon.exterior())
+                .chain(polygon.interiors())
+                .map(ring)
+                .collect()
+        })
+        .collect();
+    json!({ "type": "MultiPolygon", "coordinates": polygons })
+}
+
+pub fn wkt(geometry: &MultiPolygon) -> String {
+    let ring = |ring: &LineString| {
+        let coords: Vec<String> = ring.coords().map(|c| format!("{} {}", c.x, c.y)).collect();
+        format!("({})", coords.join(","))
+    };
+    let polygons: Vec<String> = geometry
+        .iter()
+        .map(|polygon| {
+            let rings: Vec<String> = std::iter::once(polygon.exterior())
+                .chain(polygon.interiors())
+                .map(ring)
+                .collect();
+            format!("({})", rings.join(","))
+        })
+        .collect();
+    format!("MULTIPOLYGON({})", polygons.join(","))
+}
+
+/// Parses "min_lon,min_lat,max_lon,max_lat".
+pub fn parse_bbox(value: &str) -> Result<[f64; 4], String> {
+    parse_numbers(value).ok_or_else(|| "expected min_lon,min_lat,max_lon,max_lat".to_string())
+}
+
+/// Parses "lon,lat".
+pub fn parse_point(value: &str) -> Result<[f64; 2], String> {
+    parse_numbers(value).ok_or_else(|| "expected lon,lat".to_string())
+}
+
+fn parse_numbers<const N: usize>(value: &str) -> Option<[f64; N]> {
+    let values: Vec<f64> = value
+        .split(',')
+        .map(|v| v.trim().parse().ok())
+        .collect::<Option<_>>()?;
+    values.try_into().ok()
+}
+
+/// Parses "COLUMN=VALUE".
+pub fn parse_condition(value: &str) -> Result<(String, String), String> {
+    value
+        .split_once('=')
+        .filter(|(column, _)| !column.is_empty())
+        .map(|(column, value)| (column.to_string(), value.to_string()))
+        .ok_or_else(|| "expected COLUMN=VALUE".to_string())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::parse::{CommonProperties, Feature, FeatureProperties, ParsedXML};
+    use crate::writer::{FGBWriter, WriterOptions};
+    use geo_types::polygon;
+
+    /// 0.001° squares with their south-west corners at (lon, 31.5) and the given properties.
+    pub(crate) fn sample_parsed(
+        市区町村名: &str,
+        parcels: Vec<(f64, FeatureProperties)>,
+    ) -> ParsedXML {
+        let features = parcels
+            .into_iter()
+            .map(|(lon, props)| Feature {
+                geometry: MultiPolygon::from(vec![polygon![
+                    (x: lon, y: 31.5),
+                    (x: lon + 0.001, y: 31.5),
+                    (x: lon + 0.001, y: 31.501),
+                    (x: lon, y: 31.501),
+                    (x: lon, y: 31.5),
+                ]]),
+                props,
+            })
+            .collect();
+        ParsedXML {
+            file_name: "test.xml".to_string(),
+            features,
+            common_props: CommonProperties {
+                地図名: "テスト地図".to_string(),
+                市区町村コード: "46505".to_string(),
+                市区町村名: 市区町村名.to_string(),
+                座標系: "公共座標2系".to_string(),
+                測地系判別: None,
+            },
+            skip_reason: None,
+            filtered_chikugai: 0,
+            warnings: vec![],
+        }
+    }
+
+    pub(crate) fn write_parsed(path: &Path, parsed: ParsedXML, write_index: bool) -> Result<()> {
+        let mut fgb = FGBWriter::new(
+            path,
+            &WriterOptions {
+                write_index,
+                write_fid: false,
+            },
+        )?;
+        fgb.add_xml_features(parsed)?;
+        fgb.flush()?;
+        Ok(())
+    }
+
+    /// Writes [`sample_parsed`] with a spatial index.
+    pub(crate) fn write_sample(
+        path: &Path,
+        市区町村名: &str,
+        parcels: Vec<(f64, FeatureProperties)>,
+    ) -> Result<()> {
+        write_parsed(path, sample_parsed(市区町村名, parcels), true)
+    }
+
+    /// Two squares next to each other in 屋久島町宮之浦, 地番 1 and 2.
+    pub(crate) fn sample_fgb(path: &Path) -> Result<()> {
+        let props = |地番: &str| FeatureProperties {
+            筆id: format!("H{}", 地番),
+            大字名: Some("宮之浦".to_string()),
+            地番: Some(地番.to_string()),
+            ..Default::default()
+        };
+        write_sample(
+            path,
+            "屋久島町",
+            vec![(130.5, props("1")), (130.501, props("2"))],
+        )
+    }
+
+    fn find(path: &Path, filter: Filter) -> Vec<String> {
+        let columns = columns(path).unwrap();
+        let index = columns.iter().position(|c| c == "地番").unwrap();
+        let mut found = Vec::new();
+        find_features(path, &filter, None, |m| {
+            found.push(m.values[index].clone().unwrap());
+            Ok(())
+        })
+        .unwrap();
+        found.sort();
+        found
+    }
+
+    #[test]
+    fn test_find_features() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("out.fgb");
+        sample_fgb(&path)?;
+
+        let bbox = |bbox| Filter {
+            bbox: Some(bbox),
+            ..Default::default()
+        };
+        assert_eq!(find(&path, bbox([130.4, 31.4, 130.6, 31.6])), ["1", "2"]);
+        assert_eq!(
+            find(&path, bbox([130.5003, 31.5003, 130.5004, 31.5004])),
+            ["1"]
+        );
+        assert!(find(&path, bbox([131.0, 32.0, 131.1, 32.1])).is_empty());
+
+        let point = |point| Filter {
+            point: Some(point),
+            ..Default::default()
+        };
+        assert_eq!(find(&path, point([130.5015, 31.5005])), ["2"]);
+        assert!(find(&path, point([130.5015, 31.502])).is_empty());
+
+        let condition = |conditions: &[(&str, &str)]| Filter {
+            conditions: conditions
+                .iter()
+                .map(|(c, v)| (c.to_string(), v.to_string()))
+                .collect(),
+            ..Default::default()
+        };
+        assert_eq!(
+            find(
+                &path,
+                condition(&[("市区町村名", "屋久島町"), ("地番", "2")])
+            ),
+            ["2"]
+        );
+        assert!(find(&path, condition(&[("大字名", "安房")])).is_empty());
+        assert!(find_features(&path, &condition(&[("番地", "1")]), None, |_| Ok(())).is_err());
+
+        // The limit stops the search and is reported.
+        assert!(find_features(&path, &Filter::default(), Some(1), |_| Ok(
+            ()
+        ))?);
+        assert!(!find_features(&path, &Filter::default(), Some(2), |_| Ok(
+            ()
+        ))?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_features() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("out.fgb");
+        sample_fgb(&path)?;
+        let filter = Filter {
+            conditions: vec![("地番".to_string(), "1".to_string())],
+            ..Default::default()
+        };
+
+        let mut geojson = Vec::new();
+        assert_eq!(
+            write_features(&path, &filter, None, OutputFormat::Geojson, &mut geojson)?,
+            (1, false)
+        );
+        let collection: Value = serde_json::from_slice(&geojson)?;
+        assert_eq!(collection["features"][0]["properties"]["大字名"], "宮之浦");
+        assert_eq!(
+            collection["features"][0]["properties"]["小字名"],
+            Value::Null
+        );
+
+        let mut csv = Vec::new();
+        write_features(&path, &filter, None, OutputFormat::Csv, &mut csv)?;
+        let csv = String::from_utf8(csv)?;
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("global_id,地図名,") && lines[0].ends_with(",geometry"));
+        assert!(lines[1].contains(",宮之浦,") && lines[1].contains("\"MULTIPOLYGON(((130.5 31.5,"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_arguments() {
+        assert_eq!(
+            parse_bbox("130.5, 31.5,131,32"),
+            Ok([130.5, 31.5, 131.0, 32.0])
+        );
+        assert!(parse_bbox("130.5,31.5").is_err());
+        assert_eq!(parse_point("130.5,31.5"), Ok([130.5, 31.5]));
+        assert_eq!(
+            parse_condition("地番=123=4"),
+            Ok(("地番".to_string(), "123=4".to_string()))
+        );
+        assert!(parse_condition("=1").is_err());
+    }
+}
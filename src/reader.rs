@@ -1,9 +1,39 @@
+use crate::memory::{InputBudget, Reservation};
+use flate2::read::MultiGzDecoder;
 use std::fs::File;
-use std::io::{self, Read, Seek, SeekFrom};
-use std::path::Path;
+use std::io::{self, BufReader, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
 use tempfile::NamedTempFile;
 use zip::ZipArchive;
 
+/// Input path that reads a zip, tar or XML stream from standard input.
+pub const STDIN_PATH: &str = "-";
+/// Name reported for an XML file read from standard input.
+const STDIN_FILE_NAME: &str = "stdin";
+
+/// Default for [`ReaderOptions::in_memory_zip_limit`].
+pub const DEFAULT_IN_MEMORY_ZIP_LIMIT: u64 = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct ReaderOptions {
+    /// Nested zips (and zip streams) up to this many bytes are extracted in memory.
+    /// Larger ones are copied to a temporary file first.
+    pub in_memory_zip_limit: u64,
+    /// Memory budget the nested zips extracted in memory are reserved from.
+    pub memory: Option<InputBudget>,
+}
+
+impl Default for ReaderOptions {
+    fn default() -> Self {
+        ReaderOptions {
+            in_memory_zip_limit: DEFAULT_IN_MEMORY_ZIP_LIMIT,
+            memory: None,
+        }
+    }
+}
+
 pub struct FileData {
     pub file_name: String,
     pub contents: String,
@@ -19,24 +49,149 @@ pub enum ReaderError {
     Utf8(#[from] std::string::FromUtf8Error),
 }
 
-pub fn iter_xml_contents(
-    path: &Path,
-) -> Box<dyn Iterator<Item = Result<FileData, ReaderError>> + '_> {
-    let ext = path
-        .extension()
-        .and_then(|os_str| os_str.to_str())
-        .map(|s| s.to_lowercase());
-
-    match ext.as_deref() {
-        Some("xml") => Box::new(std::iter::once(read_xml_file(path))),
-        Some("zip") => match read_zip_archive(path) {
+impl ReaderError {
+    /// Short, stable name of the error variant, used for reporting.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ReaderError::Io(_) => "Io",
+            ReaderError::Zip(_) => "Zip",
+            ReaderError::Utf8(_) => "Utf8",
+        }
+    }
+}
+
+pub type XmlIter = Box<dyn Iterator<Item = Result<FileData, ReaderError>>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Xml,
+    /// A single gzip-compressed XML file.
+    XmlGz,
+    Zip,
+    Tar,
+    TarGz,
+}
+
+impl Format {
+    fn from_name(name: &str) -> Option<Self> {
+        let name = name.to_lowercase();
+        if name.ends_with(".xml") {
+            Some(Format::Xml)
+        } else if name.ends_with(".xml.gz") {
+            Some(Format::XmlGz)
+        } else if name.ends_with(".zip") {
+            Some(Format::Zip)
+        } else if name.ends_with(".tar") {
+            Some(Format::Tar)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(Format::TarGz)
+        } else {
+            None
+        }
+    }
+}
+
+fn path_format(path: &Path) -> Option<Format> {
+    Format::from_name(path.file_name()?.to_str()?)
+}
+
+/// Whether the input is a zip file, which can be read with [`ZipEntries`].
+pub fn is_zip(path: &Path) -> bool {
+    path_format(path) == Some(Format::Zip)
+}
+
+pub fn is_stdin(path: &Path) -> bool {
+    path.as_os_str() == STDIN_PATH
+}
+
+/// Whether [`iter_xml_contents`] can read the file, judging by its extension.
+pub fn is_supported(path: &Path) -> bool {
+    is_stdin(path) || path_format(path).is_some()
+}
+
+pub fn iter_xml_contents(path: &Path, options: &ReaderOptions) -> XmlIter {
+    let options = options.clone();
+    if is_stdin(path) {
+        return read_stream(io::stdin(), options);
+    }
+    let file_name = path
+        .file_name()
+        .and_then(|s| s.to_str())
+        .unwrap_or_default();
+    match path_format(path) {
+        Some(Format::Xml) => Box::new(std::iter::once(read_xml_file(path))),
+        Some(Format::XmlGz) => Box::new(std::iter::once(
+            File::open(path)
+                .map_err(ReaderError::from)
+                .and_then(|file| read_xml(gz_file_name(file_name), MultiGzDecoder::new(file))),
+        )),
+        Some(Format::Zip) => match read_zip_archive(path, options) {
             Ok(iter) => Box::new(iter),
             Err(e) => Box::new(std::iter::once(Err(e))),
         },
-        _ => Box::new(std::iter::empty()),
+        Some(Format::Tar) => match File::open(path) {
+            Ok(file) => read_tar_stream(BufReader::new(file), options),
+            Err(e) => Box::new(std::iter::once(Err(e.into()))),
+        },
+        Some(Format::TarGz) => match File::open(path) {
+            Ok(file) => read_tar_stream(MultiGzDecoder::new(BufReader::new(file)), options),
+            Err(e) => Box::new(std::iter::once(Err(e.into()))),
+        },
+        None => Box::new(std::iter::empty()),
     }
 }
 
+/// Runs `f` on every XML file of the inputs, on `threads` worker threads, and returns
+/// the results in the order the files were read. An input that fails to read gives an
+/// `Err` with its path; the XML files read from it before the error are still mapped.
+pub fn map_xml_files<T, F>(
+    paths: &[PathBuf],
+    options: &ReaderOptions,
+    threads: usize,
+    f: F,
+) -> Vec<Result<T, (PathBuf, ReaderError)>>
+where
+    T: Send,
+    F: Fn(FileData) -> T + Sync,
+{
+    let results = Mutex::new(Vec::new());
+    let (tx, rx) = crossbeam_channel::bounded(threads);
+    thread::scope(|s| {
+        for _ in 0..threads {
+            let rx = rx.clone();
+            let (results, f) = (&results, &f);
+            s.spawn(move || {
+                while let Ok((seq, file_data)) = rx.recv() {
+                    let result = f(file_data);
+                    results.lock().unwrap().push((seq, Ok(result)));
+                }
+            });
+        }
+        drop(rx);
+
+        let mut seq = 0;
+        'inputs: for path in paths {
+            for item in iter_xml_contents(path, options) {
+                match item {
+                    Ok(file_data) => {
+                        // Only fails if every worker panicked, which the scope re-raises.
+                        if tx.send((seq, file_data)).is_err() {
+                            break 'inputs;
+                        }
+                    }
+                    Err(e) => results.lock().unwrap().push((seq, Err((path.clone(), e)))),
+                }
+                seq += 1;
+            }
+        }
+        drop(tx);
+    });
+    let mut results = results.into_inner().unwrap();
+    // Workers finish in any order.
+    results.sort_by_key(|(seq, _)| *seq);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
 fn read_xml_file(path: &Path) -> Result<FileData, ReaderError> {
     let contents = std::fs::read(path)?;
     let name = path
@@ -50,19 +205,107 @@ fn read_xml_file(path: &Path) -> Result<FileData, ReaderError> {
     })
 }
 
+fn read_xml(file_name: &str, mut reader: impl Read) -> Result<FileData, ReaderError> {
+    let mut contents = Vec::new();
+    reader.read_to_end(&mut contents)?;
+    Ok(FileData {
+        file_name: file_name.to_string(),
+        contents: String::from_utf8(contents)?,
+    })
+}
+
+/// `foo.xml.gz` -> `foo.xml`
+fn gz_file_name(file_name: &str) -> &str {
+    &file_name[..file_name.len() - ".gz".len()]
+}
+
+/// Reads an entry of a zip or tar archive: an XML file, a gzip-compressed XML file or
+/// a nested zip. Other entries are skipped.
+fn read_entry(
+    entry_path: &Path,
+    entry: &mut impl Read,
+    size: u64,
+    options: ReaderOptions,
+) -> XmlIter {
+    let file_name = entry_path
+        .file_name()
+        .and_then(|s| s.to_str())
+        .unwrap_or_default();
+    match Format::from_name(file_name) {
+        Some(Format::Xml) => Box::new(std::iter::once(read_xml(file_name, entry))),
+        Some(Format::XmlGz) => Box::new(std::iter::once(read_xml(
+            gz_file_name(file_name),
+            MultiGzDecoder::new(entry),
+        ))),
+        Some(Format::Zip) => match read_nested_zip(entry, Some(size), options) {
+            Ok(iter) => iter,
+            Err(e) => Box::new(std::iter::once(Err(e))),
+        },
+        _ => Box::new(std::iter::empty()),
+    }
+}
+
+/// Opens a zip that can't be seeked (a nested entry or a stream). Small zips are read
+/// into memory, larger ones are copied to a temporary file.
+///
+/// `size` is the size of the zip if known. Otherwise up to the limit is read into
+/// memory, and spilled to a temporary file if the zip turns out to be larger.
+fn read_nested_zip(
+    reader: &mut impl Read,
+    size: Option<u64>,
+    options: ReaderOptions,
+) -> Result<XmlIter, ReaderError> {
+    let limit = options.in_memory_zip_limit;
+    let mut buf = Vec::new();
+    if size.is_none_or(|size| size <= limit) {
+        // The size of a stream is only known once it has been read.
+        let mut memory = match (&options.memory, size) {
+            (Some(memory), Some(size)) => Some(memory.reserve_archive(size as usize)),
+            _ => None,
+        };
+        buf.reserve(size.unwrap_or(0) as usize);
+        reader
+            .by_ref()
+            .take(limit.saturating_add(1))
+            .read_to_end(&mut buf)?;
+        if buf.len() as u64 <= limit {
+            if let (Some(budget), None) = (&options.memory, &memory) {
+                memory = Some(budget.reserve_archive(buf.len()));
+            }
+            let archive = ZipArchive::new(Cursor::new(buf))?;
+            let mut iter = ZipXmlIter::new(archive, options);
+            iter._memory = memory;
+            return Ok(Box::new(iter));
+        }
+    }
+    let mut tmp = NamedTempFile::new()?;
+    tmp.write_all(&buf)?;
+    drop(buf);
+    io::copy(reader, tmp.as_file_mut())?;
+    // clone handle for ZipArchive; the file is deleted once the last handle is closed
+    let mut file = tmp.as_file().try_clone()?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(Box::new(ZipXmlIter::new(ZipArchive::new(file)?, options)))
+}
+
 // streaming ZIP/XML iterator without collecting
 struct ZipXmlIter<R: Read + Seek> {
     archive: ZipArchive<R>,
     index: usize,
-    nested: Option<Box<ZipXmlIter<std::fs::File>>>,
+    nested: Option<XmlIter>,
+    options: ReaderOptions,
+    /// Memory reserved for an archive read into memory, released when it is dropped.
+    _memory: Option<Reservation>,
 }
 
 impl<R: Read + Seek> ZipXmlIter<R> {
-    fn new(archive: ZipArchive<R>) -> Self {
+    fn new(archive: ZipArchive<R>, options: ReaderOptions) -> Self {
         ZipXmlIter {
             archive,
             index: 0,
             nested: None,
+            options,
+            _memory: None,
         }
     }
 }
@@ -84,86 +327,169 @@ impl<R: Read + Seek> Iterator for ZipXmlIter<R> {
             }
             let idx = self.index;
             self.index += 1;
-            // pull the next entry
-            let mut entry = match self.archive.by_index(idx) {
-                Ok(e) => e,
-                Err(e) => return Some(Err(ReaderError::Zip(e))),
-            };
-            let entry_path = match entry.enclosed_name() {
-                Some(p) => p.to_path_buf(),
-                None => continue,
+            self.nested = Some(read_zip_entry(&mut self.archive, idx, self.options.clone()));
+        }
+    }
+}
+
+/// Reads the XML files of the `index`-th entry of a zip archive.
+fn read_zip_entry<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    index: usize,
+    options: ReaderOptions,
+) -> XmlIter {
+    let mut entry = match archive.by_index(index) {
+        Ok(e) => e,
+        Err(e) => return Box::new(std::iter::once(Err(ReaderError::Zip(e)))),
+    };
+    if entry.is_dir() {
+        return Box::new(std::iter::empty());
+    }
+    let entry_path = match entry.enclosed_name() {
+        Some(p) => p.to_path_buf(),
+        None => return Box::new(std::iter::empty()),
+    };
+    let size = entry.size();
+    read_entry(&entry_path, &mut entry, size, options)
+}
+
+/// A zip archive on disk whose entries are read one at a time, so that several threads
+/// (each with its own `ZipEntries` and file handle) can decompress the same archive.
+pub struct ZipEntries {
+    archive: ZipArchive<File>,
+    options: ReaderOptions,
+}
+
+impl ZipEntries {
+    pub fn open(path: &Path, options: &ReaderOptions) -> Result<Self, ReaderError> {
+        Ok(ZipEntries {
+            archive: ZipArchive::new(File::open(path)?)?,
+            options: options.clone(),
+        })
+    }
+
+    pub fn entry_count(&self) -> usize {
+        self.archive.len()
+    }
+
+    pub fn entry_name(&self, index: usize) -> &str {
+        self.archive.name_for_index(index).unwrap_or_default()
+    }
+
+    /// Reads the XML files of one entry, like [`iter_xml_contents`] does for the whole archive.
+    pub fn read(&mut self, index: usize) -> XmlIter {
+        read_zip_entry(&mut self.archive, index, self.options.clone())
+    }
+}
+
+// replace read_zip_archive with streaming version
+fn read_zip_archive(path: &Path, options: ReaderOptions) -> Result<ZipXmlIter<File>, ReaderError> {
+    let file = File::open(path)?;
+    let archive = ZipArchive::new(file)?;
+    Ok(ZipXmlIter::new(archive, options))
+}
+
+/// Streams the XML files of a tar archive.
+///
+/// The entries of a `tar::Archive` borrow it, so the archive is read on its own thread,
+/// which hands over one XML file at a time.
+fn read_tar_stream(reader: impl Read + Send + 'static, options: ReaderOptions) -> XmlIter {
+    let (tx, rx) = crossbeam_channel::bounded(0);
+    thread::spawn(move || {
+        let mut archive = tar::Archive::new(reader);
+        let entries = match archive.entries() {
+            Ok(entries) => entries,
+            Err(e) => {
+                let _ = tx.send(Err(e.into()));
+                return;
+            }
+        };
+        for entry in entries {
+            let mut entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    // The rest of a broken tar stream can't be read.
+                    let _ = tx.send(Err(e.into()));
+                    return;
+                }
             };
-            let ext = entry_path
-                .extension()
-                .and_then(|s| s.to_str())
-                .map(|s| s.to_lowercase());
-            match ext.as_deref() {
-                Some("xml") => {
-                    let name = entry_path
-                        .file_name()
-                        .and_then(|s| s.to_str())
-                        .unwrap_or_default()
-                        .to_string();
-                    let mut contents = String::new();
-                    if let Err(e) = entry.read_to_string(&mut contents) {
-                        return Some(Err(ReaderError::Io(e)));
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let entry_path = match entry.path() {
+                Ok(path) => path.into_owned(),
+                Err(e) => {
+                    if tx.send(Err(e.into())).is_err() {
+                        return;
                     }
-                    return Some(Ok(FileData {
-                        file_name: name,
-                        contents,
-                    }));
+                    continue;
                 }
-                Some("zip") if !entry.is_dir() => {
-                    // prepare nested ZIP iterator
-                    match NamedTempFile::new() {
-                        Ok(mut tmp) => {
-                            if let Err(e) = io::copy(&mut entry, tmp.as_file_mut()) {
-                                return Some(Err(ReaderError::Io(e)));
-                            }
-                            if let Err(e) = tmp.as_file_mut().seek(SeekFrom::Start(0)) {
-                                return Some(Err(ReaderError::Io(e)));
-                            }
-                            // clone handle for ZipArchive
-                            match tmp
-                                .as_file()
-                                .try_clone()
-                                .and_then(|mut f| f.seek(SeekFrom::Start(0)).map(|_| f))
-                            {
-                                Ok(cloned_file) => match ZipArchive::new(cloned_file) {
-                                    Ok(nested_arc) => {
-                                        let mut nested_it = ZipXmlIter::new(nested_arc);
-                                        if let Some(item) = nested_it.next() {
-                                            self.nested = Some(Box::new(nested_it));
-                                            return Some(item);
-                                        } else {
-                                            continue;
-                                        }
-                                    }
-                                    Err(e) => return Some(Err(ReaderError::Zip(e))),
-                                },
-                                Err(e) => return Some(Err(ReaderError::Io(e))),
-                            }
-                        }
-                        Err(e) => return Some(Err(ReaderError::Io(e))),
-                    }
+            };
+            let size = entry.size();
+            for item in read_entry(&entry_path, &mut entry, size, options.clone()) {
+                // The receiver was dropped: stop reading.
+                if tx.send(item).is_err() {
+                    return;
                 }
-                _ => continue,
             }
         }
+    });
+    Box::new(rx.into_iter())
+}
+
+/// Reads a zip, tar (optionally gzip-compressed) or XML stream, telling them apart by
+/// their first bytes.
+fn read_stream(reader: impl Read + Send + 'static, options: ReaderOptions) -> XmlIter {
+    let (head, mut reader) = match peek(reader) {
+        Ok(peeked) => peeked,
+        Err(e) => return Box::new(std::iter::once(Err(e.into()))),
+    };
+    if head.starts_with(b"PK\x03\x04") {
+        match read_nested_zip(&mut reader, None, options) {
+            Ok(iter) => iter,
+            Err(e) => Box::new(std::iter::once(Err(e))),
+        }
+    } else if head.starts_with(&[0x1f, 0x8b]) {
+        let (head, reader) = match peek(MultiGzDecoder::new(reader)) {
+            Ok(peeked) => peeked,
+            Err(e) => return Box::new(std::iter::once(Err(e.into()))),
+        };
+        if is_tar(&head) {
+            read_tar_stream(reader, options)
+        } else {
+            Box::new(std::iter::once(read_xml(STDIN_FILE_NAME, reader)))
+        }
+    } else if is_tar(&head) {
+        read_tar_stream(reader, options)
+    } else {
+        Box::new(std::iter::once(read_xml(STDIN_FILE_NAME, reader)))
     }
 }
 
-// replace read_zip_archive with streaming version
-fn read_zip_archive(path: &Path) -> Result<ZipXmlIter<File>, ReaderError> {
-    let file = File::open(path)?;
-    let archive = ZipArchive::new(file)?;
-    Ok(ZipXmlIter::new(archive))
+/// Size of a tar header block, which holds the `ustar` magic.
+const TAR_HEADER_SIZE: u64 = 512;
+
+fn is_tar(head: &[u8]) -> bool {
+    head.get(257..262) == Some(b"ustar")
+}
+
+/// A stream with its first bytes put back in front.
+type Peeked<R> = io::Chain<Cursor<Vec<u8>>, R>;
+
+/// Reads the first bytes of a stream and returns them, along with a reader that still
+/// yields the whole stream.
+fn peek<R: Read>(mut reader: R) -> io::Result<(Vec<u8>, Peeked<R>)> {
+    let mut head = Vec::new();
+    reader
+        .by_ref()
+        .take(TAR_HEADER_SIZE)
+        .read_to_end(&mut head)?;
+    Ok((head.clone(), Cursor::new(head).chain(reader)))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::path::PathBuf;
 
     fn testdata_path() -> PathBuf {
         let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
@@ -198,7 +524,7 @@ mod tests {
     fn test_read_zip_archive_success() {
         let mut path = testdata_path();
         path.push("46505-3411-1.zip");
-        let result = read_zip_archive(&path);
+        let result = read_zip_archive(&path, ReaderOptions::default());
         assert!(result.is_ok());
         let mut iter = result.unwrap();
         let first_item = iter.next();
@@ -214,7 +540,7 @@ mod tests {
     fn test_read_zip_archive_multiple_xml() {
         let mut path = testdata_path();
         path.push("46505-3411-2025.zip");
-        let result = read_zip_archive(&path);
+        let result = read_zip_archive(&path, ReaderOptions::default());
         assert!(result.is_ok());
         let iter = result.unwrap();
         let items = iter.filter_map(|r| r.ok()).collect::<Vec<_>>();
@@ -233,7 +559,7 @@ mod tests {
     fn test_read_zip_archive_not_found() {
         let mut path = testdata_path();
         path.push("non_existent_archive.zip");
-        let result = read_zip_archive(&path);
+        let result = read_zip_archive(&path, ReaderOptions::default());
         assert!(result.is_err());
         match result.err().unwrap() {
             ReaderError::Io(e) => assert_eq!(e.kind(), io::ErrorKind::NotFound),
@@ -245,7 +571,7 @@ mod tests {
     fn test_read_zip_archive_invalid_zip() {
         let mut path = testdata_path();
         path.push("46505-3411-56.xml");
-        let result = read_zip_archive(&path);
+        let result = read_zip_archive(&path, ReaderOptions::default());
         assert!(result.is_err());
         match result.err().unwrap() {
             ReaderError::Zip(_) => {}
@@ -263,7 +589,10 @@ mod tests {
             base_path.join("non_existent_file.xml"),
         ];
 
-        let results: Vec<_> = paths.iter().flat_map(|p| iter_xml_contents(p)).collect();
+        let results: Vec<_> = paths
+            .iter()
+            .flat_map(|p| iter_xml_contents(p, &ReaderOptions::default()))
+            .collect();
 
         assert!(results.len() >= 2);
         assert!(results[0].is_ok());
@@ -293,7 +622,10 @@ mod tests {
     fn test_iter_xml_contents_only_zip() {
         let base_path = testdata_path();
         let paths = [base_path.join("46505-3411-1.zip")];
-        let results: Vec<_> = paths.iter().flat_map(|p| iter_xml_contents(p)).collect();
+        let results: Vec<_> = paths
+            .iter()
+            .flat_map(|p| iter_xml_contents(p, &ReaderOptions::default()))
+            .collect();
         assert!(!results.is_empty());
         assert!(results.iter().all(|r| r.is_ok()));
     }
@@ -302,7 +634,10 @@ mod tests {
     fn test_iter_xml_contents_only_xml() {
         let base_path = testdata_path();
         let paths = [base_path.join("46505-3411-56.xml")];
-        let results: Vec<_> = paths.iter().flat_map(|p| iter_xml_contents(p)).collect();
+        let results: Vec<_> = paths
+            .iter()
+            .flat_map(|p| iter_xml_contents(p, &ReaderOptions::default()))
+            .collect();
         assert_eq!(results.len(), 1);
         assert!(results[0].is_ok());
     }
@@ -310,7 +645,10 @@ mod tests {
     #[test]
     fn test_iter_xml_contents_empty_input() {
         let paths: Vec<PathBuf> = vec![];
-        let results: Vec<_> = paths.iter().flat_map(|p| iter_xml_contents(p)).collect();
+        let results: Vec<_> = paths
+            .iter()
+            .flat_map(|p| iter_xml_contents(p, &ReaderOptions::default()))
+            .collect();
         assert!(results.is_empty());
     }
 
@@ -322,7 +660,10 @@ mod tests {
             base_path.join("..").join("Cargo.toml"),
         ];
         if paths.iter().all(|p| p.exists()) {
-            let results: Vec<_> = paths.iter().flat_map(|p| iter_xml_contents(p)).collect();
+            let results: Vec<_> = paths
+                .iter()
+                .flat_map(|p| iter_xml_contents(p, &ReaderOptions::default()))
+                .collect();
             assert!(results.is_empty(), "Should ignore non-XML/ZIP files");
         } else {
             println!(
@@ -330,4 +671,161 @@ mod tests {
             );
         }
     }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        std::io::Write::write_all(&mut encoder, data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// A tar archive with a nested zip, an XML file, a gzip-compressed XML file and a
+    /// file that is skipped.
+    fn sample_tar() -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut append = |path: &str, data: &[u8]| {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, path, data).unwrap();
+        };
+        append(
+            "a/46505-3411-1.zip",
+            &std::fs::read(testdata_path().join("46505-3411-1.zip")).unwrap(),
+        );
+        append("b.xml", b"<b/>");
+        append("c.xml.gz", &gzip(b"<c/>"));
+        append("readme.txt", b"");
+        builder.into_inner().unwrap()
+    }
+
+    fn file_names(iter: XmlIter) -> Vec<String> {
+        iter.map(|r| r.unwrap().file_name).collect()
+    }
+
+    #[test]
+    fn test_iter_xml_contents_tar() {
+        let dir = tempfile::tempdir().unwrap();
+        let tar_path = dir.path().join("data.tar");
+        let tar_gz_path = dir.path().join("data.tar.gz");
+        let xml_gz_path = dir.path().join("d.xml.gz");
+        std::fs::write(&tar_path, sample_tar()).unwrap();
+        std::fs::write(&tar_gz_path, gzip(&sample_tar())).unwrap();
+        std::fs::write(&xml_gz_path, gzip(b"<d/>")).unwrap();
+
+        let expected = vec!["46505-3411-1.xml", "b.xml", "c.xml"];
+        for path in [&tar_path, &tar_gz_path] {
+            assert_eq!(
+                file_names(iter_xml_contents(path, &ReaderOptions::default())),
+                expected
+            );
+        }
+        let xml: Vec<_> = iter_xml_contents(&xml_gz_path, &ReaderOptions::default())
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(xml[0].file_name, "d.xml");
+        assert_eq!(xml[0].contents, "<d/>");
+    }
+
+    #[test]
+    fn test_nested_zip_temp_file() {
+        // Nested zips over the limit (and zip streams of unknown size) go through a
+        // temporary file and give the same result.
+        let options = ReaderOptions {
+            in_memory_zip_limit: 100,
+            memory: None,
+        };
+        assert_eq!(
+            file_names(read_tar_stream(Cursor::new(sample_tar()), options.clone())),
+            vec!["46505-3411-1.xml", "b.xml", "c.xml"]
+        );
+        let zip = std::fs::read(testdata_path().join("46505-3411-1.zip")).unwrap();
+        let xml: Vec<_> = read_stream(Cursor::new(zip), options)
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(xml[0].file_name, "46505-3411-1.xml");
+    }
+
+    #[test]
+    fn test_read_stream() {
+        let zip = std::fs::read(testdata_path().join("46505-3411-1.zip")).unwrap();
+        assert_eq!(
+            file_names(read_stream(Cursor::new(zip), ReaderOptions::default())),
+            vec!["46505-3411-1.xml"]
+        );
+        assert_eq!(
+            file_names(read_stream(
+                Cursor::new(gzip(&sample_tar())),
+                ReaderOptions::default()
+            )),
+            vec!["46505-3411-1.xml", "b.xml", "c.xml"]
+        );
+        let xml: Vec<_> = read_stream(Cursor::new(gzip(b"<e/>")), ReaderOptions::default())
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(xml[0].file_name, STDIN_FILE_NAME);
+        assert_eq!(xml[0].contents, "<e/>");
+        assert_eq!(
+            file_names(read_stream(
+                Cursor::new(b"<f/>".to_vec()),
+                ReaderOptions::default()
+            )),
+            vec![STDIN_FILE_NAME]
+        );
+    }
+
+    #[test]
+    fn test_zip_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let zip_path = dir.path().join("data.zip");
+        let mut writer = zip::ZipWriter::new(File::create(&zip_path).unwrap());
+        let options = zip::write::SimpleFileOptions::default();
+        writer.start_file("a.xml", options).unwrap();
+        writer.write_all(b"<a/>").unwrap();
+        writer.add_directory("b/", options).unwrap();
+        writer.start_file("b/nested.zip", options).unwrap();
+        writer
+            .write_all(&std::fs::read(testdata_path().join("46505-3411-1.zip")).unwrap())
+            .unwrap();
+        writer.finish().unwrap();
+
+        let mut zip = ZipEntries::open(&zip_path, &ReaderOptions::default()).unwrap();
+        let names: Vec<_> = (0..zip.entry_count())
+            .flat_map(|index| file_names(zip.read(index)))
+            .collect();
+        assert_eq!(names, vec!["a.xml", "46505-3411-1.xml"]);
+        assert_eq!(
+            file_names(iter_xml_contents(&zip_path, &ReaderOptions::default())),
+            names
+        );
+    }
+
+    #[test]
+    fn test_map_xml_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let tar_path = dir.path().join("data.tar");
+        let xml_path = dir.path().join("d.xml");
+        std::fs::write(&tar_path, sample_tar()).unwrap();
+        std::fs::write(&xml_path, "<d/>").unwrap();
+        let missing = dir.path().join("missing.xml");
+
+        let paths = [tar_path, missing.clone(), xml_path];
+        let results = map_xml_files(&paths, &ReaderOptions::default(), 3, |file_data| {
+            file_data.file_name
+        });
+        let results: Vec<_> = results
+            .into_iter()
+            .map(|result| result.map_err(|(path, _)| path))
+            .collect();
+        assert_eq!(
+            results,
+            vec![
+                Ok("46505-3411-1.xml".to_string()),
+                Ok("b.xml".to_string()),
+                Ok("c.xml".to_string()),
+                Err(missing),
+                Ok("d.xml".to_string()),
+            ]
+        );
+    }
 }
@@ -0,0 +1,327 @@
+use crate::error::Error;
+use crate::parse::SkipReason;
+use crate::stats::StageReport;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Machine-readable summary of a conversion run.
+#[derive(Debug, Default, Serialize)]
+pub struct RunReport {
+    pub output: PathBuf,
+    /// Whether the output file was created (false if no features were written).
+    pub output_written: bool,
+    /// Whether the run was stopped early because of an error in strict mode.
+    pub aborted: bool,
+    pub elapsed_ms: u64,
+    /// Utilisation of the pipeline stages.
+    pub stages: Vec<StageReport>,
+    pub inputs: Vec<InputReport>,
+    /// The error that stopped the run.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InputReport {
+    pub path: PathBuf,
+    pub status: InputStatus,
+    /// Whether the results were read from the cache (`--cache-dir`) instead of parsed.
+    pub cached: bool,
+    /// Errors encountered while reading the input (e.g. a corrupt zip entry).
+    pub errors: Vec<ErrorReport>,
+    /// Time spent reading / unzipping this input.
+    pub elapsed_ms: u64,
+    pub files: Vec<FileReport>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InputStatus {
+    Pending,
+    Ok,
+    Error,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FileReport {
+    pub file_name: String,
+    pub status: FileStatus,
+    pub feature_count: usize,
+    pub skip_reason: Option<SkipReason>,
+    /// Number of 地区外 / 別図 features that were filtered out.
+    pub filtered_chikugai: usize,
+    pub error: Option<ErrorReport>,
+    /// Elements that were dropped in lenient mode.
+    pub warnings: Vec<ErrorReport>,
+    /// Features dropped because an input with a higher priority had them too (`--dedup`).
+    pub duplicates_dropped: usize,
+    pub parse_ms: u64,
+    pub write_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileStatus {
+    /// Parsed and waiting to be written.
+    Parsed,
+    Written,
+    Skipped,
+    ParseError,
+    WriteError,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorReport {
+    /// Error variant name, e.g. `MissingElement` or `Zip`.
+    pub kind: &'static str,
+    pub message: String,
+    /// Line / column in the XML file, for parse errors.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub column: Option<u32>,
+    /// Enclosing element, e.g. `筆 H000000001`, for parse errors.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub element: Option<String>,
+}
+
+impl ErrorReport {
+    pub fn new(kind: &'static str, error: &impl std::fmt::Display) -> Self {
+        ErrorReport {
+            kind,
+            message: error.to_string(),
+            line: None,
+            column: None,
+            element: None,
+        }
+    }
+}
+
+impl From<&Error> for ErrorReport {
+    fn from(error: &Error) -> Self {
+        let mut report = ErrorReport::new(error.kind(), error);
+        if let Some(location) = error.location() {
+            report.line = location.position.map(|(line, _)| line);
+            report.column = location.position.map(|(_, col)| col);
+            report.element = location.element.clone();
+        }
+        report
+    }
+}
+
+impl RunReport {
+    pub fn new(output: &Path, src_files: &[PathBuf]) -> Self {
+        RunReport {
+            output: output.to_path_buf(),
+            output_written: false,
+            aborted: false,
+            elapsed_ms: 0,
+            stages: vec![],
+            inputs: src_files
+                .iter()
+                .map(|path| InputReport {
+                    path: path.clone(),
+                    status: InputStatus::Pending,
+                    cached: false,
+                    errors: vec![],
+                    elapsed_ms: 0,
+                    files: vec![],
+                })
+                .collect(),
+            error: None,
+        }
+    }
+
+    /// Total number of XML files that were read from the inputs.
+    pub fn xml_file_count(&self) -> usize {
+        self.inputs.iter().map(|i| i.files.len()).sum()
+    }
+
+    /// Number of lenient-mode warnings (dropped elements) across all files.
+    pub fn warning_count(&self) -> usize {
+        self.inputs
+            .iter()
+            .flat_map(|i| &i.files)
+            .map(|f| f.warnings.len())
+            .sum()
+    }
+
+    /// Number of features dropped as duplicates (`--dedup`).
+    pub fn duplicate_count(&self) -> usize {
+        self.inputs
+            .iter()
+            .flat_map(|i| &i.files)
+            .map(|f| f.duplicates_dropped)
+            .sum()
+    }
+
+    /// Number of failures (input read errors and XML parse/write errors), by error kind.
+    pub fn failure_counts(&self) -> BTreeMap<&'static str, usize> {
+        let mut counts = BTreeMap::new();
+        for input in &self.inputs {
+            for error in &input.errors {
+                *counts.entry(error.kind).or_insert(0) += 1;
+            }
+            for error in input.files.iter().filter_map(|f| f.error.as_ref()) {
+                *counts.entry(error.kind).or_insert(0) += 1;
+            }
+        }
+        counts
+    }
+
+    pub fn write_json(&self, path: &Path) -> std::io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writer.write_all(b"\n")?;
+        writer.flush()
+    }
+}
+
+/// Thread-safe handle used by the pipeline stages to fill in a [`RunReport`].
+#[derive(Clone)]
+pub struct ReportCollector(Arc<Mutex<RunReport>>);
+
+impl ReportCollector {
+    pub fn new(report: RunReport) -> Self {
+        ReportCollector(Arc::new(Mutex::new(report)))
+    }
+
+    pub fn input_error(&self, input: usize, error: ErrorReport) {
+        let mut report = self.0.lock().unwrap();
+        report.inputs[input].errors.push(error);
+    }
+
+    pub fn input_done(&self, input: usize, elapsed: Duration) {
+        let mut report = self.0.lock().unwrap();
+        let input = &mut report.inputs[input];
+        input.elapsed_ms = elapsed.as_millis() as u64;
+        input.status = if input.errors.is_empty() {
+            InputStatus::Ok
+        } else {
+            InputStatus::Error
+        };
+    }
+
+    /// Marks an input whose results were loaded from the cache.
+    pub fn input_cached(&self, input: usize, elapsed: Duration) {
+        let mut report = self.0.lock().unwrap();
+        let input = &mut report.inputs[input];
+        input.cached = true;
+        input.elapsed_ms = elapsed.as_millis() as u64;
+        input.status = InputStatus::Ok;
+    }
+
+    /// Adds a file entry to an input and returns its index within that input.
+    pub fn add_file(&self, input: usize, file: FileReport) -> usize {
+        let mut report = self.0.lock().unwrap();
+        let files = &mut report.inputs[input].files;
+        files.push(file);
+        files.len() - 1
+    }
+
+    pub fn file_written(&self, input: usize, file: usize, elapsed: Duration) {
+        let mut report = self.0.lock().unwrap();
+        let file = &mut report.inputs[input].files[file];
+        if file.status == FileStatus::Parsed {
+            file.status = FileStatus::Written;
+        }
+        file.write_ms = elapsed.as_millis() as u64;
+    }
+
+    pub fn duplicates_dropped(&self, input: usize, file: usize, count: usize) {
+        let mut report = self.0.lock().unwrap();
+        report.inputs[input].files[file].duplicates_dropped = count;
+    }
+
+    pub fn file_write_error(&self, input: usize, file: usize, error: ErrorReport) {
+        let mut report = self.0.lock().unwrap();
+        let file = &mut report.inputs[input].files[file];
+        file.status = FileStatus::WriteError;
+        file.error = Some(error);
+    }
+
+    /// Consumes the collector and returns the final report.
+    /// Must only be called once all pipeline threads have finished.
+    pub fn finish(self, output_written: bool, elapsed: Duration) -> RunReport {
+        let mut report = Arc::try_unwrap(self.0)
+            .map(|m| m.into_inner().unwrap())
+            .unwrap_or_else(|arc| std::mem::take(&mut *arc.lock().unwrap()));
+        report.output_written = output_written;
+        report.elapsed_ms = elapsed.as_millis() as u64;
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_serialization() {
+        let collector = ReportCollector::new(RunReport::new(
+            Path::new("out.fgb"),
+            &[PathBuf::from("a.zip")],
+        ));
+        let file = collector.add_file(
+            0,
+            FileReport {
+                file_name: "a.xml".to_string(),
+                status: FileStatus::Parsed,
+                feature_count: 3,
+                skip_reason: None,
+                filtered_chikugai: 1,
+                error: None,
+                warnings: vec![],
+                duplicates_dropped: 0,
+                parse_ms: 5,
+                write_ms: 0,
+            },
+        );
+        collector.file_written(0, file, Duration::from_millis(2));
+        collector.input_done(0, Duration::from_millis(10));
+        let report = collector.finish(true, Duration::from_millis(20));
+        assert_eq!(report.xml_file_count(), 1);
+        assert!(report.failure_counts().is_empty());
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["inputs"][0]["status"], "ok");
+        assert_eq!(json["inputs"][0]["files"][0]["status"], "written");
+        assert_eq!(json["inputs"][0]["files"][0]["feature_count"], 3);
+        assert_eq!(json["inputs"][0]["files"][0]["filtered_chikugai"], 1);
+    }
+
+    #[test]
+    fn test_failure_counts() {
+        let collector = ReportCollector::new(RunReport::new(
+            Path::new("out.fgb"),
+            &[PathBuf::from("a.zip"), PathBuf::from("b.zip")],
+        ));
+        collector.input_error(0, ErrorReport::new("Zip", &"bad zip"));
+        for name in ["a.xml", "b.xml"] {
+            collector.add_file(
+                1,
+                FileReport {
+                    file_name: name.to_string(),
+                    status: FileStatus::ParseError,
+                    feature_count: 0,
+                    skip_reason: None,
+                    filtered_chikugai: 0,
+                    error: Some(ErrorReport::new("MissingElement", &"missing")),
+                    warnings: vec![],
+                    duplicates_dropped: 0,
+                    parse_ms: 0,
+                    write_ms: 0,
+                },
+            );
+        }
+        let report = collector.finish(false, Duration::ZERO);
+        let counts = report.failure_counts();
+        assert_eq!(counts.get("Zip"), Some(&1));
+        assert_eq!(counts.get("MissingElement"), Some(&2));
+    }
+}
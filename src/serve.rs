@@ -0,0 +1,366 @@
+//! A local web server for checking a converted FlatGeobuf file in the browser.
+//!
+//! Vector tiles are cut on request from the features the packed R-tree of the file
+//! finds for the tile, so nothing has to be prepared first. Bounding box queries
+//! return GeoJSON, and `/` is a small map viewer using the tiles.
+
+use crate::dataset::feature_from_fgb;
+use crate::id;
+use crate::mvt::LayerBuilder;
+use crate::query::{self, Filter};
+use crate::tiles::{self, LAYER_NAME};
+use anyhow::{Context, Result, anyhow, bail};
+use flatgeobuf::{FallibleStreamingIterator, FgbReader};
+use log::{info, warn};
+use serde_json::{Value, json};
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::thread;
+use tiny_http::{Header, Response, Server};
+
+pub const DEFAULT_PORT: u16 = 8080;
+/// Most features a bounding box query returns.
+pub const DEFAULT_FEATURE_LIMIT: usize = 5000;
+const VIEWER_HTML: &str = include_str!("viewer.html");
+
+#[derive(Debug, Clone)]
+pub struct ServeOptions {
+    pub host: String,
+    pub port: u16,
+    /// Tiles below this zoom level are served empty; they would hold too many parcels.
+    pub min_zoom: u8,
+    /// Tiles at this zoom level and above are not simplified. The viewer overzooms
+    /// them instead of requesting deeper tiles.
+    pub max_zoom: u8,
+    pub feature_limit: usize,
+    pub threads: usize,
+}
+
+/// The FlatGeobuf file being served.
+pub struct ServedFile {
+    path: PathBuf,
+    name: String,
+    bounds: Option<[f64; 4]>,
+    feature_count: u64,
+    options: ServeOptions,
+}
+
+/// A response, before it is turned into HTTP.
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Html(&'static str),
+    Json(Value),
+    Tile(Vec<u8>),
+    /// An empty tile.
+    NoContent,
+    NotFound,
+    BadRequest(String),
+    Failed(String),
+}
+
+impl ServedFile {
+    pub fn open(path: &Path, options: ServeOptions) -> Result<Self> {
+        let reader = FgbReader::open(BufReader::new(File::open(path)?))?;
+        let header = reader.header();
+        if header.index_node_size() == 0 {
+            bail!(
+                "{} has no spatial index; convert it again without --disable-fgb-index",
+                path.display()
+            );
+        }
+        let bounds = header
+            .envelope()
+            .filter(|envelope| envelope.len() >= 4)
+            .map(|envelope| {
+                [
+                    envelope.get(0),
+                    envelope.get(1),
+                    envelope.get(2),
+                    envelope.get(3),
+                ]
+            });
+        Ok(ServedFile {
+            path: path.to_path_buf(),
+            name: path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            bounds,
+            feature_count: header.features_count(),
+            options,
+        })
+    }
+
+    /// Serves requests until the process is stopped.
+    pub fn serve(&self) -> Result<()> {
+        let address = format!("{}:{}", self.options.host, self.options.port);
+        let server = Server::http(&address)
+            .map_err(|e| anyhow!("Failed to listen on {}: {}", address, e))?;
+        println!("Serving {} at http://{}/", self.path.display(), address);
+        println!("Press Ctrl+C to stop.");
+        thread::scope(|s| {
+            for _ in 0..self.options.threads.max(1) {
+                s.spawn(|| {
+                    while let Ok(request) = server.recv() {
+                        let url = request.url().to_string();
+                        let reply = self.route(&url).unwrap_or_else(|e| {
+                            warn!("Failed to serve {}: {:#}", url, e);
+                            eprintln!("Failed to serve {}: {:#}", url, e);
+                            Reply::Failed(format!("{:#}", e))
+                        });
+                        info!("{} {}", request.method(), url);
+                        if let Err(e) = request.respond(response(reply)) {
+                            warn!("Failed to send response for {}: {}", url, e);
+                        }
+                    }
+                });
+            }
+        });
+        Ok(())
+    }
+
+    fn route(&self, url: &str) -> Result<Reply> {
+        let (path, query) = url.split_once('?').unwrap_or((url, ""));
+        match path {
+            "/" | "/index.html" => Ok(Reply::Html(VIEWER_HTML)),
+            "/metadata.json" => Ok(Reply::Json(self.metadata())),
+            "/features" | "/features.geojson" => self.features(query),
+            _ => match parse_tile_path(path) {
+                Some((zoom, x, y)) => self.tile(zoom, x, y),
+                None => Ok(Reply::NotFound),
+            },
+        }
+    }
+
+    fn metadata(&self) -> Value {
+        json!({
+            "name": self.name,
+            "bounds": self.bounds,
+            "features": self.feature_count,
+            "layer": LAYER_NAME,
+            "minzoom": self.options.min_zoom,
+            "maxzoom": self.options.max_zoom,
+        })
+    }
+
+    fn tile(&self, zoom: u8, x: u32, y: u32) -> Result<Reply> {
+        if zoom < self.options.min_zoom {
+            return Ok(Reply::NoContent);
+        }
+        let [min_lon, min_lat, max_lon, max_lat] = tiles::tile_bounds(zoom, x, y);
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        let mut iter =
+            FgbReader::open(&mut reader)?.select_bbox(min_lon, min_lat, max_lon, max_lat)?;
+
+        let names = tiles::attribute_names();
+        let attributes: Vec<usize> = (0..names.len()).collect();
+        let mut layer = LayerBuilder::new(names.len());
+        let mut empty = true;
+        while let Some(feature) = iter.next()? {
+            let (common, feature) = feature_from_fgb(feature)?;
+            let projected = tiles::project(&feature.geometry);
+            let geometry = if zoom < self.options.max_zoom {
+                tiles::simplify(&projected, zoom, tiles::DEFAULT_SIMPLIFY)
+            } else {
+                Cow::Borrowed(&projected)
+            };
+            let polygons = tiles::tile_polygons(&geometry, zoom, x, y);
+            if polygons.is_empty() {
+                continue;
+            }
+            let global_id = id::global_id(&common, &feature.props);
+            layer.add_polygons(
+                id::fid(&common, &feature.props) as u64,
+                &polygons,
+                &tiles::feature_attributes(&attributes, &global_id, &common, &feature.props),
+            );
+            empty = false;
+        }
+        Ok(if empty {
+            Reply::NoContent
+        } else {
+            Reply::Tile(layer.finish(LAYER_NAME, &names))
+        })
+    }
+
+    /// `bbox=min_lon,min_lat,max_lon,max_lat`, optionally with `limit=N`.
+    fn features(&self, query: &str) -> Result<Reply> {
+        let mut bbox = None;
+        let mut limit = self.options.feature_limit;
+        for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+            match key.as_ref() {
+                "bbox" => match query::parse_bbox(&value) {
+                    Ok(value) => bbox = Some(value),
+                    Err(e) => {
+                        return Ok(Reply::BadRequest(format!("Invalid bbox {}: {}", value, e)));
+                    }
+                },
+                "limit" => match value.parse::<usize>() {
+                    Ok(n) => limit = n.min(self.options.feature_limit),
+                    Err(_) => return Ok(Reply::BadRequest(format!("Invalid limit: {}", value))),
+                },
+                _ => {}
+            }
+        }
+        if bbox.is_none() {
+            return Ok(Reply::BadRequest(
+                "bbox=min_lon,min_lat,max_lon,max_lat is required".to_string(),
+            ));
+        }
+
+        let filter = Filter {
+            bbox,
+            ..Default::default()
+        };
+        let columns = query::columns(&self.path)?;
+        let mut features = Vec::new();
+        let truncated = query::find_features(&self.path, &filter, Some(limit), |found| {
+            features.push(query::feature_json(&columns, &found));
+            Ok(())
+        })?;
+        Ok(Reply::Json(json!({
+            "type": "FeatureCollection",
+            "features": features,
+            "truncated": truncated,
+        })))
+    }
+}
+
+fn response(reply: Reply) -> Response<std::io::Cursor<Vec<u8>>> {
+    let (status, content_type, body) = match reply {
+        Reply::Html(html) => (200, "text/html; charset=utf-8", html.as_bytes().to_vec()),
+        Reply::Json(value) => (200, "application/json", value.to_string().into_bytes()),
+        Reply::Tile(tile) => (200, "application/vnd.mapbox-vector-tile", tile),
+        Reply::NoContent => (204, "application/vnd.mapbox-vector-tile", Vec::new()),
+        Reply::NotFound => (404, "text/plain; charset=utf-8", b"Not found".to_vec()),
+        Reply::BadRequest(message) => (400, "text/plain; charset=utf-8", message.into_bytes()),
+        Reply::Failed(message) => (500, "text/plain; charset=utf-8", message.into_bytes()),
+    };
+    let header = |name: &str, value: &str| {
+        Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("valid header")
+    };
+    Response::from_data(body)
+        .with_status_code(status)
+        .with_header(header("Content-Type", content_type))
+        // Let other local viewers use the tiles as well.
+        .with_header(header("Access-Control-Allow-Origin", "*"))
+}
+
+/// `/tiles/{z}/{x}/{y}.mvt` (or `.pbf`).
+fn parse_tile_path(path: &str) -> Option<(u8, u32, u32)> {
+    let rest = path.strip_prefix("/tiles/")?;
+    let rest = rest
+        .strip_suffix(".mvt")
+        .or_else(|| rest.strip_suffix(".pbf"))?;
+    let mut parts = rest.split('/');
+    let zoom: u8 = parts.next()?.parse().ok()?;
+    let x: u32 = parts.next()?.parse().ok()?;
+    let y: u32 = parts.next()?.parse().ok()?;
+    let valid =
+        parts.next().is_none() && zoom <= tiles::MAX_ZOOM && x >> zoom == 0 && y >> zoom == 0;
+    valid.then_some((zoom, x, y))
+}
+
+/// Opens `path` and serves it until the process is stopped.
+pub fn serve(path: &Path, options: ServeOptions) -> Result<()> {
+    ServedFile::open(path, options)
+        .with_context(|| format!("Failed to open {}", path.display()))?
+        .serve()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::FeatureProperties;
+    use crate::query::tests::{sample_fgb, sample_parsed, write_parsed};
+
+    fn options() -> ServeOptions {
+        ServeOptions {
+            host: "127.0.0.1".to_string(),
+            port: DEFAULT_PORT,
+            min_zoom: 14,
+            max_zoom: 16,
+            feature_limit: 10,
+            threads: 1,
+        }
+    }
+
+    #[test]
+    fn test_parse_tile_path() {
+        assert_eq!(
+            parse_tile_path("/tiles/14/14131/6697.mvt"),
+            Some((14, 14131, 6697))
+        );
+        assert_eq!(parse_tile_path("/tiles/1/1/0.pbf"), Some((1, 1, 0)));
+        assert_eq!(parse_tile_path("/tiles/1/2/0.mvt"), None);
+        assert_eq!(parse_tile_path("/tiles/1/0.mvt"), None);
+        assert_eq!(parse_tile_path("/tiles/a/0/0.mvt"), None);
+    }
+
+    #[test]
+    fn test_route() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("out.fgb");
+        sample_fgb(&path)?;
+        let served = ServedFile::open(&path, options())?;
+
+        assert_eq!(served.route("/")?, Reply::Html(VIEWER_HTML));
+        assert_eq!(served.route("/nothing")?, Reply::NotFound);
+        let Reply::Json(metadata) = served.route("/metadata.json")? else {
+            panic!("expected JSON");
+        };
+        assert_eq!(metadata["features"], 2);
+
+        // The tile the parcel is in, an empty tile next to it and a tile below --min-zoom.
+        assert!(matches!(
+            served.route("/tiles/16/56524/26720.mvt")?,
+            Reply::Tile(_)
+        ));
+        assert_eq!(served.route("/tiles/16/56530/26720.mvt")?, Reply::NoContent);
+        assert_eq!(served.route("/tiles/10/883/417.mvt")?, Reply::NoContent);
+
+        let Reply::Json(collection) = served.route("/features?bbox=130.4,31.4,130.6,31.6")? else {
+            panic!("expected JSON");
+        };
+        let mut chiban: Vec<_> = collection["features"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|feature| feature["properties"]["地番"].clone())
+            .collect();
+        chiban.sort_by_key(Value::to_string);
+        assert_eq!(chiban, ["1", "2"]);
+        assert_eq!(
+            collection["features"][0]["geometry"]["type"],
+            "MultiPolygon"
+        );
+        // Form-encoded parameters.
+        let Reply::Json(collection) =
+            served.route("/features?bbox=130%2E5015%2C31.4,+130.6%2c31.6&limit=1")?
+        else {
+            panic!("expected JSON");
+        };
+        assert_eq!(collection["features"][0]["properties"]["地番"], "2");
+        assert_eq!(collection["truncated"], false);
+        let Reply::Json(collection) = served.route("/features?bbox=131,32,131.1,32.1")? else {
+            panic!("expected JSON");
+        };
+        assert_eq!(collection["features"].as_array().map(Vec::len), Some(0));
+        let Reply::BadRequest(message) = served.route("/features?bbox=1,2")? else {
+            panic!("expected a bad request");
+        };
+        assert_eq!(
+            message,
+            "Invalid bbox 1,2: expected min_lon,min_lat,max_lon,max_lat"
+        );
+
+        // Files written without an index can't be served.
+        let unindexed = dir.path().join("unindexed.fgb");
+        let parsed = sample_parsed("屋久島町", vec![(130.5, FeatureProperties::default())]);
+        write_parsed(&unindexed, parsed, false)?;
+        assert!(ServedFile::open(&unindexed, options()).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,150 @@
+//! Per-stage utilisation of the conversion pipeline, to help tune the thread counts
+//! and channel capacities.
+//!
+//! Every stage records how long its threads ran, how long they waited for work from
+//! the previous stage and how long they waited to hand results on (a full channel, the
+//! reorder permits of `--deterministic` or the `--max-memory` budget). The rest is
+//! time spent working.
+
+use crossbeam_channel::{Receiver, RecvError, RecvTimeoutError};
+use serde::Serialize;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StageReport {
+    pub stage: &'static str,
+    pub threads: usize,
+    /// Time the threads of the stage ran, summed over all threads.
+    pub thread_ms: u64,
+    /// Time spent waiting for work from the previous stage.
+    pub input_wait_ms: u64,
+    /// Time spent waiting for the next stage to take results.
+    pub output_wait_ms: u64,
+}
+
+impl StageReport {
+    pub fn busy_ms(&self) -> u64 {
+        self.thread_ms
+            .saturating_sub(self.input_wait_ms + self.output_wait_ms)
+    }
+
+    /// `ms` as a percentage of the thread time.
+    pub fn percent(&self, ms: u64) -> f64 {
+        if self.thread_ms == 0 {
+            return 0.0;
+        }
+        ms as f64 * 100.0 / self.thread_ms as f64
+    }
+}
+
+#[derive(Clone)]
+pub struct StageStats(Arc<StageCounters>);
+
+struct StageCounters {
+    stage: &'static str,
+    threads: usize,
+    thread_ns: AtomicU64,
+    input_wait_ns: AtomicU64,
+    output_wait_ns: AtomicU64,
+}
+
+impl StageStats {
+    pub fn new(stage: &'static str, threads: usize) -> Self {
+        StageStats(Arc::new(StageCounters {
+            stage,
+            threads,
+            thread_ns: AtomicU64::new(0),
+            input_wait_ns: AtomicU64::new(0),
+            output_wait_ns: AtomicU64::new(0),
+        }))
+    }
+
+    /// Starts timing a thread of the stage. The time is recorded when the timer is dropped.
+    pub fn thread(&self) -> ThreadTimer {
+        ThreadTimer {
+            stats: self.clone(),
+            start: Instant::now(),
+        }
+    }
+
+    pub fn recv<T>(&self, rx: &Receiver<T>) -> Result<T, RecvError> {
+        self.waiting_for_input(|| rx.recv())
+    }
+
+    pub fn recv_timeout<T>(
+        &self,
+        rx: &Receiver<T>,
+        timeout: Duration,
+    ) -> Result<T, RecvTimeoutError> {
+        self.waiting_for_input(|| rx.recv_timeout(timeout))
+    }
+
+    pub fn waiting_for_input<R>(&self, f: impl FnOnce() -> R) -> R {
+        timed(&self.0.input_wait_ns, f)
+    }
+
+    pub fn waiting_for_output<R>(&self, f: impl FnOnce() -> R) -> R {
+        timed(&self.0.output_wait_ns, f)
+    }
+
+    pub fn report(&self) -> StageReport {
+        let ms = |ns: &AtomicU64| ns.load(Ordering::Relaxed) / 1_000_000;
+        StageReport {
+            stage: self.0.stage,
+            threads: self.0.threads,
+            thread_ms: ms(&self.0.thread_ns),
+            input_wait_ms: ms(&self.0.input_wait_ns),
+            output_wait_ms: ms(&self.0.output_wait_ns),
+        }
+    }
+}
+
+pub struct ThreadTimer {
+    stats: StageStats,
+    start: Instant,
+}
+
+impl Drop for ThreadTimer {
+    fn drop(&mut self) {
+        add_elapsed(&self.stats.0.thread_ns, self.start);
+    }
+}
+
+fn timed<R>(counter: &AtomicU64, f: impl FnOnce() -> R) -> R {
+    let start = Instant::now();
+    let result = f();
+    add_elapsed(counter, start);
+    result
+}
+
+fn add_elapsed(counter: &AtomicU64, start: Instant) {
+    counter.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stage_stats() {
+        let stats = StageStats::new("parse", 1);
+        {
+            let _timer = stats.thread();
+            let (tx, rx) = crossbeam_channel::bounded(1);
+            let sender = std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(30));
+                tx.send(1).unwrap();
+            });
+            assert_eq!(stats.recv(&rx), Ok(1));
+            sender.join().unwrap();
+            stats.waiting_for_output(|| std::thread::sleep(Duration::from_millis(10)));
+        }
+        let report = stats.report();
+        assert!(report.input_wait_ms >= 30);
+        assert!(report.output_wait_ms >= 10);
+        assert!(report.thread_ms >= report.input_wait_ms + report.output_wait_ms);
+        assert!(report.percent(report.input_wait_ms) > 50.0);
+    }
+}
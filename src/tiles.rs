@@ -0,0 +1,606 @@
+//! Vector tile output: the parsed features cut into Mapbox Vector Tiles for a range of
+//! zoom levels and stored in a PMTiles or MBTiles archive.
+//!
+//! Every feature is projected to Web Mercator once, then simplified, clipped and
+//! encoded into each tile it touches as it is added. The encoded tiles are kept in
+//! memory until the archive is written by [`TileWriter::flush`].
+
+use crate::id;
+use crate::mvt::{EXTENT, LayerBuilder, Ring, ring_area};
+use crate::parse::{CommonProperties, FeatureProperties, ParsedXML};
+use crate::pmtiles::{self, ArchiveInfo, gzip, tile_id};
+use crate::writer::{PROPERTY_COLUMNS, property_values, remove_output};
+use anyhow::{Result, anyhow, bail};
+use geo::{BoundingRect, MapCoords, Simplify};
+use geo_types::{Coord, LineString, MultiPolygon};
+use log::{info, warn};
+use rusqlite::{Connection, params};
+use serde_json::json;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::f64::consts::PI;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Name of the layer in the tiles.
+pub const LAYER_NAME: &str = "fude";
+pub const DEFAULT_MIN_ZOOM: u8 = 14;
+pub const DEFAULT_MAX_ZOOM: u8 = 16;
+pub const MAX_ZOOM: u8 = 22;
+/// Default simplification tolerance, in tile units (1/4096 of a tile).
+pub const DEFAULT_SIMPLIFY: f64 = 4.0;
+/// Features are kept this far (in tile units) beyond the tile edge, so that renderers
+/// don't draw the clipped edges as outlines.
+const BUFFER: f64 = 64.0;
+/// Tiles larger than this (before compression) are slow to render; they are counted
+/// and reported.
+const LARGE_TILE_SIZE: usize = 500 * 1024;
+const MAX_LATITUDE: f64 = 85.051_128_78;
+
+const MBTILES_SCHEMA: &str = "
+    CREATE TABLE metadata (name TEXT, value TEXT);
+    CREATE TABLE tiles (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER, tile_data BLOB);
+    CREATE UNIQUE INDEX tile_index ON tiles (zoom_level, tile_column, tile_row);
+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TileFormat {
+    PMTiles,
+    MBTiles,
+}
+
+impl TileFormat {
+    /// The tile format for an output path, by its extension.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_string_lossy().to_lowercase();
+        match extension.as_str() {
+            "pmtiles" => Some(TileFormat::PMTiles),
+            "mbtiles" => Some(TileFormat::MBTiles),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TileOptions {
+    pub format: TileFormat,
+    pub min_zoom: u8,
+    pub max_zoom: u8,
+    /// Attributes to write, by name (see [`attribute_names`]). Empty writes them all.
+    pub attributes: Vec<String>,
+    /// Simplification tolerance in tile units. Below the maximum zoom, features are
+    /// simplified by this much at each zoom level; the maximum zoom keeps full detail
+    /// so that overzoomed tiles stay accurate.
+    pub simplify: f64,
+}
+
+/// Names of the attributes that can be written to the tiles, in order.
+pub fn attribute_names() -> Vec<&'static str> {
+    std::iter::once("global_id")
+        .chain(PROPERTY_COLUMNS.iter().map(|(name, _)| *name))
+        .collect()
+}
+
+/// Checks a `--tile-attributes` entry.
+pub fn parse_attribute(name: &str) -> Result<String, String> {
+    let names = attribute_names();
+    if names.contains(&name) {
+        Ok(name.to_string())
+    } else {
+        Err(format!("expected one of {}", names.join(", ")))
+    }
+}
+
+enum Destination {
+    PMTiles(BufWriter<File>),
+    MBTiles(Connection),
+}
+
+pub struct TileWriter {
+    output_path: PathBuf,
+    destination: Destination,
+    options: TileOptions,
+    /// Indexes into [`attribute_names`] of the attributes to write.
+    attributes: Vec<usize>,
+    tiles: HashMap<(u8, u32, u32), LayerBuilder>,
+    /// Bounds of all features as (min lon, min lat, max lon, max lat).
+    bounds: Option<[f64; 4]>,
+}
+
+impl TileWriter {
+    pub fn new(output_path: &Path, options: &TileOptions) -> Result<Self> {
+        if options.min_zoom > options.max_zoom || options.max_zoom > MAX_ZOOM {
+            bail!(
+                "Invalid zoom range {}-{} (zoom levels go up to {})",
+                options.min_zoom,
+                options.max_zoom,
+                MAX_ZOOM
+            );
+        }
+        let names = attribute_names();
+        let attributes = if options.attributes.is_empty() {
+            (0..names.len()).collect()
+        } else {
+            options
+                .attributes
+                .iter()
+                .map(|attribute| {
+                    names
+                        .iter()
+                        .position(|name| name == attribute)
+                        .ok_or_else(|| anyhow!("Unknown tile attribute: {}", attribute))
+                })
+                .collect::<Result<_>>()?
+        };
+        let destination = match options.format {
+            TileFormat::PMTiles => Destination::PMTiles(BufWriter::new(File::create(output_path)?)),
+            TileFormat::MBTiles => {
+                remove_output(output_path)?;
+                let connection = Connection::open(output_path)?;
+                connection.execute_batch(MBTILES_SCHEMA)?;
+                Destination::MBTiles(connection)
+            }
+        };
+        Ok(TileWriter {
+            output_path: output_path.to_path_buf(),
+            destination,
+            options: options.clone(),
+            attributes,
+            tiles: HashMap::new(),
+            bounds: None,
+        })
+    }
+
+    pub fn add_xml_features(&mut self, parsed: ParsedXML) -> Result<()> {
+        let common = &parsed.common_props;
+        for feature in &parsed.features {
+            let global_id = id::global_id(common, &feature.props);
+            let attributes =
+                feature_attributes(&self.attributes, &global_id, common, &feature.props);
+            let id = id::fid(common, &feature.props) as u64;
+
+            let Some(rect) = feature.geometry.bounding_rect() else {
+                continue;
+            };
+            let [min_x, min_y, max_x, max_y] =
+                self.bounds
+                    .get_or_insert([f64::MAX, f64::MAX, f64::MIN, f64::MIN]);
+            *min_x = min_x.min(rect.min().x);
+            *min_y = min_y.min(rect.min().y);
+            *max_x = max_x.max(rect.max().x);
+            *max_y = max_y.max(rect.max().y);
+
+            let projected = project(&feature.geometry);
+            for zoom in self.options.min_zoom..=self.options.max_zoom {
+                self.add_to_zoom(zoom, &projected, id, &attributes);
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds a feature (in Web Mercator, scaled to 0..1) to the tiles of one zoom level.
+    fn add_to_zoom(
+        &mut self,
+        zoom: u8,
+        geometry: &MultiPolygon,
+        id: u64,
+        attributes: &[(usize, &str)],
+    ) {
+        let geometry = if zoom < self.options.max_zoom {
+            simplify(geometry, zoom, self.options.simplify)
+        } else {
+            Cow::Borrowed(geometry)
+        };
+        let Some(rect) = geometry.bounding_rect() else {
+            return;
+        };
+        let scale = (1u64 << zoom) as f64;
+        let last = (1u32 << zoom) - 1;
+        let buffer = BUFFER / EXTENT as f64;
+        let tile_range = |min: f64, max: f64| {
+            let first = ((min * scale - buffer).floor().max(0.0) as u32).min(last);
+            let end = ((max * scale + buffer).floor().max(0.0) as u32).min(last);
+            first..=end
+        };
+        let attribute_count = self.attributes.len();
+        for x in tile_range(rect.min().x, rect.max().x) {
+            for y in tile_range(rect.min().y, rect.max().y) {
+                let polygons = tile_polygons(&geometry, zoom, x, y);
+                if !polygons.is_empty() {
+                    self.tiles
+                        .entry((zoom, x, y))
+                        .or_insert_with(|| LayerBuilder::new(attribute_count))
+                        .add_polygons(id, &polygons, attributes);
+                }
+            }
+        }
+    }
+
+    /// Encodes the tiles and writes the archive. Like [`crate::writer::FGBWriter::flush`],
+    /// the file is removed if there are no features and the return value tells whether
+    /// it was created.
+    pub fn flush(self) -> Result<bool> {
+        if self.tiles.is_empty() {
+            self.discard()?;
+            return Ok(false);
+        }
+        let TileWriter {
+            output_path,
+            destination,
+            options,
+            attributes,
+            tiles,
+            bounds,
+        } = self;
+        let all_names = attribute_names();
+        let names: Vec<&str> = attributes.iter().map(|&i| all_names[i]).collect();
+
+        let mut tiles: Vec<(u64, (u8, u32, u32), LayerBuilder)> = tiles
+            .into_iter()
+            .map(|((z, x, y), layer)| (tile_id(z, x, y), (z, x, y), layer))
+            .collect();
+        tiles.sort_unstable_by_key(|(tile_id, ..)| *tile_id);
+        let mut tiles_per_zoom = BTreeMap::new();
+        let mut large_tiles = 0;
+        let tiles = tiles
+            .into_iter()
+            .map(|(tile_id, key, layer)| {
+                *tiles_per_zoom.entry(key.0).or_insert(0) += 1;
+                let tile = layer.finish(LAYER_NAME, &names);
+                if tile.len() > LARGE_TILE_SIZE {
+                    large_tiles += 1;
+                }
+                Ok((tile_id, key, gzip(&tile)?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        for (zoom, count) in &tiles_per_zoom {
+            info!("[Tiles] Zoom {}: {} tile(s)", zoom, count);
+        }
+        if large_tiles > 0 {
+            warn!(
+                "{} tile(s) are larger than {} KiB; consider a higher --min-zoom or fewer attributes",
+                large_tiles,
+                LARGE_TILE_SIZE >> 10
+            );
+        }
+
+        let bounds = bounds.expect("bounds are set with the first feature");
+        let vector_layers = json!([{
+            "id": LAYER_NAME,
+            "fields": names.iter().map(|name| (name.to_string(), json!("String"))).collect::<serde_json::Map<_, _>>(),
+            "minzoom": options.min_zoom,
+            "maxzoom": options.max_zoom,
+        }]);
+        let name = output_path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let generator = format!("mojxml-rs {}", env!("CARGO_PKG_VERSION"));
+
+        match destination {
+            Destination::PMTiles(mut out) => {
+                let tiles: Vec<(u64, Vec<u8>)> = tiles
+                    .into_iter()
+                    .map(|(tile_id, _, data)| (tile_id, data))
+                    .collect();
+                let info = ArchiveInfo {
+                    min_zoom: options.min_zoom,
+                    max_zoom: options.max_zoom,
+                    bounds,
+                    metadata: json!({
+                        "name": name,
+                        "format": "pbf",
+                        "type": "overlay",
+                        "generator": generator,
+                        "vector_layers": vector_layers,
+                    }),
+                };
+                pmtiles::write_archive(&mut out, &tiles, &info)?;
+                out.flush()?;
+            }
+            Destination::MBTiles(mut connection) => {
+                let [min_lon, min_lat, max_lon, max_lat] = bounds;
+                let metadata = [
+                    ("name", name),
+                    ("format", "pbf".to_string()),
+                    ("type", "overlay".to_string()),
+                    ("generator", generator),
+                    ("minzoom", options.min_zoom.to_string()),
+                    ("maxzoom", options.max_zoom.to_string()),
+                    (
+                        "bounds",
+                        format!("{},{},{},{}", min_lon, min_lat, max_lon, max_lat),
+                    ),
+                    (
+                        "center",
+                        format!(
+                            "{},{},{}",
+                            (min_lon + max_lon) / 2.0,
+                            (min_lat + max_lat) / 2.0,
+                            options.min_zoom
+                        ),
+                    ),
+                    (
+                        "json",
+                        json!({ "vector_layers": vector_layers }).to_string(),
+                    ),
+                ];
+                let transaction = connection.transaction()?;
+                {
+                    let mut insert = transaction.prepare(
+                        "INSERT INTO tiles (zoom_level, tile_column, tile_row, tile_data) VALUES (?1, ?2, ?3, ?4)",
+                    )?;
+                    for (_, (z, x, y), data) in &tiles {
+                        // MBTiles counts rows from the bottom (TMS).
+                        insert.execute(params![z, x, (1u32 << z) - 1 - y, data])?;
+                    }
+                    let mut insert = transaction
+                        .prepare("INSERT INTO metadata (name, value) VALUES (?1, ?2)")?;
+                    for (name, value) in metadata {
+                        insert.execute(params![name, value])?;
+                    }
+                }
+                transaction.commit()?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Throw away the tiles and remove the output file.
+    pub fn discard(self) -> Result<()> {
+        // Close the file before removing it
+        drop(self.destination);
+        remove_output(&self.output_path)
+    }
+}
+
+/// (index in `attributes`, value) pairs of the attributes a feature has. `attributes`
+/// are indexes into [`attribute_names`].
+pub fn feature_attributes<'a>(
+    attributes: &[usize],
+    global_id: &'a str,
+    common: &'a CommonProperties,
+    props: &'a FeatureProperties,
+) -> Vec<(usize, &'a str)> {
+    let properties = property_values(common, props);
+    attributes
+        .iter()
+        .enumerate()
+        .filter_map(|(i, &attribute)| {
+            let value = match attribute {
+                0 => Some(global_id),
+                _ => properties[attribute - 1].map(String::as_str),
+            };
+            value.map(|value| (i, value))
+        })
+        .collect()
+}
+
+/// Projects a geometry to Web Mercator, scaled so that the world is the unit square
+/// with y pointing down.
+pub fn project(geometry: &MultiPolygon) -> MultiPolygon {
+    geometry.map_coords(mercator)
+}
+
+fn mercator(coord: Coord) -> Coord {
+    let lat = coord.y.clamp(-MAX_LATITUDE, MAX_LATITUDE).to_radians();
+    Coord {
+        x: (coord.x + 180.0) / 360.0,
+        y: (1.0 - lat.tan().asinh() / PI) / 2.0,
+    }
+}
+
+/// Longitude and latitude of a point in the unit square of [`project`].
+fn unproject(x: f64, y: f64) -> (f64, f64) {
+    let lon = x * 360.0 - 180.0;
+    let lat = ((1.0 - 2.0 * y) * PI).sinh().atan().to_degrees();
+    (lon, lat)
+}
+
+/// Bounds of a tile and its buffer as (min lon, min lat, max lon, max lat).
+pub fn tile_bounds(zoom: u8, x: u32, y: u32) -> [f64; 4] {
+    let scale = (1u64 << zoom) as f64;
+    let buffer = BUFFER / EXTENT as f64;
+    let (min_lon, max_lat) = unproject((x as f64 - buffer) / scale, (y as f64 - buffer) / scale);
+    let (max_lon, min_lat) = unproject(
+        (x as f64 + 1.0 + buffer) / scale,
+        (y as f64 + 1.0 + buffer) / scale,
+    );
+    [min_lon, min_lat, max_lon, max_lat]
+}
+
+/// Simplifies a projected geometry by `tolerance` tile units at `zoom`.
+pub fn simplify(geometry: &MultiPolygon, zoom: u8, tolerance: f64) -> Cow<'_, MultiPolygon> {
+    if tolerance <= 0.0 {
+        return Cow::Borrowed(geometry);
+    }
+    let scale = (1u64 << zoom) as f64;
+    Cow::Owned(geometry.simplify(&(tolerance / (EXTENT as f64 * scale))))
+}
+
+/// The parts of a projected geometry within tile (`x`, `y`) and its buffer, in tile
+/// coordinates.
+pub fn tile_polygons(geometry: &MultiPolygon, zoom: u8, x: u32, y: u32) -> Vec<Vec<Ring>> {
+    let scale = (1u64 << zoom) as f64;
+    let extent = EXTENT as f64;
+    let to_tile = |coord: &Coord| {
+        (
+            (coord.x * scale - x as f64) * extent,
+            (coord.y * scale - y as f64) * extent,
+        )
+    };
+    let mut polygons = Vec::new();
+    for polygon in geometry {
+        // Holes of a polygon whose exterior is gone are dropped with it.
+        let Some(exterior) = tile_ring(polygon.exterior(), &to_tile) else {
+            continue;
+        };
+        let mut rings = vec![exterior];
+        rings.extend(
+            polygon
+                .interiors()
+                .iter()
+                .filter_map(|ring| tile_ring(ring, &to_tile)),
+        );
+        polygons.push(rings);
+    }
+    polygons
+}
+
+/// Clips a ring to the tile and rounds it to integer coordinates. Rings that collapse
+/// to nothing at this zoom level are dropped.
+fn tile_ring(ring: &LineString, to_tile: &impl Fn(&Coord) -> (f64, f64)) -> Option<Ring> {
+    let mut points: Vec<(f64, f64)> = ring.0.iter().map(to_tile).collect();
+    if points.len() > 1 && points.first() == points.last() {
+        points.pop();
+    }
+    let points = clip_ring(points, -BUFFER, EXTENT as f64 + BUFFER);
+    let mut ring: Ring = Vec::with_capacity(points.len());
+    for (x, y) in points {
+        let point = (x.round() as i32, y.round() as i32);
+        if ring.last() != Some(&point) {
+            ring.push(point);
+        }
+    }
+    while ring.len() > 1 && ring.first() == ring.last() {
+        ring.pop();
+    }
+    (ring.len() >= 3 && ring_area(&ring) != 0).then_some(ring)
+}
+
+/// Clips a ring to the square `min..=max` (Sutherland-Hodgman).
+fn clip_ring(mut points: Vec<(f64, f64)>, min: f64, max: f64) -> Vec<(f64, f64)> {
+    let within = |&(x, y): &(f64, f64)| x >= min && x <= max && y >= min && y <= max;
+    if points.iter().all(within) {
+        return points;
+    }
+    // (axis, bound, whether the inside is above the bound)
+    for (axis, bound, above) in [
+        (0, min, true),
+        (0, max, false),
+        (1, min, true),
+        (1, max, false),
+    ] {
+        let Some(&last) = points.last() else {
+            break;
+        };
+        let value = |point: (f64, f64)| if axis == 0 { point.0 } else { point.1 };
+        let inside = |point: (f64, f64)| {
+            if above {
+                value(point) >= bound
+            } else {
+                value(point) <= bound
+            }
+        };
+        let crossing = |a: (f64, f64), b: (f64, f64)| {
+            let t = (bound - value(a)) / (value(b) - value(a));
+            if axis == 0 {
+                (bound, a.1 + t * (b.1 - a.1))
+            } else {
+                (a.0 + t * (b.0 - a.0), bound)
+            }
+        };
+        let mut clipped = Vec::with_capacity(points.len() + 4);
+        let mut previous = last;
+        for &current in &points {
+            if inside(current) {
+                if !inside(previous) {
+                    clipped.push(crossing(previous, current));
+                }
+                clipped.push(current);
+            } else if inside(previous) {
+                clipped.push(crossing(previous, current));
+            }
+            previous = current;
+        }
+        points = clipped;
+    }
+    points
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::FeatureProperties;
+
+    #[test]
+    fn test_clip_ring() {
+        // A square half outside the right edge is cut at the edge.
+        let square = vec![(50.0, 0.0), (150.0, 0.0), (150.0, 100.0), (50.0, 100.0)];
+        let clipped = clip_ring(square, 0.0, 100.0);
+        assert_eq!(
+            clipped,
+            vec![(50.0, 0.0), (100.0, 0.0), (100.0, 100.0), (50.0, 100.0)]
+        );
+        assert!(clip_ring(vec![(200.0, 0.0), (300.0, 0.0), (300.0, 50.0)], 0.0, 100.0).is_empty());
+    }
+
+    /// One parcel about 100 m across, near Kagoshima.
+    fn sample_parsed() -> ParsedXML {
+        let props = FeatureProperties {
+            筆id: "H000000001".to_string(),
+            地番: Some("1-1".to_string()),
+            ..Default::default()
+        };
+        crate::query::tests::sample_parsed("テスト市", vec![(130.5, props)])
+    }
+
+    fn options(format: TileFormat) -> TileOptions {
+        TileOptions {
+            format,
+            min_zoom: 10,
+            max_zoom: 12,
+            attributes: vec!["地番".to_string()],
+            simplify: DEFAULT_SIMPLIFY,
+        }
+    }
+
+    #[test]
+    fn test_write_pmtiles() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("out.pmtiles");
+        let mut writer = TileWriter::new(&path, &options(TileFormat::PMTiles))?;
+        writer.add_xml_features(sample_parsed())?;
+        assert_eq!(writer.tiles.len(), 3);
+        assert!(writer.flush()?);
+
+        let data = std::fs::read(&path)?;
+        assert_eq!(&data[..8], b"PMTiles\x03");
+        // One tile at each of the zoom levels 10 to 12.
+        assert_eq!(u64::from_le_bytes(data[72..80].try_into()?), 3);
+        assert_eq!((data[100], data[101]), (10, 12));
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_mbtiles() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("out.mbtiles");
+        let mut writer = TileWriter::new(&path, &options(TileFormat::MBTiles))?;
+        writer.add_xml_features(sample_parsed())?;
+        writer.flush()?;
+
+        let connection = Connection::open(&path)?;
+        let (x, row): (u32, u32) = connection.query_row(
+            "SELECT tile_column, tile_row FROM tiles WHERE zoom_level = 12",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        let tile = mercator(Coord { x: 130.5, y: 31.5 });
+        assert_eq!(x, (tile.x * 4096.0) as u32);
+        assert_eq!(row, 4095 - (tile.y * 4096.0) as u32);
+        let json: String = connection.query_row(
+            "SELECT value FROM metadata WHERE name = 'json'",
+            [],
+            |row| row.get(0),
+        )?;
+        assert!(json.contains("地番") && !json.contains("global_id"));
+
+        // No features, no file.
+        let empty = dir.path().join("empty.mbtiles");
+        assert!(!TileWriter::new(&empty, &options(TileFormat::MBTiles))?.flush()?);
+        assert!(!empty.exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,720 @@
+//! Topology checks between the parcels of a 地図: overlapping parcels, and slivers or
+//! gaps between neighbours.
+//!
+//! Within a 地図, parcels should tile without gaps or overlaps. Neighbours are recognised
+//! by the `GM_Curve`s they share: every curve of a ring is one vertex, and a boundary
+//! segment (two consecutive curves) used by two parcels is their common boundary by
+//! construction. A gap can only open up along segments used by a single parcel, so gaps
+//! are only searched between neighbours that both have such segments. Overlaps are
+//! searched between all parcels whose bounding boxes intersect.
+
+use crate::parse::{
+    CommonProperties, Feature, ParseOptions, ParsedXML, parse_document, read_document,
+};
+use crate::reader::{FileData, ReaderOptions, map_xml_files};
+use crate::report::ErrorReport;
+use crate::writer::remove_output;
+use anyhow::Result;
+use flatgeobuf::{
+    ColumnType, FgbCrs, FgbWriter, FgbWriterOptions, GeometryType,
+    geozero::{ColumnValue, PropertyProcessor},
+};
+use geo::{Area, BooleanOps, BoundingRect, Euclidean, GeodesicArea, Haversine, Length};
+use geo_types::{Coord, Geometry, LineString, MultiPolygon, Polygon, Rect};
+use roxmltree::{Document, Node};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Overlaps and gaps smaller than this (in m²) are ignored as rounding noise.
+pub const DEFAULT_MIN_AREA: f64 = 0.01;
+/// Holes between two neighbours are reported as gaps up to this mean width (in m). Wider
+/// ones are taken to be roads, waterways or other land without a 地番.
+pub const DEFAULT_MAX_GAP_WIDTH: f64 = 0.5;
+
+#[derive(Debug, Clone)]
+pub struct TopologyOptions {
+    pub min_area: f64,
+    pub max_gap_width: f64,
+    /// Also check files in 任意座標系.
+    pub include_arbitrary_crs: bool,
+    pub threads: usize,
+    pub reader: ReaderOptions,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum IssueType {
+    Overlap,
+    Gap,
+}
+
+impl IssueType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IssueType::Overlap => "overlap",
+            IssueType::Gap => "gap",
+        }
+    }
+}
+
+/// An overlap or gap between two parcels of the same file.
+#[derive(Debug, Clone)]
+pub struct TopologyIssue {
+    pub issue_type: IssueType,
+    /// 筆id of the two parcels, in document order.
+    pub 筆id: [String; 2],
+    pub 地番: [Option<String>; 2],
+    /// The overlapping area, or the gap between the parcels.
+    pub geometry: MultiPolygon,
+    /// In m² (or square units of the arbitrary coordinate system).
+    pub area: f64,
+    /// Twice the area over the perimeter, in m: the width of a long, thin sliver.
+    pub mean_width: f64,
+}
+
+/// The issues of one XML file.
+#[derive(Debug)]
+pub struct FileTopology {
+    pub file_name: String,
+    pub common_props: CommonProperties,
+    pub issues: Vec<TopologyIssue>,
+}
+
+#[derive(Debug, Default)]
+pub struct TopologySummary {
+    pub xml_file_count: usize,
+    pub overlap_count: usize,
+    pub gap_count: usize,
+    /// Inputs or XML files that could not be read or parsed.
+    pub failures: Vec<(String, ErrorReport)>,
+    pub output_written: bool,
+}
+
+/// The curves making up each parcel, read from the XML: the `GM_CompositeCurve.generator`
+/// references of every ring of the 筆's surface.
+#[derive(Debug, Default)]
+pub struct CurveTopology {
+    /// 筆id → GM_Surface id.
+    surfaces: HashMap<String, String>,
+    /// GM_Surface id → curve ids of each ring.
+    rings: HashMap<String, Vec<Vec<String>>>,
+}
+
+impl CurveTopology {
+    pub fn from_document(doc: &Document) -> Self {
+        let mut topology = CurveTopology::default();
+        for node in doc.descendants().filter(Node::is_element) {
+            match node.tag_name().name() {
+                "GM_Surface" => {
+                    let Some(id) = node.attribute("id") else {
+                        continue;
+                    };
+                    let rings = node
+                        .descendants()
+                        .filter(|n| n.tag_name().name() == "GM_Ring")
+                        .map(|ring| {
+                            ring.children()
+                                .filter_map(|n| n.attribute("idref"))
+                                .map(str::to_string)
+                                .collect()
+                        })
+                        .collect();
+                    topology.rings.insert(id.to_string(), rings);
+                }
+                "筆" => {
+                    let shape = node
+                        .children()
+                        .find(|n| n.tag_name().name() == "形状")
+                        .and_then(|n| n.attribute("idref"));
+                    if let (Some(id), Some(shape)) = (node.attribute("id"), shape) {
+                        topology.surfaces.insert(id.to_string(), shape.to_string());
+                    }
+                }
+                _ => {}
+            }
+        }
+        topology
+    }
+}
+
+/// A surface and the first parcel using it. Parcels sharing a surface (e.g. the 筆 of a
+/// 筆界未定 group) are checked once.
+struct Shape<'a> {
+    feature: &'a Feature,
+    rings: &'a [Vec<String>],
+    bbox: Rect,
+    /// Whether a boundary segment of the shape is used by no other shape.
+    has_free_segments: bool,
+}
+
+/// Finds the overlaps and gaps between the parcels of a parsed file.
+pub fn check_topology(
+    parsed: &ParsedXML,
+    topology: &CurveTopology,
+    options: &TopologyOptions,
+) -> Vec<TopologyIssue> {
+    let geographic = parsed.common_props.座標系 != "任意座標系";
+    let mut seen = HashSet::new();
+    let mut shapes = Vec::new();
+    for feature in &parsed.features {
+        let Some(surface) = topology.surfaces.get(&feature.props.筆id) else {
+            continue;
+        };
+        let (Some(rings), Some(bbox)) = (
+            topology.rings.get(surface),
+            feature.geometry.bounding_rect(),
+        ) else {
+            continue;
+        };
+        if seen.insert(surface) {
+            shapes.push(Shape {
+                feature,
+                rings,
+                bbox,
+                has_free_segments: false,
+            });
+        }
+    }
+
+    // Vertices and boundary segments, each counted once per shape.
+    let mut vertices: HashMap<&str, Vec<usize>> = HashMap::new();
+    let mut segments: HashMap<(&str, &str), usize> = HashMap::new();
+    for (index, shape) in shapes.iter().enumerate() {
+        let ids: HashSet<&str> = shape.rings.iter().flatten().map(String::as_str).collect();
+        for id in ids {
+            vertices.entry(id).or_default().push(index);
+        }
+        for segment in shape_segments(shape.rings) {
+            *segments.entry(segment).or_default() += 1;
+        }
+    }
+    // The rings of the geometry follow the rings of curve ids, one coordinate per curve.
+    let mut coords: HashMap<&str, Coord> = HashMap::new();
+    for shape in &shapes {
+        let geometry_rings = shape
+            .feature
+            .geometry
+            .iter()
+            .flat_map(|polygon| std::iter::once(polygon.exterior()).chain(polygon.interiors()));
+        for (ids, ring) in shape.rings.iter().zip(geometry_rings) {
+            if ids.len() == ring.0.len() {
+                coords.extend(ids.iter().map(String::as_str).zip(ring.0.iter().copied()));
+            }
+        }
+    }
+    for shape in &mut shapes {
+        shape.has_free_segments =
+            shape_segments(shape.rings).any(|segment| segments[&segment] == 1);
+    }
+
+    let mut issues = Vec::new();
+    for (a, b) in bbox_pairs(&shapes) {
+        let (a, b) = (&shapes[a], &shapes[b]);
+        let overlap = a.feature.geometry.intersection(&b.feature.geometry);
+        let (area, mean_width) = measure(&overlap, geographic);
+        if area >= options.min_area {
+            issues.push(issue(IssueType::Overlap, a, b, overlap, area, mean_width));
+        }
+    }
+
+    let neighbours: BTreeSet<(usize, usize)> = vertices
+        .values()
+        .flat_map(|indices| {
+            indices
+                .iter()
+                .enumerate()
+                .flat_map(|(i, a)| indices[i + 1..].iter().map(move |b| (*a, *b)))
+        })
+        .collect();
+    for (a, b) in neighbours {
+        let (a, b) = (&shapes[a], &shapes[b]);
+        if !a.has_free_segments || !b.has_free_segments {
+            continue;
+        }
+        for gap in find_gaps(a, b, &coords) {
+            let (area, mean_width) = measure(&gap, geographic);
+            if area >= options.min_area && mean_width <= options.max_gap_width {
+                issues.push(issue(IssueType::Gap, a, b, gap, area, mean_width));
+            }
+        }
+    }
+    issues
+}
+
+/// Reads the file and checks its topology. 地区外 / 別図 parcels are left out, as they
+/// are in a conversion.
+pub fn check_file(
+    file: &FileData,
+    options: &TopologyOptions,
+) -> crate::error::Result<FileTopology> {
+    let parse_options = ParseOptions {
+        include_arbitrary_crs: options.include_arbitrary_crs,
+        include_chikugai: false,
+        lenient: true,
+    };
+    let doc = read_document(file)?;
+    let parsed = parse_document(&file.file_name, &doc, &parse_options)?;
+    let topology = CurveTopology::from_document(&doc);
+    Ok(FileTopology {
+        issues: check_topology(&parsed, &topology, options),
+        file_name: parsed.file_name,
+        common_props: parsed.common_props,
+    })
+}
+
+/// Checks all XML files of the inputs and writes the issues to `output_path`.
+pub fn check_inputs(
+    output_path: &Path,
+    paths: &[PathBuf],
+    options: &TopologyOptions,
+) -> Result<TopologySummary> {
+    let mut summary = TopologySummary::default();
+    let mut files = Vec::new();
+    let results = map_xml_files(paths, &options.reader, options.threads, |file_data| {
+        check_file(&file_data, options).map_err(|e| (file_data.file_name, e))
+    });
+    for result in results {
+        match result {
+            Ok(Ok(file)) => {
+                summary.xml_file_count += 1;
+                for issue in &file.issues {
+                    match issue.issue_type {
+                        IssueType::Overlap => summary.overlap_count += 1,
+                        IssueType::Gap => summary.gap_count += 1,
+                    }
+                }
+                files.push(file);
+            }
+            Ok(Err((file_name, e))) => {
+                summary.xml_file_count += 1;
+                summary.failures.push((file_name, ErrorReport::from(&e)));
+            }
+            Err((path, e)) => summary
+                .failures
+                .push((path.display().to_string(), ErrorReport::new(e.kind(), &e))),
+        }
+    }
+    summary.output_written = write_issues(output_path, &files)?;
+    Ok(summary)
+}
+
+/// Boundary segments of the rings as (smaller id, larger id), without duplicates.
+fn shape_segments(rings: &[Vec<String>]) -> impl Iterator<Item = (&str, &str)> {
+    let segments: HashSet<(&str, &str)> = rings
+        .iter()
+        .flat_map(|ring| ring.windows(2))
+        .filter(|pair| pair[0] != pair[1])
+        .map(|pair| {
+            let (a, b) = (pair[0].as_str(), pair[1].as_str());
+            if a < b { (a, b) } else { (b, a) }
+        })
+        .collect();
+    segments.into_iter()
+}
+
+/// Pairs of shapes whose bounding boxes intersect, found by sweeping along x.
+fn bbox_pairs(shapes: &[Shape]) -> Vec<(usize, usize)> {
+    let mut order: Vec<usize> = (0..shapes.len()).collect();
+    order.sort_by(|a, b| shapes[*a].bbox.min().x.total_cmp(&shapes[*b].bbox.min().x));
+    let mut pairs = Vec::new();
+    for (i, a) in order.iter().enumerate() {
+        let bbox = shapes[*a].bbox;
+        for b in &order[i + 1..] {
+            let other = shapes[*b].bbox;
+            if other.min().x > bbox.max().x {
+                break;
+            }
+            if other.min().y <= bbox.max().y && bbox.min().y <= other.max().y {
+                pairs.push(((*a).min(*b), (*a).max(*b)));
+            }
+        }
+    }
+    pairs.sort_unstable();
+    pairs
+}
+
+/// Regions enclosed by two neighbours between the curves they share: the boundary of `a`
+/// from one shared curve to the next, closed by the boundary of `b` back to the first one.
+/// Regions that are inside either parcel (the parcels themselves, or overlaps) are not gaps.
+fn find_gaps(a: &Shape, b: &Shape, coords: &HashMap<&str, Coord>) -> Vec<MultiPolygon> {
+    let a_ids: HashSet<&str> = a.rings.iter().flatten().map(String::as_str).collect();
+    let b_ids: HashSet<&str> = b.rings.iter().flatten().map(String::as_str).collect();
+    let shared_ids: HashSet<&str> = a_ids.intersection(&b_ids).copied().collect();
+    let b_segments: HashSet<(&str, &str)> = shape_segments(b.rings).collect();
+    let mut seen = HashSet::new();
+    let mut gaps = Vec::new();
+    for ring in a.rings {
+        let ids = open_ring(ring);
+        let shared: Vec<usize> = (0..ids.len())
+            .filter(|i| shared_ids.contains(ids[*i]))
+            .collect();
+        for (k, &start) in shared.iter().enumerate() {
+            let end = shared[(k + 1) % shared.len()];
+            let (u, v) = (ids[start], ids[end]);
+            let segment = if u < v { (u, v) } else { (v, u) };
+            if u == v || (end == (start + 1) % ids.len() && b_segments.contains(&segment)) {
+                continue;
+            }
+            let a_path = cyclic_path(&ids, start, end);
+            for b_ring in b.rings {
+                for b_path in paths_between(open_ring(b_ring), v, u, &shared_ids) {
+                    let path: Vec<&str> = a_path.iter().chain(&b_path[1..]).copied().collect();
+                    let mut key = path.clone();
+                    key.sort_unstable();
+                    if path.len() < 4 || !seen.insert(key) {
+                        continue;
+                    }
+                    let Some(points) = path
+                        .iter()
+                        .map(|id| coords.get(id).copied())
+                        .collect::<Option<Vec<Coord>>>()
+                    else {
+                        continue;
+                    };
+                    let gap =
+                        MultiPolygon::new(vec![Polygon::new(LineString::new(points), vec![])]);
+                    let area = gap.unsigned_area();
+                    let inside = gap.intersection(&a.feature.geometry).unsigned_area()
+                        + gap.intersection(&b.feature.geometry).unsigned_area();
+                    if area > 0.0 && inside <= area * 0.01 {
+                        gaps.push(gap);
+                    }
+                }
+            }
+        }
+    }
+    gaps
+}
+
+/// The curve ids of a ring without the closing repetition of the first one.
+fn open_ring(ring: &[String]) -> Vec<&str> {
+    let mut ids: Vec<&str> = ring.iter().map(String::as_str).collect();
+    if ids.len() > 1 && ids.first() == ids.last() {
+        ids.pop();
+    }
+    ids
+}
+
+/// The ids from `start` to `end` (both included), wrapping around the end of the ring.
+fn cyclic_path<'a>(ids: &[&'a str], start: usize, end: usize) -> Vec<&'a str> {
+    let mut path = vec![ids[start]];
+    let mut i = start;
+    while i != end {
+        i = (i + 1) % ids.len();
+        path.push(ids[i]);
+    }
+    path
+}
+
+/// The paths along the ring, in either direction, from `from` to `to` that pass no other
+/// curve of `shared` on the way.
+fn paths_between<'a>(
+    ids: Vec<&'a str>,
+    from: &str,
+    to: &str,
+    shared: &HashSet<&str>,
+) -> Vec<Vec<&'a str>> {
+    let Some(start) = ids.iter().position(|id| *id == from) else {
+        return vec![];
+    };
+    let mut paths = Vec::new();
+    for step in [1, ids.len() - 1] {
+        let mut path = vec![ids[start]];
+        let mut i = start;
+        loop {
+            i = (i + step) % ids.len();
+            path.push(ids[i]);
+            if ids[i] == to {
+                paths.push(path);
+                break;
+            }
+            if i == start || shared.contains(ids[i]) {
+                break;
+            }
+        }
+    }
+    paths
+}
+
+/// Area and mean width, in meters for geographic coordinates.
+fn measure(geometry: &MultiPolygon, geographic: bool) -> (f64, f64) {
+    let rings = geometry
+        .iter()
+        .flat_map(|polygon| std::iter::once(polygon.exterior()).chain(polygon.interiors()));
+    let (area, perimeter) = if geographic {
+        let perimeter: f64 = rings.map(|ring: &LineString| Haversine.length(ring)).sum();
+        (geometry.geodesic_area_unsigned(), perimeter)
+    } else {
+        let perimeter: f64 = rings.map(|ring: &LineString| Euclidean.length(ring)).sum();
+        (geometry.unsigned_area(), perimeter)
+    };
+    let mean_width = if perimeter > 0.0 {
+        2.0 * area / perimeter
+    } else {
+        0.0
+    };
+    (area, mean_width)
+}
+
+fn issue(
+    issue_type: IssueType,
+    a: &Shape,
+    b: &Shape,
+    geometry: MultiPolygon,
+    area: f64,
+    mean_width: f64,
+) -> TopologyIssue {
+    let (a, b) = (&a.feature.props, &b.feature.props);
+    TopologyIssue {
+        issue_type,
+        筆id: [a.筆id.clone(), b.筆id.clone()],
+        地番: [a.地番.clone(), b.地番.clone()],
+        geometry,
+        area,
+        mean_width,
+    }
+}
+
+/// Writes the error layer. Returns `false` (and removes the file left by an earlier
+/// run) if there were no issues.
+fn write_issues(output_path: &Path, files: &[FileTopology]) -> Result<bool> {
+    if files.iter().all(|file| file.issues.is_empty()) {
+        remove_output(output_path)?;
+        return Ok(false);
+    }
+    // 任意座標系 coordinates aren't longitude / latitude, so the CRS is left unknown
+    // if any of the issues are in it.
+    let geographic = files
+        .iter()
+        .filter(|file| !file.issues.is_empty())
+        .all(|file| file.common_props.座標系 != "任意座標系");
+    let crs = if geographic {
+        FgbCrs {
+            code: 4326,
+            ..Default::default()
+        }
+    } else {
+        FgbCrs::default()
+    };
+    let mut fgb = FgbWriter::create_with_options(
+        "mojxml_topology",
+        GeometryType::MultiPolygon,
+        FgbWriterOptions {
+            crs,
+            ..Default::default()
+        },
+    )?;
+    for name in [
+        "issue_type",
+        "file",
+        "市区町村コード",
+        "地図名",
+        "筆id_a",
+        "地番_a",
+        "筆id_b",
+        "地番_b",
+    ] {
+        fgb.add_column(name, ColumnType::String, |_, col| {
+            col.nullable = name.starts_with("地番");
+        });
+    }
+    for name in ["area_m2", "mean_width_m"] {
+        fgb.add_column(name, ColumnType::Double, |_, _| {});
+    }
+
+    for file in files {
+        for issue in &file.issues {
+            let mut props_result = Ok(());
+            fgb.add_feature_geom(Geometry::from(issue.geometry.clone()), |feat| {
+                props_result = write_issue_properties(feat, file, issue);
+            })?;
+            props_result?;
+        }
+    }
+
+    let mut writer = BufWriter::new(File::create(output_path)?);
+    fgb.write(&mut writer)?;
+    writer.flush()?;
+    Ok(true)
+}
+
+fn write_issue_properties(
+    feat: &mut impl PropertyProcessor,
+    file: &FileTopology,
+    issue: &TopologyIssue,
+) -> geozero::error::Result<()> {
+    let strings = [
+        (0, "issue_type", Some(issue.issue_type.as_str())),
+        (1, "file", Some(file.file_name.as_str())),
+        (
+            2,
+            "市区町村コード",
+            Some(file.common_props.市区町村コード.as_str()),
+        ),
+        (3, "地図名", Some(file.common_props.地図名.as_str())),
+        (4, "筆id_a", Some(issue.筆id[0].as_str())),
+        (5, "地番_a", issue.地番[0].as_deref()),
+        (6, "筆id_b", Some(issue.筆id[1].as_str())),
+        (7, "地番_b", issue.地番[1].as_deref()),
+    ];
+    for (i, name, value) in strings {
+        if let Some(v) = value {
+            feat.property(i, name, &ColumnValue::String(v))?;
+        }
+    }
+    feat.property(8, "area_m2", &ColumnValue::Double(issue.area))?;
+    feat.property(9, "mean_width_m", &ColumnValue::Double(issue.mean_width))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::tests::{SAMPLE_XML, sample_file};
+    use flatgeobuf::FgbReader;
+    use std::fs;
+
+    const OPTIONS: TopologyOptions = TopologyOptions {
+        min_area: DEFAULT_MIN_AREA,
+        max_gap_width: DEFAULT_MAX_GAP_WIDTH,
+        include_arbitrary_crs: true,
+        threads: 2,
+        reader: ReaderOptions {
+            in_memory_zip_limit: 0,
+            memory: None,
+        },
+    };
+
+    /// A 任意座標系 map with one surface per ring of curve ids; 筆 `H<n>` uses surface
+    /// `S<n>`, except for the extra `shared` 筆 which use `S1`.
+    fn map_xml(curves: &[(&str, f64, f64)], rings: &[&[&str]], shared: usize) -> String {
+        let (head, _) = SAMPLE_XML.split_once("<空間属性>").unwrap();
+        let mut xml = format!("{}<空間属性>\n", head);
+        for (id, x, y) in curves {
+            xml += &format!(
+                "<zmn:GM_Curve id=\"{}\"><zmn:GM_Curve.segment><zmn:GM_LineString><zmn:GM_LineString.controlPoint><zmn:GM_PointArray.column><zmn:GM_Position.direct><zmn:X>{}</zmn:X><zmn:Y>{}</zmn:Y></zmn:GM_Position.direct></zmn:GM_PointArray.column></zmn:GM_LineString.controlPoint></zmn:GM_LineString></zmn:GM_Curve.segment></zmn:GM_Curve>\n",
+                id, x, y
+            );
+        }
+        for (n, ring) in rings.iter().enumerate() {
+            let generators: String = ring
+                .iter()
+                .chain(&ring[..1])
+                .map(|id| format!("<zmn:GM_CompositeCurve.generator idref=\"{}\"/>", id))
+                .collect();
+            xml += &format!(
+                "<zmn:GM_Surface id=\"S{}\"><zmn:GM_Surface.patch><zmn:GM_Polygon><zmn:GM_Polygon.boundary><zmn:GM_SurfaceBoundary><zmn:GM_SurfaceBoundary.exterior><zmn:GM_Ring>{}</zmn:GM_Ring></zmn:GM_SurfaceBoundary.exterior></zmn:GM_SurfaceBoundary></zmn:GM_Polygon.boundary></zmn:GM_Polygon></zmn:GM_Surface.patch></zmn:GM_Surface>\n",
+                n + 1,
+                generators
+            );
+        }
+        xml += "</空間属性>\n<主題属性>\n";
+        let shapes = (1..=rings.len()).chain(std::iter::repeat_n(1, shared));
+        for (n, surface) in shapes.enumerate() {
+            xml += &format!(
+                "<筆 id=\"H{}\"><地番>{}</地番><形状 idref=\"S{}\"/></筆>\n",
+                n + 1,
+                n + 1,
+                surface
+            );
+        }
+        xml + "</主題属性>\n</地図>\n"
+    }
+
+    /// Two neighbours sharing the curves at (10, 0) and (10, 10); the second one bends
+    /// away from their common boundary to (`bend`, 5). A third parcel lies inside the first.
+    fn sample_map(bend: f64) -> String {
+        map_xml(
+            &[
+                ("C1", 0.0, 0.0),
+                ("C2", 10.0, 0.0),
+                ("C3", 10.0, 10.0),
+                ("C4", 0.0, 10.0),
+                ("C5", 20.0, 0.0),
+                ("C6", 20.0, 10.0),
+                ("C7", bend, 5.0),
+                ("C8", 2.0, 2.0),
+                ("C9", 4.0, 2.0),
+                ("C10", 4.0, 4.0),
+                ("C11", 2.0, 4.0),
+            ],
+            &[
+                &["C1", "C2", "C3", "C4"],
+                &["C2", "C5", "C6", "C3", "C7"],
+                &["C8", "C9", "C10", "C11"],
+            ],
+            1,
+        )
+    }
+
+    fn issues(xml: &str) -> Vec<TopologyIssue> {
+        check_file(&sample_file(xml), &OPTIONS).unwrap().issues
+    }
+
+    #[test]
+    fn test_overlap_and_gap() {
+        let found = issues(&sample_map(10.2));
+        let summary: Vec<(IssueType, [&str; 2])> = found
+            .iter()
+            .map(|i| (i.issue_type, [i.筆id[0].as_str(), i.筆id[1].as_str()]))
+            .collect();
+        // H4 shares the surface of H1 and is not reported as overlapping it.
+        assert_eq!(
+            summary,
+            [
+                (IssueType::Overlap, ["H1", "H3"]),
+                (IssueType::Gap, ["H1", "H2"]),
+            ]
+        );
+        assert!((found[0].area - 4.0).abs() < 1e-6);
+        assert_eq!(found[0].地番[1].as_deref(), Some("3"));
+        assert!((found[1].area - 1.0).abs() < 1e-6);
+        assert!(found[1].mean_width < 0.1);
+    }
+
+    #[test]
+    fn test_exact_neighbours_and_wide_holes() {
+        // Bending onto the common boundary leaves nothing between the parcels.
+        let found = issues(&sample_map(10.0));
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].issue_type, IssueType::Overlap);
+
+        // A hole this wide is taken to be land without a 地番.
+        let found = issues(&sample_map(13.0));
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].issue_type, IssueType::Overlap);
+    }
+
+    #[test]
+    fn test_check_inputs() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let map = dir.path().join("a.xml");
+        fs::write(&map, sample_map(10.2))?;
+        let broken = dir.path().join("b.xml");
+        fs::write(&broken, "<地図>")?;
+        let output = dir.path().join("errors.fgb");
+
+        let summary = check_inputs(&output, &[map.clone(), broken], &OPTIONS)?;
+        assert_eq!(summary.xml_file_count, 2);
+        assert_eq!((summary.overlap_count, summary.gap_count), (1, 1));
+        assert_eq!(summary.failures.len(), 1);
+        assert!(summary.output_written);
+        let fgb = FgbReader::open(File::open(&output)?)?;
+        assert_eq!(fgb.header().features_count(), 2);
+        // The map is in 任意座標系, so the CRS is unknown.
+        assert_eq!(fgb.header().crs().map(|crs| crs.code()), Some(0));
+
+        // 任意座標系 files are skipped unless asked for.
+        let options = TopologyOptions {
+            include_arbitrary_crs: false,
+            ..OPTIONS
+        };
+        let summary = check_inputs(&output, &[map], &options)?;
+        assert_eq!(summary.overlap_count + summary.gap_count, 0);
+        assert!(!summary.output_written);
+        // The error layer of the earlier run is removed.
+        assert!(!output.exists());
+        Ok(())
+    }
+}
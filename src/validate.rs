@@ -0,0 +1,536 @@
+//! Checking the structure of MOJ XML files against the rules of the 地図XMLフォーマット
+//! before converting them.
+//!
+//! This is not a full XSD validation. It checks what the conversion depends on: the
+//! namespaces, the required elements of 地図 and 筆, coordinates, and that every id is
+//! unique and every idref points to an element of the right type (筆 → GM_Surface →
+//! GM_Curve → GM_Point). Unlike parsing, it reports every violation in a file instead
+//! of stopping at the first one.
+//!
+//! With [`ValidateOptions::integrity`], the [anomalies](crate::integrity) of each file
+//! are reported too. They don't make a file invalid.
+
+use crate::constants::{get_proj, get_xml_namespace};
+use crate::error::Error;
+use crate::integrity::{Anomaly, find_anomalies};
+use crate::reader::{FileData, ReaderOptions, map_xml_files};
+use crate::report::ErrorReport;
+use anyhow::Result;
+use clap::ValueEnum;
+use roxmltree::{Document, Node};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::path::PathBuf;
+
+/// Children of 地図 that every file has to have (測地系判別 is optional).
+const REQUIRED_ELEMENTS: [&str; 6] = [
+    "地図名",
+    "市区町村コード",
+    "市区町村名",
+    "座標系",
+    "空間属性",
+    "主題属性",
+];
+const SPATIAL_ELEMENTS: [&str; 3] = ["GM_Point", "GM_Curve", "GM_Surface"];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ValidateFormat {
+    /// One line per violation.
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone)]
+pub struct ValidateOptions {
+    pub threads: usize,
+    /// Also look for orphaned or shared geometries.
+    pub integrity: bool,
+    pub reader: ReaderOptions,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FileValidation {
+    /// The XML file name, or the input path if the input could not be read.
+    pub file: String,
+    pub violations: Vec<ErrorReport>,
+    /// Only with [`ValidateOptions::integrity`].
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub anomalies: Vec<Anomaly>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Validation {
+    pub xml_file_count: usize,
+    /// Only files with violations or anomalies.
+    pub files: Vec<FileValidation>,
+}
+
+impl Validation {
+    pub fn violation_count(&self) -> usize {
+        self.files.iter().map(|f| f.violations.len()).sum()
+    }
+
+    pub fn anomaly_count(&self) -> usize {
+        self.files.iter().map(|f| f.anomalies.len()).sum()
+    }
+
+    /// Files with at least one violation.
+    pub fn invalid_file_count(&self) -> usize {
+        self.files
+            .iter()
+            .filter(|f| !f.violations.is_empty())
+            .count()
+    }
+
+    pub fn write(&self, format: ValidateFormat, out: &mut impl Write) -> Result<()> {
+        match format {
+            ValidateFormat::Text => {
+                for file in &self.files {
+                    for violation in &file.violations {
+                        // Violations in XML files name their location in the message;
+                        // errors reading an input don't.
+                        if violation.line.is_none() {
+                            write!(out, "{}: ", file.file)?;
+                        }
+                        writeln!(out, "[{}] {}", violation.kind, violation.message)?;
+                    }
+                    for anomaly in &file.anomalies {
+                        writeln!(
+                            out,
+                            "{}:{}:{}: [{:?}] {}",
+                            file.file, anomaly.line, anomaly.column, anomaly.kind, anomaly
+                        )?;
+                    }
+                }
+            }
+            ValidateFormat::Json => {
+                serde_json::to_writer_pretty(&mut *out, self)?;
+                writeln!(out)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Validates all XML files of the inputs.
+pub fn validate(paths: &[PathBuf], options: &ValidateOptions) -> Result<Validation> {
+    let mut validation = Validation::default();
+    let results = map_xml_files(paths, &options.reader, options.threads, |file_data| {
+        let (violations, anomalies) = check_xml_content(&file_data, options.integrity);
+        FileValidation {
+            file: file_data.file_name,
+            violations: violations.iter().map(ErrorReport::from).collect(),
+            anomalies,
+        }
+    });
+    for result in results {
+        match result {
+            Ok(file) => {
+                validation.xml_file_count += 1;
+                if !file.violations.is_empty() || !file.anomalies.is_empty() {
+                    validation.files.push(file);
+                }
+            }
+            Err((path, e)) => validation.files.push(FileValidation {
+                file: path.display().to_string(),
+                violations: vec![ErrorReport::new(e.kind(), &e)],
+                anomalies: vec![],
+            }),
+        }
+    }
+    Ok(validation)
+}
+
+/// Returns every violation found in the file, in document order, and with `integrity`
+/// its anomalies. Files that are not well-formed XML have no anomalies.
+fn check_xml_content(file: &FileData, integrity: bool) -> (Vec<Error>, Vec<Anomaly>) {
+    let (violations, anomalies) = match Document::parse(&file.contents) {
+        Ok(doc) => {
+            let anomalies = if integrity {
+                find_anomalies(&doc)
+            } else {
+                vec![]
+            };
+            (Validator::default().validate(&doc), anomalies)
+        }
+        Err(e) => {
+            let pos = e.pos();
+            (vec![Error::Xml(e).at_position(pos.row, pos.col)], vec![])
+        }
+    };
+    let violations = violations
+        .into_iter()
+        .map(|e| e.in_file(&file.file_name))
+        .collect();
+    (violations, anomalies)
+}
+
+#[derive(Default)]
+struct Validator<'a> {
+    /// Element name of every id.
+    ids: HashMap<&'a str, &'a str>,
+    /// (element name, namespace) pairs already reported as unknown.
+    unknown_namespaces: HashSet<(&'a str, Option<&'a str>)>,
+    /// The child of 空間属性 or 主題属性 being checked, and its id.
+    current: Option<(&'a str, Option<&'a str>)>,
+    violations: Vec<Error>,
+}
+
+impl<'a> Validator<'a> {
+    fn validate(mut self, doc: &'a Document) -> Vec<Error> {
+        let tizu = get_xml_namespace(None);
+        let known = [None, Some("zmn"), Some("xsi")].map(get_xml_namespace);
+        for node in doc.descendants().filter(Node::is_element) {
+            let (name, namespace) = (node.tag_name().name(), node.tag_name().namespace());
+            if !known.contains(&namespace) && self.unknown_namespaces.insert((name, namespace)) {
+                self.report(
+                    Error::UnknownNamespace {
+                        element: name.to_string(),
+                        namespace: namespace.unwrap_or("(none)").to_string(),
+                    },
+                    &node,
+                );
+            }
+        }
+
+        let root = doc.root_element();
+        if root.tag_name().name() != "地図" || root.tag_name().namespace() != tizu {
+            self.report(
+                Error::UnexpectedElement(root.tag_name().name().to_string()),
+                &root,
+            );
+            return self.violations;
+        }
+        for name in REQUIRED_ELEMENTS {
+            let Some(element) = child(&root, name, tizu) else {
+                self.report(Error::MissingElement(name.to_string()), &root);
+                continue;
+            };
+            let text = element.text().unwrap_or("").trim();
+            if !matches!(name, "空間属性" | "主題属性") && text.is_empty() {
+                self.report(Error::MissingElement(name.to_string()), &element);
+            }
+            if name == "座標系" && !text.is_empty() && get_proj(text).is_err() {
+                self.report(Error::UnsupportedCrs(text.to_string()), &element);
+            }
+        }
+
+        // Collect the ids first; references may point forward.
+        for node in root.descendants().filter(Node::is_element) {
+            if let Some(id) = node.attribute("id") {
+                self.current = enclosing(&node);
+                if self.ids.insert(id, node.tag_name().name()).is_some() {
+                    self.report(Error::DuplicateId(id.to_string()), &node);
+                }
+            }
+        }
+
+        let spatial = child(&root, "空間属性", tizu);
+        let subject = child(&root, "主題属性", tizu);
+        let elements = [spatial, subject]
+            .into_iter()
+            .flatten()
+            .flat_map(|parent| parent.children().filter(Node::is_element));
+        for node in elements {
+            let name = node.tag_name().name();
+            self.current = Some((name, node.attribute("id")));
+            let spatial_element = node.parent() == spatial;
+            if spatial_element
+                && (!SPATIAL_ELEMENTS.contains(&name)
+                    || node.tag_name().namespace() != get_xml_namespace(Some("zmn")))
+            {
+                self.report(Error::UnexpectedElement(name.to_string()), &node);
+                continue;
+            }
+            if (spatial_element || name == "筆") && node.attribute("id").is_none() {
+                self.report(missing_attribute(name, "id"), &node);
+            }
+            match name {
+                "GM_Point" => self.validate_point(&node),
+                "GM_Curve" => self.validate_curve(&node),
+                "GM_Surface" => self.validate_surface(&node),
+                "筆" => self.validate_fude(&node),
+                _ => {}
+            }
+        }
+
+        for node in root.descendants().filter(|n| n.has_attribute("idref")) {
+            self.current = enclosing(&node);
+            self.validate_reference(&node);
+        }
+
+        // Sorted by position, so violations read in document order.
+        self.violations
+            .sort_by_key(|e| e.location().and_then(|l| l.position));
+        self.violations
+    }
+
+    fn validate_point(&mut self, point: &Node<'a, 'a>) {
+        match descendant(point, "DirectPosition") {
+            Some(position) => self.validate_coordinates(&position),
+            None => self.report(Error::MissingElement("DirectPosition".to_string()), point),
+        }
+    }
+
+    fn validate_curve(&mut self, curve: &Node<'a, 'a>) {
+        if descendant(curve, "GM_Curve.segment").is_none() {
+            self.report(Error::MissingElement("GM_Curve.segment".to_string()), curve);
+            return;
+        }
+        let columns: Vec<Node> = curve
+            .descendants()
+            .filter(|n| n.tag_name().name() == "GM_PointArray.column")
+            .collect();
+        if columns.is_empty() {
+            self.report(
+                Error::MissingElement("GM_PointArray.column".to_string()),
+                curve,
+            );
+        }
+        for column in columns {
+            let Some(position) = column.first_element_child() else {
+                self.report(Error::MissingElement("GM_Position.*".to_string()), &column);
+                continue;
+            };
+            match position.tag_name().name() {
+                "GM_Position.direct" => self.validate_coordinates(&position),
+                "GM_Position.indirect" => match position.first_element_child() {
+                    Some(reference) => self.require_idref(&reference),
+                    None => self.report(
+                        Error::MissingElement("GM_PointRef.point".to_string()),
+                        &position,
+                    ),
+                },
+                other => self.report(Error::UnexpectedElement(other.to_string()), &position),
+            }
+        }
+    }
+
+    fn validate_surface(&mut self, surface: &Node<'a, 'a>) {
+        if descendant(surface, "GM_SurfaceBoundary.exterior").is_none() {
+            self.report(
+                Error::MissingElement("GM_SurfaceBoundary.exterior".to_string()),
+                surface,
+            );
+        }
+        let rings: Vec<Node> = surface
+            .descendants()
+            .filter(|n| n.tag_name().name() == "GM_Ring")
+            .collect();
+        for ring in rings {
+            let generators: Vec<Node> = ring.children().filter(Node::is_element).collect();
+            if generators.is_empty() {
+                self.report(
+                    Error::MissingElement("GM_CompositeCurve.generator".to_string()),
+                    &ring,
+                );
+            }
+            for generator in generators {
+                self.require_idref(&generator);
+            }
+        }
+    }
+
+    fn validate_fude(&mut self, fude: &Node<'a, 'a>) {
+        match child(fude, "形状", get_xml_namespace(None)) {
+            Some(shape) => self.require_idref(&shape),
+            None => self.report(Error::MissingElement("形状".to_string()), fude),
+        }
+        if child(fude, "地番", get_xml_namespace(None)).is_none() {
+            self.report(Error::MissingElement("地番".to_string()), fude);
+        }
+    }
+
+    fn validate_coordinates(&mut self, position: &Node<'a, 'a>) {
+        for axis in ["X", "Y"] {
+            let Some(node) = position.children().find(|n| n.tag_name().name() == axis) else {
+                self.report(Error::MissingElement(axis.to_string()), position);
+                continue;
+            };
+            if let Err(e) = node.text().unwrap_or("").trim().parse::<f64>() {
+                self.report(Error::from(e), &node);
+            }
+        }
+    }
+
+    fn require_idref(&mut self, node: &Node<'a, 'a>) {
+        if !node.has_attribute("idref") {
+            self.report(missing_attribute(node.tag_name().name(), "idref"), node);
+        }
+    }
+
+    /// Checks that the idref points to an existing element, and to one of the right type
+    /// where the format defines it.
+    fn validate_reference(&mut self, node: &Node<'a, 'a>) {
+        let idref = node.attribute("idref").unwrap_or_default();
+        let parent = node.parent_element().map(|p| p.tag_name().name());
+        let expected = match (node.tag_name().name(), parent) {
+            ("GM_PointRef.point", _) => Some("GM_Point"),
+            ("GM_CompositeCurve.generator" | "GM_OrientablePrimitive.primitive", _) => {
+                Some("GM_Curve")
+            }
+            ("筆参照", _) => Some("筆"),
+            ("形状", Some("筆")) => Some("GM_Surface"),
+            ("形状", Some("筆界線")) => Some("GM_Curve"),
+            ("形状", Some("筆界点" | "基準点")) => Some("GM_Point"),
+            _ => None,
+        };
+        let found = self.ids.get(idref);
+        if found.is_none() || expected.is_some_and(|expected| found != Some(&expected)) {
+            let error = Error::ReferenceNotFound {
+                element: expected.unwrap_or("element").to_string(),
+                id: idref.to_string(),
+            };
+            self.report(error, node);
+        }
+    }
+
+    fn report(&mut self, error: Error, node: &Node) {
+        let error = match self.current {
+            Some((element, id)) => error.within(element, id),
+            None => error,
+        };
+        self.violations.push(error.at(node));
+    }
+}
+
+/// The child of 空間属性 or 主題属性 containing `node`, and its id.
+fn enclosing<'a>(node: &Node<'a, 'a>) -> Option<(&'a str, Option<&'a str>)> {
+    node.ancestors()
+        .find(|n| {
+            n.parent_element()
+                .is_some_and(|p| matches!(p.tag_name().name(), "空間属性" | "主題属性"))
+        })
+        .map(|n| (n.tag_name().name(), n.attribute("id")))
+}
+
+fn child<'a>(node: &Node<'a, 'a>, name: &str, namespace: Option<&str>) -> Option<Node<'a, 'a>> {
+    node.children()
+        .find(|n| n.tag_name().name() == name && n.tag_name().namespace() == namespace)
+}
+
+fn descendant<'a>(node: &Node<'a, 'a>, name: &str) -> Option<Node<'a, 'a>> {
+    node.descendants().find(|n| n.tag_name().name() == name)
+}
+
+fn missing_attribute(element: &str, attribute: &str) -> Error {
+    Error::MissingAttribute {
+        element: element.to_string(),
+        attribute: attribute.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::tests::{SAMPLE_XML, sample_file};
+
+    fn violations(xml: &str) -> Vec<(&'static str, Option<u32>, Option<String>)> {
+        check_xml_content(&sample_file(xml), false)
+            .0
+            .iter()
+            .map(|e| {
+                let location = e.location().unwrap();
+                (
+                    e.kind(),
+                    location.position.map(|(line, _)| line),
+                    location.element.clone(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_valid_sample() {
+        assert!(violations(SAMPLE_XML).is_empty());
+    }
+
+    #[test]
+    fn test_violations() {
+        let xml = SAMPLE_XML
+            .replace("<市区町村名>熊毛郡屋久島町</市区町村名>\n", "")
+            .replace(r#"idref="P1""#, r#"idref="C2""#)
+            .replacen(r#"<zmn:X>10</zmn:X>"#, r#"<zmn:X>1O</zmn:X>"#, 1)
+            .replace(r#"<zmn:GM_Curve id="C4">"#, r#"<zmn:GM_Curve id="C3">"#)
+            .replace("<地番>1</地番>", "")
+            .replace(
+                "</主題属性>",
+                "<筆 id=\"H2\"><地番>2</地番><形状 idref=\"S2\"/></筆>\n</主題属性>",
+            );
+        let found = violations(&xml);
+        let kinds: Vec<&str> = found.iter().map(|(kind, ..)| *kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                "MissingElement",
+                "ReferenceNotFound",
+                "ParseFloat",
+                "DuplicateId",
+                "ReferenceNotFound",
+                "MissingElement",
+                "ReferenceNotFound",
+            ]
+        );
+        assert_eq!(found[0].2, None);
+        assert_eq!(found[1].2.as_deref(), Some("GM_Curve C1"));
+        assert_eq!(found[3].2.as_deref(), Some("GM_Curve C3"));
+        assert_eq!(found[4].2.as_deref(), Some("GM_Surface S1"));
+        assert_eq!(found[6].2.as_deref(), Some("筆 H2"));
+    }
+
+    #[test]
+    fn test_malformed_and_namespaces() {
+        let found = violations("<地図>\n<地図名></市区町村名>\n</地図>");
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].0, "Xml");
+        assert_eq!(found[0].1, Some(2));
+
+        let xml = SAMPLE_XML.replace(
+            r#"xmlns:zmn="http://www.moj.go.jp/MINJI/tizuzumen""#,
+            r#"xmlns:zmn="http://example.com/zumen""#,
+        );
+        let kinds: Vec<&str> = violations(&xml).iter().map(|(kind, ..)| *kind).collect();
+        assert!(kinds.contains(&"UnknownNamespace"));
+        assert!(kinds.contains(&"UnexpectedElement"));
+    }
+
+    #[test]
+    fn test_validate_with_integrity() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let shared = dir.path().join("a.xml");
+        std::fs::write(
+            &shared,
+            SAMPLE_XML.replace(
+                "</主題属性>",
+                "<筆 id=\"H2\"><地番>2</地番><形状 idref=\"S1\"/></筆>\n</主題属性>",
+            ),
+        )?;
+        let valid = dir.path().join("b.xml");
+        std::fs::write(&valid, SAMPLE_XML)?;
+        let paths = vec![shared, valid];
+
+        let mut options = ValidateOptions {
+            threads: 2,
+            integrity: false,
+            reader: ReaderOptions::default(),
+        };
+        let validation = validate(&paths, &options)?;
+        assert_eq!(validation.xml_file_count, 2);
+        assert!(validation.files.is_empty());
+
+        options.integrity = true;
+        let validation = validate(&paths, &options)?;
+        assert_eq!(validation.files.len(), 1);
+        assert_eq!(validation.anomaly_count(), 1);
+        assert_eq!(validation.invalid_file_count(), 0);
+        let mut text = Vec::new();
+        validation.write(ValidateFormat::Text, &mut text)?;
+        assert_eq!(
+            String::from_utf8(text)?,
+            "a.xml:15:1: [SharedSurface] GM_Surface S1 is the 形状 of several 筆: H1, H2\n"
+        );
+        Ok(())
+    }
+}
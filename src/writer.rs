@@ -1,4 +1,6 @@
-use crate::parse::ParsedXML;
+use crate::id;
+use crate::parse::{CommonProperties, FeatureProperties, ParsedXML};
+use crate::tiles::{TileOptions, TileWriter};
 use anyhow::Result;
 use flatgeobuf::{
     ColumnType, FgbCrs, FgbWriter, FgbWriterOptions, GeometryType,
@@ -13,13 +15,38 @@ use std::{
 
 pub struct WriterOptions {
     pub write_index: bool,
+    /// Also write the numeric form of the global ID as a `fid` primary key column.
+    pub write_fid: bool,
 }
 
+/// Attribute columns written after `global_id`, in order, with whether they are nullable.
+pub const PROPERTY_COLUMNS: [(&str, bool); 18] = [
+    ("地図名", false),
+    ("市区町村コード", false),
+    ("市区町村名", false),
+    ("座標系", false),
+    ("測地系判別", true),
+    ("筆id", false),
+    ("精度区分", true),
+    ("大字コード", true),
+    ("丁目コード", true),
+    ("小字コード", true),
+    ("予備コード", true),
+    ("大字名", true),
+    ("丁目名", true),
+    ("小字名", true),
+    ("予備名", true),
+    ("地番", true),
+    ("座標値種別", true),
+    ("筆界未定構成筆", true),
+];
+
 pub struct FGBWriter<'a> {
     fgb: FgbWriter<'a>,
     writer: BufWriter<File>,
     output_path: PathBuf,
     has_features: bool,
+    write_fid: bool,
 }
 impl FGBWriter<'_> {
     pub fn new(output_path: &Path, options: &WriterOptions) -> Result<Self> {
@@ -38,150 +65,61 @@ impl FGBWriter<'_> {
                 ..Default::default()
             },
         )?;
-        fgb.add_column("地図名", ColumnType::String, |_, _| {});
-        fgb.add_column("市区町村コード", ColumnType::String, |_, _| {});
-        fgb.add_column("市区町村名", ColumnType::String, |_, _| {});
-        fgb.add_column("座標系", ColumnType::String, |_, _| {});
-        fgb.add_column("測地系判別", ColumnType::String, |_, col| {
-            col.nullable = true;
-        });
-        fgb.add_column("筆id", ColumnType::String, |_, _| {});
-        fgb.add_column("精度区分", ColumnType::String, |_, col| {
-            col.nullable = true;
-        });
-        fgb.add_column("大字コード", ColumnType::String, |_, col| {
-            col.nullable = true;
-        });
-        fgb.add_column("丁目コード", ColumnType::String, |_, col| {
-            col.nullable = true;
-        });
-        fgb.add_column("小字コード", ColumnType::String, |_, col| {
-            col.nullable = true;
-        });
-        fgb.add_column("予備コード", ColumnType::String, |_, col| {
-            col.nullable = true;
-        });
-        fgb.add_column("大字名", ColumnType::String, |_, col| {
-            col.nullable = true;
-        });
-        fgb.add_column("丁目名", ColumnType::String, |_, col| {
-            col.nullable = true;
-        });
-        fgb.add_column("小字名", ColumnType::String, |_, col| {
-            col.nullable = true;
-        });
-        fgb.add_column("予備名", ColumnType::String, |_, col| {
-            col.nullable = true;
-        });
-        fgb.add_column("地番", ColumnType::String, |_, col| {
-            col.nullable = true;
-        });
-        fgb.add_column("座標値種別", ColumnType::String, |_, col| {
-            col.nullable = true;
-        });
-        fgb.add_column("筆界未定構成筆", ColumnType::String, |_, col| {
-            col.nullable = true;
+        fgb.add_column("global_id", ColumnType::String, |_, col| {
+            col.primary_key = !options.write_fid;
         });
+        for (name, nullable) in PROPERTY_COLUMNS {
+            fgb.add_column(name, ColumnType::String, |_, col| {
+                col.nullable = nullable;
+            });
+        }
+        if options.write_fid {
+            fgb.add_column("fid", ColumnType::Long, |_, col| {
+                col.primary_key = true;
+            });
+        }
 
         Ok(FGBWriter {
             fgb,
             writer,
             output_path: output_path.to_path_buf(),
             has_features: false,
+            write_fid: options.write_fid,
         })
     }
 
     pub fn add_xml_features(&mut self, parsed: ParsedXML) -> Result<()> {
         // Write each feature, consuming the parsed data
         for feature in parsed.features {
-            self.has_features = true;
-            let geometry: Geometry<f64> = feature.geometry.into();
-            self.fgb.add_feature_geom(geometry, |feat| {
-                feat.property(
-                    0,
-                    "地図名",
-                    &ColumnValue::String(&parsed.common_props.地図名),
-                )
-                .unwrap();
-                feat.property(
-                    1,
-                    "市区町村コード",
-                    &ColumnValue::String(&parsed.common_props.市区町村コード),
-                )
-                .unwrap();
-                feat.property(
-                    2,
-                    "市区町村名",
-                    &ColumnValue::String(&parsed.common_props.市区町村名),
-                )
-                .unwrap();
-                feat.property(
-                    3,
-                    "座標系",
-                    &ColumnValue::String(&parsed.common_props.座標系),
-                )
-                .unwrap();
-                if let Some(ref conversion) = parsed.common_props.測地系判別 {
-                    feat.property(4, "測地系判別", &ColumnValue::String(conversion))
-                        .unwrap();
-                }
-                feat.property(5, "筆id", &ColumnValue::String(&feature.props.筆id))
-                    .unwrap();
-
-                // only set optional properties if present, leave others null
-                if let Some(v) = feature.props.精度区分.as_ref() {
-                    feat.property(6, "精度区分", &ColumnValue::String(v))
-                        .unwrap();
-                }
-                if let Some(v) = feature.props.大字コード.as_ref() {
-                    feat.property(7, "大字コード", &ColumnValue::String(v))
-                        .unwrap();
-                }
-                if let Some(v) = feature.props.丁目コード.as_ref() {
-                    feat.property(8, "丁目コード", &ColumnValue::String(v))
-                        .unwrap();
-                }
-                if let Some(v) = feature.props.小字コード.as_ref() {
-                    feat.property(9, "小字コード", &ColumnValue::String(v))
-                        .unwrap();
-                }
-                if let Some(v) = feature.props.予備コード.as_ref() {
-                    feat.property(10, "予備コード", &ColumnValue::String(v))
-                        .unwrap();
-                }
-                if let Some(v) = feature.props.大字名.as_ref() {
-                    feat.property(11, "大字名", &ColumnValue::String(v))
-                        .unwrap();
-                }
-                if let Some(v) = feature.props.丁目名.as_ref() {
-                    feat.property(12, "丁目名", &ColumnValue::String(v))
-                        .unwrap();
-                }
-                if let Some(v) = feature.props.小字名.as_ref() {
-                    feat.property(13, "小字名", &ColumnValue::String(v))
-                        .unwrap();
-                }
-                if let Some(v) = feature.props.予備名.as_ref() {
-                    feat.property(14, "予備名", &ColumnValue::String(v))
-                        .unwrap();
-                }
-                if let Some(v) = feature.props.地番.as_ref() {
-                    feat.property(15, "地番", &ColumnValue::String(v)).unwrap();
-                }
-                if let Some(v) = feature.props.座標値種別.as_ref() {
-                    feat.property(16, "座標値種別", &ColumnValue::String(v))
-                        .unwrap();
-                }
-                if let Some(v) = feature.props.筆界未定構成筆.as_ref() {
-                    feat.property(17, "筆界未定構成筆", &ColumnValue::String(v))
-                        .unwrap();
-                }
-            })?;
+            self.add_feature(
+                &parsed.common_props,
+                feature.geometry.into(),
+                &feature.props,
+            )?;
         }
 
         Ok(())
     }
 
+    /// Write a single feature. Use this instead of [`FGBWriter::add_xml_features`]
+    /// when the parsed data is still needed afterwards.
+    pub fn add_feature(
+        &mut self,
+        common_props: &CommonProperties,
+        geometry: Geometry<f64>,
+        props: &FeatureProperties,
+    ) -> Result<()> {
+        self.has_features = true;
+        // The property callback can't return an error, so keep it and check afterwards.
+        let write_fid = self.write_fid;
+        let mut props_result = Ok(());
+        self.fgb.add_feature_geom(geometry, |feat| {
+            props_result = write_properties(feat, common_props, props, write_fid);
+        })?;
+        props_result?;
+        Ok(())
+    }
+
     /// Flush the writer and finalize the FlatGeobuf file.
     /// This method must be called to ensure all data is written to the file.
     /// You cannot add any more features after calling this method.
@@ -193,24 +131,135 @@ impl FGBWriter<'_> {
             self.writer.flush()?;
             Ok(true)
         } else {
-            // Drop the writer to close the file before removing it
-            drop(self.writer);
-            // Try to remove the file, ignore "not exists" errors
-            match std::fs::remove_file(&self.output_path) {
-                Ok(_) => {}
-                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
-                Err(e) => return Err(e.into()),
-            }
+            self.discard()?;
             Ok(false)
         }
     }
+
+    /// Throw away any features added so far and remove the output file.
+    pub fn discard(self) -> Result<()> {
+        // Drop the writer to close the file before removing it
+        drop(self.writer);
+        // Try to remove the file, ignore "not exists" errors
+        match std::fs::remove_file(&self.output_path) {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Removes an output file, if it exists.
+pub fn remove_output(path: &Path) -> Result<()> {
+    match std::fs::remove_file(path) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The output of a conversion run: a FlatGeobuf file or a vector tile archive.
+pub enum Output<'a> {
+    Fgb(Box<FGBWriter<'a>>),
+    Tiles(Box<TileWriter>),
+}
+
+impl Output<'_> {
+    /// Creates the output file. Vector tiles are written when `tiles` is given.
+    pub fn create(
+        output_path: &Path,
+        options: &WriterOptions,
+        tiles: Option<&TileOptions>,
+    ) -> Result<Self> {
+        Ok(match tiles {
+            Some(tiles) => Output::Tiles(Box::new(TileWriter::new(output_path, tiles)?)),
+            None => Output::Fgb(Box::new(FGBWriter::new(output_path, options)?)),
+        })
+    }
+
+    pub fn add_xml_features(&mut self, parsed: ParsedXML) -> Result<()> {
+        match self {
+            Output::Fgb(fgb) => fgb.add_xml_features(parsed),
+            Output::Tiles(tiles) => tiles.add_xml_features(parsed),
+        }
+    }
+
+    /// See [`FGBWriter::flush`].
+    pub fn flush(self) -> Result<bool> {
+        match self {
+            Output::Fgb(fgb) => fgb.flush(),
+            Output::Tiles(tiles) => tiles.flush(),
+        }
+    }
+
+    pub fn discard(self) -> Result<()> {
+        match self {
+            Output::Fgb(fgb) => fgb.discard(),
+            Output::Tiles(tiles) => tiles.discard(),
+        }
+    }
+}
+
+fn write_properties(
+    feat: &mut impl PropertyProcessor,
+    common: &CommonProperties,
+    props: &FeatureProperties,
+    write_fid: bool,
+) -> geozero::error::Result<()> {
+    feat.property(
+        0,
+        "global_id",
+        &ColumnValue::String(&id::global_id(common, props)),
+    )?;
+    // only set optional properties if present, leave others null
+    let values = property_values(common, props);
+    for (i, ((name, _), value)) in PROPERTY_COLUMNS.iter().zip(values).enumerate() {
+        if let Some(v) = value {
+            feat.property(i + 1, name, &ColumnValue::String(v))?;
+        }
+    }
+    if write_fid {
+        feat.property(
+            PROPERTY_COLUMNS.len() + 1,
+            "fid",
+            &ColumnValue::Long(id::fid(common, props)),
+        )?;
+    }
+    Ok(())
+}
+
+/// Values of the [`PROPERTY_COLUMNS`], in order.
+pub fn property_values<'a>(
+    common: &'a CommonProperties,
+    props: &'a FeatureProperties,
+) -> [Option<&'a String>; PROPERTY_COLUMNS.len()] {
+    [
+        Some(&common.地図名),
+        Some(&common.市区町村コード),
+        Some(&common.市区町村名),
+        Some(&common.座標系),
+        common.測地系判別.as_ref(),
+        Some(&props.筆id),
+        props.精度区分.as_ref(),
+        props.大字コード.as_ref(),
+        props.丁目コード.as_ref(),
+        props.小字コード.as_ref(),
+        props.予備コード.as_ref(),
+        props.大字名.as_ref(),
+        props.丁目名.as_ref(),
+        props.小字名.as_ref(),
+        props.予備名.as_ref(),
+        props.地番.as_ref(),
+        props.座標値種別.as_ref(),
+        props.筆界未定構成筆.as_ref(),
+    ]
 }
 
 #[cfg(test)]
 mod tests {
     use geo_types::{MultiPolygon, polygon};
 
-    use crate::parse::{CommonProperties, Feature, FeatureProperties};
+    use crate::parse::Feature;
 
     use super::*;
     use std::path::PathBuf;
@@ -240,9 +289,18 @@ mod tests {
                 座標系: "公共座標1系".to_string(),
                 測地系判別: Some("変換".to_string()),
             },
+            skip_reason: None,
+            filtered_chikugai: 0,
+            warnings: vec![],
         };
         let output_path = testdata_path().join("output.fgb");
-        let mut fgb = FGBWriter::new(&output_path, &WriterOptions { write_index: true })?;
+        let mut fgb = FGBWriter::new(
+            &output_path,
+            &WriterOptions {
+                write_index: true,
+                write_fid: false,
+            },
+        )?;
         fgb.add_xml_features(parsed)?;
         fgb.flush()?;
         Ok(())
@@ -260,6 +318,9 @@ mod tests {
                 座標系: "公共座標1系".to_string(),
                 測地系判別: Some("変換".to_string()),
             },
+            skip_reason: None,
+            filtered_chikugai: 0,
+            warnings: vec![],
         };
         let output_path = testdata_path().join("output_empty.fgb");
 
@@ -268,7 +329,13 @@ mod tests {
             std::fs::remove_file(&output_path)?;
         }
 
-        let mut fgb = FGBWriter::new(&output_path, &WriterOptions { write_index: true })?;
+        let mut fgb = FGBWriter::new(
+            &output_path,
+            &WriterOptions {
+                write_index: true,
+                write_fid: false,
+            },
+        )?;
         fgb.add_xml_features(parsed)?;
         fgb.flush()?;
 